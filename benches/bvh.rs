@@ -0,0 +1,78 @@
+//! Compares the traversal speed of the BVH builders on the random sphere scene.
+//!
+//! Run with `cargo bench --bench bvh`.
+
+use raytrace::bvhnode::BvhBuilder;
+use raytrace::hitable::Hitable;
+use raytrace::ray::Ray;
+use raytrace::render::Scene;
+use raytrace::scenefile;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const RAYS: usize = 200_000;
+const RUNS: usize = 5;
+
+fn load(builder: BvhBuilder) -> Scene {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/random_scene.ron");
+    let mut scene_file = scenefile::load(&path).expect("cannot load scene");
+    scene_file.params.bvh = builder;
+    scene_file.into_scene().expect("cannot build scene")
+}
+
+/// Camera rays through random image positions, plus a random bounce off every hit so that
+/// incoherent secondary rays are measured as well.
+fn rays(scene: &Scene) -> Vec<Ray> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut rays = Vec::with_capacity(2 * RAYS);
+    while rays.len() < RAYS {
+        let r = scene.cam.get_ray(rng.gen(), rng.gen(), &mut rng);
+        if let Some(rec) = scene.world.hit(&r, 0.001, f32::MAX, &mut rng) {
+            let d = rec.n
+                + raytrace::vec3::Vec3::new(
+                    rng.gen_range(-1.0, 1.0),
+                    rng.gen_range(-1.0, 1.0),
+                    rng.gen_range(-1.0, 1.0),
+                );
+            rays.push(Ray::new(rec.p, d));
+        }
+        rays.push(r);
+    }
+    rays
+}
+
+fn measure(scene: &Scene, rays: &[Ray]) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let mut rng = StdRng::seed_from_u64(0);
+            let begin = Instant::now();
+            let hits = rays
+                .iter()
+                .filter(|r| scene.world.hit(r, 0.001, f32::MAX, &mut rng).is_some())
+                .count();
+            assert!(hits > 0);
+            begin.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let median = load(BvhBuilder::Median);
+    let sah = load(BvhBuilder::Sah);
+    let rays = rays(&sah);
+
+    let t_median = measure(&median, &rays);
+    let t_sah = measure(&sah, &rays);
+    let mrays = |t: Duration| rays.len() as f64 / t.as_secs_f64() / 1e6;
+    println!("{} rays, best of {} runs", rays.len(), RUNS);
+    println!("median: {:8.3} Mrays/s", mrays(t_median));
+    println!("sah:    {:8.3} Mrays/s", mrays(t_sah));
+    println!(
+        "speedup: {:.2}x",
+        t_median.as_secs_f64() / t_sah.as_secs_f64()
+    );
+}
@@ -0,0 +1,210 @@
+use crate::framebuffer::Framebuffer;
+use crate::hitable::Hitable;
+use crate::material::Material;
+use crate::render::Scene;
+use crate::vec3::Vec3;
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Arbitrary output variable: a property of the first surface seen through every pixel,
+/// rendered next to the image for compositing and denoising.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Aov {
+    /// distance from the camera
+    Depth,
+    /// world space position
+    Position,
+    /// shading normal
+    Normal,
+    /// base color of the material (see [`Material::albedo`]) times the vertex color
+    Albedo,
+    /// texture coordinates
+    Uv,
+    /// material, numbered 1, 2, ... in the order of [`Scene::materials`]; materials
+    /// loaded from mesh files follow in the order they first appear scanning the image
+    MaterialId,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 6] = [
+        Aov::Depth,
+        Aov::Position,
+        Aov::Normal,
+        Aov::Albedo,
+        Aov::Uv,
+        Aov::MaterialId,
+    ];
+
+    /// Name used on the command line, in file names and as EXR layer.
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::Uv => "uv",
+            Aov::MaterialId => "id",
+        }
+    }
+
+    /// Names of the channels, stored in this order in the components of the framebuffer.
+    pub fn channels(self) -> &'static [&'static str] {
+        match self {
+            Aov::Depth => &["Z"],
+            Aov::Position | Aov::Normal => &["X", "Y", "Z"],
+            Aov::Albedo => &["R", "G", "B"],
+            Aov::Uv => &["U", "V"],
+            Aov::MaterialId => &["id"],
+        }
+    }
+
+    /// Map the values into 0 ... 1 for viewing as 8 bit image: depth and position are
+    /// scaled by their maximum, normals from -1 ... 1, material IDs become random colors.
+    pub fn to_display(self, fb: &Framebuffer) -> Framebuffer {
+        let max = fb
+            .data
+            .iter()
+            .map(|c| c.x().abs().max(c.y().abs()).max(c.z().abs()))
+            .fold(0.0, f32::max)
+            .max(f32::MIN_POSITIVE);
+        let map = |c: Vec3| match self {
+            Aov::Depth => c / max,
+            Aov::Position => 0.5 * (c / max + Vec3::new(1.0, 1.0, 1.0)),
+            Aov::Normal => 0.5 * (c + Vec3::new(1.0, 1.0, 1.0)),
+            Aov::Albedo | Aov::Uv => c,
+            Aov::MaterialId => id_color(c.x() as u32),
+        };
+        Framebuffer {
+            width: fb.width,
+            height: fb.height,
+            data: fb.data.iter().map(|&c| map(c)).collect(),
+        }
+    }
+}
+
+impl FromStr for Aov {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Aov, String> {
+        Aov::ALL
+            .iter()
+            .find(|aov| aov.name() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown AOV: {}", s))
+    }
+}
+
+struct FirstHit<'a> {
+    depth: f32,
+    p: Vec3,
+    n: Vec3,
+    u: f32,
+    v: f32,
+    material: &'a Material,
+    color: Vec3,
+}
+
+/// Render the requested AOVs with one ray through the center of every pixel. Pixels whose
+/// ray hits nothing are zero in all of them.
+pub fn render_aovs(scene: &Scene, aovs: &[Aov]) -> Vec<(Aov, Framebuffer)> {
+    if aovs.is_empty() {
+        return vec![];
+    }
+    let (nx, ny) = (scene.params.nx, scene.params.ny);
+    let rows: Vec<Vec<Option<FirstHit>>> = (0..ny)
+        .into_par_iter()
+        .map(|y| {
+            // an RNG per row keeps the result independent of the thread scheduling
+            let mut rng = StdRng::seed_from_u64(
+                scene.params.seed ^ (u64::from(y) + 1).wrapping_mul(0x2545_f491_4f6c_dd1d),
+            );
+            (0..nx)
+                .map(|x| {
+                    let u = (x as f32 + 0.5) / nx as f32;
+                    let v = ((ny - y - 1) as f32 + 0.5) / ny as f32;
+                    let r = scene.cam.get_ray(u, v, &mut rng);
+                    scene
+                        .world
+                        .hit(&r, 0.001, f32::MAX, &mut rng)
+                        .map(|rec| FirstHit {
+                            depth: rec.t * r.direction.len(),
+                            p: rec.p,
+                            n: rec.n,
+                            u: rec.u,
+                            v: rec.v,
+                            material: rec.material,
+                            color: rec.color,
+                        })
+                })
+                .collect()
+        })
+        .collect();
+    let hits: Vec<Option<FirstHit>> = rows.into_iter().flatten().collect();
+
+    // the scene's materials are numbered when it is built, the address only identifies
+    // them; the rest are numbered by their first appearance
+    let mut ids: HashMap<*const Material, u32> = scene
+        .materials
+        .iter()
+        .enumerate()
+        .map(|(i, material)| (&**material as *const Material, i as u32 + 1))
+        .collect();
+    for hit in hits.iter().flatten() {
+        let next = ids.len() as u32 + 1;
+        ids.entry(hit.material as *const Material).or_insert(next);
+    }
+
+    aovs.iter()
+        .map(|&aov| {
+            let data = hits
+                .iter()
+                .map(|hit| match hit {
+                    None => Vec3::default(),
+                    Some(hit) => match aov {
+                        Aov::Depth => Vec3::new(hit.depth, hit.depth, hit.depth),
+                        Aov::Position => hit.p,
+                        Aov::Normal => hit.n.normalize(),
+                        Aov::Albedo => hit.color * hit.material.albedo(hit.u, hit.v, &hit.p),
+                        Aov::Uv => Vec3::new(hit.u, hit.v, 0.0),
+                        Aov::MaterialId => {
+                            let id = ids[&(hit.material as *const Material)] as f32;
+                            Vec3::new(id, id, id)
+                        }
+                    },
+                })
+                .collect();
+            (
+                aov,
+                Framebuffer {
+                    width: nx,
+                    height: ny,
+                    data,
+                },
+            )
+        })
+        .collect()
+}
+
+/// Distinct, stable color for a material ID, black for no material.
+fn id_color(id: u32) -> Vec3 {
+    if id == 0 {
+        return Vec3::default();
+    }
+    // golden ratio steps around the hue circle
+    let hue = (id as f32 * 0.618_034).fract() * 6.0;
+    let x = 1.0 - (hue % 2.0 - 1.0).abs();
+    match hue as u32 {
+        0 => Vec3::new(1.0, x, 0.0),
+        1 => Vec3::new(x, 1.0, 0.0),
+        2 => Vec3::new(0.0, 1.0, x),
+        3 => Vec3::new(0.0, x, 1.0),
+        4 => Vec3::new(x, 0.0, 1.0),
+        _ => Vec3::new(1.0, 0.0, x),
+    }
+}
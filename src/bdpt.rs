@@ -0,0 +1,459 @@
+//! Bidirectional path tracing: a subpath is traced from the camera and another one from a
+//! light, and every pair of their prefixes is connected into a complete path. Each way of
+//! building a path (strategy) is good at different kinds of light transport, e.g. caustics
+//! seen through a diffuse surface are hard to find from the camera but easy from the light,
+//! so the strategies are combined by multiple importance sampling.
+use crate::hitable::{HitRecord, Hitable};
+use crate::light::Light;
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::render::{Scene, Splat};
+use crate::vec3::Vec3;
+
+use rand::{Rng, RngCore};
+
+#[derive(Clone, Copy)]
+enum Kind<'a> {
+    /// point on the lens
+    Camera,
+    /// point on a light, starting a light subpath
+    Light(&'a Light),
+    /// surface hit or scattering in a volume
+    Surface(HitRecord<'a>),
+}
+
+/// Vertex of a subpath. The densities are per area (per volume in media) of choosing the
+/// vertex coming from either end of the path, for computing the MIS weights.
+#[derive(Clone, Copy)]
+struct Vertex<'a> {
+    kind: Kind<'a>,
+    p: Vec3,
+    /// unit normal for the cosine converting densities per solid angle to area, None in
+    /// volumes
+    n: Option<Vec3>,
+    /// weight of the subpath up to this vertex: the product of BSDF, cosine and inverse
+    /// density of the vertices before it
+    beta: Vec3,
+    /// density of the vertex when sampled from the previous vertex of its subpath
+    pdf_fwd: f32,
+    /// density of the vertex when sampled from the next vertex, as by the other subpath
+    pdf_rev: f32,
+    /// scattered specularly (mirror, glass), which cannot be connected to
+    delta: bool,
+}
+
+/// Vertex buffers of [`color`], kept for a whole tile so that the samples reuse their
+/// allocations.
+#[derive(Default)]
+pub struct Paths<'a> {
+    camera: Vec<Vertex<'a>>,
+    light: Vec<Vertex<'a>>,
+    scratch: Scratch<'a>,
+}
+
+/// Copies of the connected prefixes of both subpaths, whose densities [`mis_weight`]
+/// changes at the connection.
+#[derive(Default)]
+struct Scratch<'a> {
+    light: Vec<Vertex<'a>>,
+    camera: Vec<Vertex<'a>>,
+}
+
+impl<'a> Vertex<'a> {
+    fn camera(p: Vec3) -> Vertex<'a> {
+        Vertex {
+            kind: Kind::Camera,
+            p,
+            n: None,
+            beta: Vec3::new(1.0, 1.0, 1.0),
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+        }
+    }
+
+    fn surface(rec: HitRecord<'a>, beta: Vec3) -> Vertex<'a> {
+        Vertex {
+            kind: Kind::Surface(rec),
+            p: rec.p,
+            n: match rec.material {
+                Material::Isotropic { .. } => None,
+                _ => Some(rec.n.normalize()),
+            },
+            beta,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+        }
+    }
+
+    /// The light of the scene the vertex lies on, if any.
+    fn light(&self, scene: &'a Scene) -> Option<&'a Light> {
+        match self.kind {
+            Kind::Light(light) => Some(light),
+            Kind::Surface(rec) => rec.light.map(|i| &scene.lights[i]),
+            Kind::Camera => None,
+        }
+    }
+
+    /// Light emitted by the surface of the vertex.
+    fn emitted(&self) -> Vec3 {
+        match self.kind {
+            Kind::Surface(rec) => rec.material.emitted(rec.u, rec.v, &rec.p),
+            _ => Vec3::default(),
+        }
+    }
+
+    /// Fraction of the light arriving from the point `from` that is scattered towards the
+    /// point `to`, including the cosine of the direction to `from`.
+    fn eval(&self, from: Vec3, to: Vec3) -> Vec3 {
+        match self.kind {
+            Kind::Surface(rec) => {
+                let wi = (from - self.p).normalize();
+                let wo = (to - self.p).normalize();
+                rec.material.eval(&rec, wi, wo)
+            }
+            _ => Vec3::default(),
+        }
+    }
+
+    /// Convert the density `pdf` per solid angle of the direction from this vertex to `next`
+    /// to a density per area at `next`.
+    fn convert_density(&self, pdf: f32, next: &Vertex) -> f32 {
+        let d = next.p - self.p;
+        let dist_sq = d.len_squared();
+        let cos = next
+            .n
+            .map_or(1.0, |n| Vec3::dot(n, d).abs() / dist_sq.sqrt());
+        pdf * cos / dist_sq
+    }
+
+    /// Density per area of sampling `next` from this vertex, reached from `prev`.
+    fn pdf(&self, scene: &'a Scene, prev: Option<&Vertex>, next: &Vertex) -> f32 {
+        let wi = (next.p - self.p).normalize();
+        let pdf = match (self.kind, prev) {
+            (Kind::Camera, _) => scene.cam.pdf_direction(self.p, wi),
+            (Kind::Surface(rec), Some(prev)) => {
+                let wo = (prev.p - self.p).normalize();
+                rec.material.pdf(&rec, wi, wo)
+            }
+            _ => return self.pdf_light(scene, next),
+        };
+        self.convert_density(pdf, next)
+    }
+
+    /// Density per area of light leaving this vertex on a light reaching `next`.
+    fn pdf_light(&self, scene: &'a Scene, next: &Vertex) -> f32 {
+        match self.light(scene) {
+            Some(light) => {
+                let w = (next.p - self.p).normalize();
+                self.convert_density(light.pdf_direction(self.p, w), next)
+            }
+            None => 0.0,
+        }
+    }
+
+    /// Density per area of a light subpath starting at this vertex on a light.
+    fn pdf_light_origin(&self, scene: &'a Scene) -> f32 {
+        self.light(scene)
+            .map_or(0.0, |l| l.pdf_position() / scene.lights.len() as f32)
+    }
+}
+
+/// Radiance arriving along the camera ray `r`, estimated by bidirectional path tracing with
+/// paths of up to `max_depth` bounces. Light subpaths reaching the camera somewhere else in
+/// the image are added to `splats` instead, to be averaged over all light subpaths.
+pub fn color<'a>(
+    r: Ray,
+    scene: &'a Scene,
+    paths: &mut Paths<'a>,
+    splats: &mut Vec<Splat>,
+    rng: &mut dyn RngCore,
+) -> Vec3 {
+    let max_depth = scene.params.max_depth as usize;
+    let Paths {
+        camera: camera_path,
+        light: light_path,
+        scratch,
+    } = paths;
+    camera_path.clear();
+    camera_path.push(Vertex::camera(r.origin));
+    let pdf = scene.cam.pdf_direction(r.origin, r.direction);
+    let beta = Vec3::new(1.0, 1.0, 1.0);
+    // the background can only be found from the camera
+    let mut radiance = random_walk(scene, r, beta, pdf, max_depth + 2, camera_path, rng);
+    light_path.clear();
+    light_subpath(scene, max_depth + 1, light_path, rng);
+
+    for t in 1..=camera_path.len() {
+        for s in 0..=light_path.len() {
+            // the lens alone does not find a light, and lights are not seen on the lens
+            if s + t < 2 || (s == 1 && t == 1) || s + t - 2 > max_depth {
+                continue;
+            }
+            let (c, position) = connect(scene, light_path, camera_path, scratch, s, t, rng);
+            match position {
+                Some(position) => splats.push((position, c)),
+                None => radiance += c,
+            }
+        }
+    }
+    radiance
+}
+
+/// Extend `path` by tracing the ray `r` until it has `max_vertices`, leaves the scene or is
+/// absorbed; `beta` is the weight of the path so far and `pdf` the density per solid angle
+/// of the direction of `r`. Returns the background light reached, weighted by the path.
+fn random_walk<'a>(
+    scene: &'a Scene,
+    r: Ray,
+    beta: Vec3,
+    pdf: f32,
+    max_vertices: usize,
+    path: &mut Vec<Vertex<'a>>,
+    rng: &mut dyn RngCore,
+) -> Vec3 {
+    let mut r = r;
+    let mut beta = beta;
+    let mut pdf_fwd = pdf;
+    while path.len() < max_vertices {
+        let rec = match scene.world.hit(&r, 0.001, f32::MAX, rng) {
+            Some(rec) => rec,
+            None => return beta * scene.background.color(&r),
+        };
+        let prev = path.len() - 1;
+        let mut vertex = Vertex::surface(rec, beta);
+        vertex.pdf_fwd = path[prev].convert_density(pdf_fwd, &vertex);
+        path.push(vertex);
+        if path.len() == max_vertices {
+            break;
+        }
+
+        let s = match rec.material.scatter(&r, &rec, rng) {
+            Some(s) => s,
+            None => break,
+        };
+        let wo = -r.direction.normalize();
+        let pdf_rev = match s.pdf {
+            Some(pdf) => {
+                pdf_fwd = pdf;
+                rec.material.pdf(&rec, wo, s.direction)
+            }
+            None => {
+                path[prev + 1].delta = true;
+                pdf_fwd = 0.0;
+                0.0
+            }
+        };
+        path[prev].pdf_rev = path[prev + 1].convert_density(pdf_rev, &path[prev]);
+        beta *= s.att;
+        if prev + 1 >= scene.params.roulette_depth as usize {
+            let p = beta.x().max(beta.y()).max(beta.z()).min(1.0);
+            if rng.gen::<f32>() >= p {
+                break;
+            }
+            beta /= p;
+        }
+        r = Ray::new(rec.p, s.direction);
+    }
+    Vec3::default()
+}
+
+/// Trace a subpath of up to `max_vertices` from a randomly chosen light of the scene into
+/// `path`, which stays empty if the scene has no lights.
+fn light_subpath<'a>(
+    scene: &'a Scene,
+    max_vertices: usize,
+    path: &mut Vec<Vertex<'a>>,
+    rng: &mut dyn RngCore,
+) {
+    if scene.lights.is_empty() {
+        return;
+    }
+    let light = &scene.lights[rng.gen_range(0, scene.lights.len())];
+    let e = light.sample_emission(rng);
+    if e.pdf_direction <= 0.0 {
+        return;
+    }
+    let pdf_origin = e.pdf_position / scene.lights.len() as f32;
+    path.push(Vertex {
+        kind: Kind::Light(light),
+        p: e.p,
+        n: Some(e.n),
+        beta: e.radiance / pdf_origin,
+        pdf_fwd: pdf_origin,
+        pdf_rev: 0.0,
+        delta: false,
+    });
+    let beta = e.radiance * Vec3::dot(e.n, e.direction) / (pdf_origin * e.pdf_direction);
+    let r = Ray::new(e.p, e.direction);
+    random_walk(scene, r, beta, e.pdf_direction, max_vertices, path, rng);
+}
+
+/// Whether nothing is in between the points `a` and `b`.
+fn visible(scene: &Scene, a: Vec3, b: Vec3, rng: &mut dyn RngCore) -> bool {
+    let d = b - a;
+    let dist = d.len();
+    scene
+        .world
+        .hit(&Ray::new(a, d / dist), 0.001, dist - 0.001, rng)
+        .is_none()
+}
+
+/// MIS weighted contribution of the path connecting the first `s` vertices of the light
+/// subpath to the first `t` vertices of the camera subpath, and for `t == 1` the image
+/// coordinates it reaches the camera at. With `s == 1` a new point on a light is sampled
+/// instead of using the start of the light subpath, which is better distributed.
+fn connect<'a>(
+    scene: &'a Scene,
+    light_path: &[Vertex<'a>],
+    camera_path: &[Vertex<'a>],
+    scratch: &mut Scratch<'a>,
+    s: usize,
+    t: usize,
+    rng: &mut dyn RngCore,
+) -> (Vec3, Option<(f32, f32)>) {
+    let none = (Vec3::default(), None);
+    let pt = &camera_path[t - 1];
+    let mut sampled = None;
+    let mut position = None;
+    let c = if s == 0 {
+        // the camera subpath found a light
+        let c = pt.beta * pt.emitted();
+        if pt.light(scene).is_none() {
+            // only found this way
+            return (c, None);
+        }
+        c
+    } else if t == 1 {
+        // the light subpath is seen by the camera
+        let qs = &light_path[s - 1];
+        if qs.delta {
+            return none;
+        }
+        let lens = pt.p;
+        position = scene.cam.project(lens, qs.p);
+        let d = qs.p - lens;
+        let pdf = scene.cam.pdf_direction(lens, d);
+        if position.is_none() || pdf <= 0.0 || !visible(scene, qs.p, lens, rng) {
+            return none;
+        }
+        qs.beta * qs.eval(lens, light_path[s - 2].p) * (pdf / d.len_squared())
+    } else if s == 1 {
+        // sample a point on a light as seen from the camera subpath
+        if pt.delta {
+            return none;
+        }
+        let n = scene.lights.len() as f32;
+        let i = rng.gen_range(0, scene.lights.len());
+        let light = &scene.lights[i];
+        let (wi, pdf) = match light.sample(pt.p, rng) {
+            Some(sample) => sample,
+            None => return none,
+        };
+        let l = match scene.world.hit(&Ray::new(pt.p, wi), 0.001, f32::MAX, rng) {
+            Some(l) if l.light == Some(i) => l,
+            _ => return none,
+        };
+        let vertex = Vertex {
+            kind: Kind::Light(light),
+            p: l.p,
+            n: Some(l.n.normalize()),
+            beta: l.material.emitted(l.u, l.v, &l.p) * (n / pdf),
+            pdf_fwd: light.pdf_position() / n,
+            pdf_rev: 0.0,
+            delta: false,
+        };
+        sampled = Some(vertex);
+        pt.beta * pt.eval(vertex.p, camera_path[t - 2].p) * vertex.beta
+    } else {
+        let qs = &light_path[s - 1];
+        if qs.delta || pt.delta {
+            return none;
+        }
+        let f = qs.eval(pt.p, light_path[s - 2].p) * pt.eval(qs.p, camera_path[t - 2].p);
+        if f == Vec3::default() || !visible(scene, pt.p, qs.p, rng) {
+            return none;
+        }
+        qs.beta * f * pt.beta / (qs.p - pt.p).len_squared()
+    };
+    if c == Vec3::default() {
+        return none;
+    }
+    let weight = mis_weight(scene, light_path, camera_path, scratch, sampled, s, t);
+    (c * weight, position)
+}
+
+/// Power heuristic weight of the strategy (`s`, `t`) among all strategies that could have
+/// built the same path, from the ratios of the densities of its vertices when sampled from
+/// either end. `sampled` replaces the first light vertex for `s == 1`.
+fn mis_weight<'a>(
+    scene: &'a Scene,
+    light_path: &[Vertex<'a>],
+    camera_path: &[Vertex<'a>],
+    scratch: &mut Scratch<'a>,
+    sampled: Option<Vertex<'a>>,
+    s: usize,
+    t: usize,
+) -> f32 {
+    if s + t == 2 {
+        return 1.0;
+    }
+    let Scratch {
+        light: lv,
+        camera: cv,
+    } = scratch;
+    lv.clear();
+    lv.extend_from_slice(&light_path[..s]);
+    cv.clear();
+    cv.extend_from_slice(&camera_path[..t]);
+    if let Some(vertex) = sampled {
+        lv[0] = vertex;
+    }
+
+    // the densities of the vertices at the connection, as if sampled by the other subpath
+    if s > 0 {
+        lv[s - 1].delta = false;
+    }
+    cv[t - 1].delta = false;
+    cv[t - 1].pdf_rev = if s > 0 {
+        lv[s - 1].pdf(scene, s.checked_sub(2).map(|i| &lv[i]), &cv[t - 1])
+    } else {
+        cv[t - 1].pdf_light_origin(scene)
+    };
+    if t > 1 {
+        cv[t - 2].pdf_rev = if s > 0 {
+            cv[t - 1].pdf(scene, Some(&lv[s - 1]), &cv[t - 2])
+        } else {
+            cv[t - 1].pdf_light(scene, &cv[t - 2])
+        };
+    }
+    if s > 0 {
+        lv[s - 1].pdf_rev = cv[t - 1].pdf(scene, t.checked_sub(2).map(|i| &cv[i]), &lv[s - 1]);
+    }
+    if s > 1 {
+        lv[s - 2].pdf_rev = lv[s - 1].pdf(scene, Some(&cv[t - 1]), &lv[s - 2]);
+    }
+
+    // delta vertices have no density, their ratio cancels out
+    let remap = |pdf: f32| if pdf != 0.0 { pdf } else { 1.0 };
+    let mut sum = 0.0;
+    // strategies moving the connection towards the camera, (s + t - i, i)
+    let mut ratio = 1.0;
+    for i in (1..t).rev() {
+        ratio *= remap(cv[i].pdf_rev) / remap(cv[i].pdf_fwd);
+        if !cv[i].delta && !cv[i - 1].delta {
+            sum += ratio * ratio;
+        }
+    }
+    // strategies moving the connection towards the light, (i, s + t - i)
+    let mut ratio = 1.0;
+    for i in (0..s).rev() {
+        ratio *= remap(lv[i].pdf_rev) / remap(lv[i].pdf_fwd);
+        let delta_before = i > 0 && lv[i - 1].delta;
+        if !lv[i].delta && !delta_before {
+            sum += ratio * ratio;
+        }
+    }
+    1.0 / (1.0 + sum)
+}
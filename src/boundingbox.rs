@@ -8,9 +8,11 @@ pub struct BoundingBox {
 }
 
 impl BoundingBox {
-    pub fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+    /// Test the ray against the box, `inv_d` holds the reciprocal of the ray direction
+    /// components so that it can be computed once per ray.
+    pub fn hit(&self, r: &Ray, inv_d: Vec3, t_min: f32, t_max: f32) -> bool {
         for a in 0..3 {
-            let inv_d = 1.0 / r.direction.i(a);
+            let inv_d = inv_d.i(a);
             let mut t0 = (self.min.i(a) - r.origin.i(a)) * inv_d;
             let mut t1 = (self.max.i(a) - r.origin.i(a)) * inv_d;
             if inv_d < 0.0 {
@@ -24,6 +26,16 @@ impl BoundingBox {
         }
         true
     }
+
+    /// Surface area of the box, the cost measure of the SAH BVH builder.
+    pub fn area(&self) -> f32 {
+        let d = self.max - self.min;
+        2.0 * (d.x() * d.y() + d.y() * d.z() + d.z() * d.x())
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        0.5 * (self.min + self.max)
+    }
 }
 
 pub fn surrounding_box(box0: &BoundingBox, box1: &BoundingBox) -> BoundingBox {
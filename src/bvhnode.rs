@@ -1,109 +1,458 @@
 use crate::boundingbox;
 use crate::boundingbox::BoundingBox;
+use crate::error::RaytraceError;
 use crate::hitable::{HitRecord, Hitable};
+use crate::light::Light;
 use crate::ray::Ray;
+use crate::vec3::Vec3;
 
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::str::FromStr;
 
+/// Number of bins the centroids are sorted into when evaluating SAH split candidates.
+const SAH_BINS: usize = 16;
+
+/// Strategy for splitting the objects of a BVH node between its two children.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum BvhBuilder {
+    /// split at the median along a random axis, from a fixed seed so that the tree is
+    /// the same on every run
+    Median,
+    /// binned surface area heuristic, choosing axis and split position by the expected
+    /// traversal cost; deterministic for a given list of objects
+    #[default]
+    Sah,
+}
+
+impl FromStr for BvhBuilder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<BvhBuilder, String> {
+        match s {
+            "median" => Ok(BvhBuilder::Median),
+            "sah" => Ok(BvhBuilder::Sah),
+            _ => Err(format!("unknown BVH builder: {}", s)),
+        }
+    }
+}
+
+/// Bounding volume hierarchy over a list of objects.
 pub struct BvhNode {
-    left: Box<dyn Hitable>,
-    right: Option<Box<dyn Hitable>>,
+    bvh: Bvh<Box<dyn Hitable>>,
+}
+
+/// Bounding volume hierarchy over items of any type, stored as a flat array of nodes in
+/// depth first order with the items referenced by index. Also used by triangle meshes
+/// over their faces.
+pub(crate) struct Bvh<T> {
+    nodes: Vec<LinearNode>,
+    items: Vec<T>,
+}
+
+struct LinearNode {
     bbox: BoundingBox,
+    /// leaf: index of the first primitive, interior node: index of the second child
+    offset: usize,
+    /// number of primitives in a leaf, 0 for interior nodes
+    count: usize,
+    /// split axis of an interior node, decides which child is visited first
+    axis: usize,
 }
 
+/// Maximum number of primitives in a leaf.
+const MAX_LEAF_SIZE: usize = 2;
+
+/// Size of the traversal stack, which holds at most one node per level of the tree.
+const STACK_SIZE: usize = 64;
+
+/// Below this depth nodes are split at the median whatever the builder, so that even
+/// degenerate SAH splits keep the tree within [`STACK_SIZE`] levels for up to 2^32 items.
+const MAX_SAH_DEPTH: usize = 32;
+
 impl BvhNode {
-    pub fn new(items: Vec<Box<dyn Hitable>>) -> BvhNode {
-        let mut rng = rand::thread_rng();
-        let mut sorted_items = items;
-
-        let axis: usize = rng.gen_range(0, 3);
-        if axis == 0 {
-            sorted_items.sort_by(|a, b| {
-                let box_left = a.bounding_box().unwrap();
-                let box_right = b.bounding_box().unwrap();
-                box_left.min.x().partial_cmp(&box_right.min.x()).unwrap()
-            });
-        } else if axis == 1 {
-            sorted_items.sort_by(|a, b| {
-                let box_left = a.bounding_box().unwrap();
-                let box_right = b.bounding_box().unwrap();
-                box_left.min.y().partial_cmp(&box_right.min.y()).unwrap()
-            });
-        } else {
-            sorted_items.sort_by(|a, b| {
-                let box_left = a.bounding_box().unwrap();
-                let box_right = b.bounding_box().unwrap();
-                box_left.min.z().partial_cmp(&box_right.min.z()).unwrap()
-            });
+    pub fn new(items: Vec<Box<dyn Hitable>>) -> Result<BvhNode, RaytraceError> {
+        BvhNode::with_builder(items, BvhBuilder::default())
+    }
+
+    pub fn with_builder(
+        items: Vec<Box<dyn Hitable>>,
+        builder: BvhBuilder,
+    ) -> Result<BvhNode, RaytraceError> {
+        if items.is_empty() {
+            return Err(RaytraceError::EmptyBvh);
         }
+        let items = items
+            .into_iter()
+            .map(|item| match item.bounding_box() {
+                Some(bbox) => Ok((bbox, item)),
+                None => Err(RaytraceError::Unbounded),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(BvhNode {
+            bvh: Bvh::new(items, builder),
+        })
+    }
+}
 
-        if sorted_items.len() == 1 {
-            let left = sorted_items.pop().unwrap();
-            let bbox = left.bounding_box().unwrap();
-            BvhNode {
-                left,
-                right: None,
-                bbox,
-            }
-        } else if sorted_items.len() == 2 {
-            let right = sorted_items.pop().unwrap();
-            let left = sorted_items.pop().unwrap();
-            let bbox = boundingbox::surrounding_box(
-                &left.bounding_box().unwrap(),
-                &right.bounding_box().unwrap(),
-            );
-            BvhNode {
-                left,
-                right: Some(right),
-                bbox,
+impl<T> Bvh<T> {
+    /// Build the hierarchy over a non-empty list of items with their bounding boxes.
+    pub(crate) fn new(items: Items<T>, builder: BvhBuilder) -> Bvh<T> {
+        assert!(!items.is_empty(), "BVH over no items");
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(2 * items.len()),
+            items: Vec::with_capacity(items.len()),
+        };
+        bvh.build(items, builder, 0, &mut StdRng::seed_from_u64(0));
+        bvh
+    }
+
+    pub(crate) fn bounding_box(&self) -> &BoundingBox {
+        &self.nodes[0].bbox
+    }
+
+    pub(crate) fn items_mut(&mut self) -> &mut [T] {
+        &mut self.items
+    }
+
+    /// Append the nodes of the subtree over `items`, the first child directly follows its
+    /// parent.
+    fn build(&mut self, items: Items<T>, builder: BvhBuilder, depth: usize, rng: &mut dyn RngCore) {
+        let bbox = items.iter().skip(1).fold(items[0].0.clone(), |acc, item| {
+            boundingbox::surrounding_box(&acc, &item.0)
+        });
+        let this = self.nodes.len();
+        self.nodes.push(LinearNode {
+            bbox,
+            offset: self.items.len(),
+            count: items.len(),
+            axis: 0,
+        });
+        if items.len() <= MAX_LEAF_SIZE {
+            self.items.extend(items.into_iter().map(|(_, item)| item));
+            return;
+        }
+
+        let (axis, left, right) = match builder {
+            BvhBuilder::Sah if depth < MAX_SAH_DEPTH => split_sah(items),
+            _ => split_median(items, rng),
+        };
+        self.build(left, builder, depth + 1, rng);
+        let second = self.nodes.len();
+        self.build(right, builder, depth + 1, rng);
+
+        let node = &mut self.nodes[this];
+        node.offset = second;
+        node.count = 0;
+        node.axis = axis;
+    }
+
+    /// Find the closest hit along `r`, visiting the nearer child of every node first.
+    /// `hit_item` intersects a single item with the ray up to the given maximum distance.
+    pub(crate) fn hit<'a, F>(
+        &'a self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+        mut hit_item: F,
+    ) -> Option<HitRecord<'a>>
+    where
+        F: FnMut(&'a T, f32) -> Option<HitRecord<'a>>,
+    {
+        let inv_d = r.inv_direction();
+        let dir_is_neg = [inv_d.x() < 0.0, inv_d.y() < 0.0, inv_d.z() < 0.0];
+
+        let mut hit_record = None;
+        let mut closest_so_far = t_max;
+        let mut stack = [0; STACK_SIZE];
+        let mut stack_len = 0;
+        let mut n = 0;
+        loop {
+            let node = &self.nodes[n];
+            if node.bbox.hit(r, inv_d, t_min, closest_so_far) {
+                if node.count > 0 {
+                    for item in &self.items[node.offset..node.offset + node.count] {
+                        if let Some(rec) = hit_item(item, closest_so_far) {
+                            closest_so_far = rec.t;
+                            hit_record = Some(rec);
+                        }
+                    }
+                } else if dir_is_neg[node.axis] {
+                    // the second child lies further along the split axis, it is nearer
+                    stack[stack_len] = n + 1;
+                    stack_len += 1;
+                    n = node.offset;
+                    continue;
+                } else {
+                    stack[stack_len] = node.offset;
+                    stack_len += 1;
+                    n += 1;
+                    continue;
+                }
             }
-        } else {
-            let right = sorted_items.split_off(sorted_items.len() / 2);
-            let left = sorted_items;
-
-            let left = BvhNode::new(left);
-            let right = BvhNode::new(right);
-            let bbox = boundingbox::surrounding_box(
-                &left.bounding_box().unwrap(),
-                &right.bounding_box().unwrap(),
-            );
-            BvhNode {
-                left: Box::new(left),
-                right: Some(Box::new(right)),
-                bbox,
+            if stack_len == 0 {
+                break;
             }
+            stack_len -= 1;
+            n = stack[stack_len];
         }
+        hit_record
     }
 }
 
-impl Hitable for BvhNode {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        if self.bbox.hit(r, t_min, t_max) {
-            if self.right.is_none() {
-                self.left.hit(&r, t_min, t_max)
-            } else {
-                let hit_left = self.left.hit(&r, t_min, t_max);
-                let hit_right = self.right.as_ref().unwrap().hit(&r, t_min, t_max);
-                if hit_left.is_some() && hit_right.is_some() {
-                    if hit_left.as_ref().unwrap().t < hit_right.as_ref().unwrap().t {
-                        hit_left
-                    } else {
-                        hit_right
-                    }
-                } else if hit_left.is_some() {
-                    hit_left
-                } else if hit_right.is_some() {
-                    hit_right
+pub(crate) type Items<T> = Vec<(BoundingBox, T)>;
+
+/// Split into two halves, returning the split axis with the two parts.
+fn split_median<T>(mut items: Items<T>, rng: &mut dyn RngCore) -> (usize, Items<T>, Items<T>) {
+    let axis: usize = rng.gen_range(0, 3);
+    items.sort_by(|(box_left, _), (box_right, _)| {
+        box_left
+            .min
+            .i(axis)
+            .partial_cmp(&box_right.min.i(axis))
+            .unwrap_or(Ordering::Equal)
+    });
+
+    let right = items.split_off(items.len() / 2);
+    (axis, items, right)
+}
+
+/// Split by the binned surface area heuristic: the centroids are sorted into equally sized
+/// bins along each axis, and the boundary between bins minimizing
+/// `area(left) * count(left) + area(right) * count(right)` is chosen.
+fn split_sah<T>(mut items: Items<T>) -> (usize, Items<T>, Items<T>) {
+    let centroids: Vec<Vec3> = items.iter().map(|(bbox, _)| bbox.centroid()).collect();
+    let mut c_min = centroids[0];
+    let mut c_max = centroids[0];
+    for c in &centroids[1..] {
+        for axis in 0..3 {
+            c_min.set_i(axis, c_min.i(axis).min(c.i(axis)));
+            c_max.set_i(axis, c_max.i(axis).max(c.i(axis)));
+        }
+    }
+    let bin = |c: &Vec3, axis: usize| {
+        let extent = c_max.i(axis) - c_min.i(axis);
+        let b = ((c.i(axis) - c_min.i(axis)) / extent * SAH_BINS as f32) as usize;
+        b.min(SAH_BINS - 1)
+    };
+
+    // (cost, axis, index of the first bin on the right side)
+    let mut best: Option<(f32, usize, usize)> = None;
+    for axis in 0..3 {
+        if c_max.i(axis) - c_min.i(axis) <= 0.0 {
+            continue;
+        }
+        let mut counts = [0usize; SAH_BINS];
+        let mut boxes: [Option<BoundingBox>; SAH_BINS] = Default::default();
+        for ((bbox, _), c) in items.iter().zip(&centroids) {
+            let b = bin(c, axis);
+            counts[b] += 1;
+            boxes[b] = Some(match &boxes[b] {
+                Some(bin_box) => boundingbox::surrounding_box(bin_box, bbox),
+                None => bbox.clone(),
+            });
+        }
+
+        // sweep from the right to get the cost of all right sides, then from the left
+        let mut right_costs = [0.0; SAH_BINS];
+        let mut right_box: Option<BoundingBox> = None;
+        let mut right_count = 0;
+        for b in (1..SAH_BINS).rev() {
+            right_box = grow(right_box, &boxes[b]);
+            right_count += counts[b];
+            right_costs[b] = match &right_box {
+                Some(bbox) => bbox.area() * right_count as f32,
+                None => 0.0,
+            };
+        }
+        let mut left_box: Option<BoundingBox> = None;
+        let mut left_count = 0;
+        for split in 1..SAH_BINS {
+            left_box = grow(left_box, &boxes[split - 1]);
+            left_count += counts[split - 1];
+            if left_count == 0 || left_count == items.len() {
+                continue;
+            }
+            let cost = left_box.as_ref().map_or(0.0, |bbox| bbox.area()) * left_count as f32
+                + right_costs[split];
+            if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                best = Some((cost, axis, split));
+            }
+        }
+    }
+
+    match best {
+        Some((_, axis, split)) => {
+            let mut left = Vec::with_capacity(items.len());
+            let mut right = Vec::with_capacity(items.len());
+            for (item, c) in items.into_iter().zip(&centroids) {
+                if bin(c, axis) < split {
+                    left.push(item);
                 } else {
-                    None
+                    right.push(item);
                 }
             }
-        } else {
-            None
+            (axis, left, right)
+        }
+        // all centroids coincide, any split is as good as another
+        None => {
+            let right = items.split_off(items.len() / 2);
+            (0, items, right)
         }
     }
+}
+
+fn grow(bbox: Option<BoundingBox>, other: &Option<BoundingBox>) -> Option<BoundingBox> {
+    match (bbox, other) {
+        (Some(bbox), Some(other)) => Some(boundingbox::surrounding_box(&bbox, other)),
+        (None, other) => other.clone(),
+        (bbox, None) => bbox,
+    }
+}
+
+impl Hitable for BvhNode {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rng: &mut dyn RngCore) -> Option<HitRecord<'_>> {
+        self.bvh.hit(r, t_min, t_max, |primitive, t_max| {
+            primitive.hit(r, t_min, t_max, rng)
+        })
+    }
 
     fn bounding_box(&self) -> Option<BoundingBox> {
-        Some(self.bbox.clone())
+        Some(self.bvh.bounding_box().clone())
+    }
+
+    fn collect_lights(&mut self, lights: &mut Vec<Light>) {
+        for primitive in self.bvh.items_mut() {
+            primitive.collect_lights(lights);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hitable::HitableList;
+    use crate::material::Material;
+    use crate::sphere::Sphere;
+    use crate::texture::Texture;
+    use std::sync::Arc;
+
+    fn spheres(rng: &mut StdRng) -> Vec<Box<dyn Hitable>> {
+        let mut list: Vec<Box<dyn Hitable>> = vec![Box::new(Sphere::new(
+            Vec3::new(0.0, -1000.0, 0.0),
+            1000.0,
+            Arc::new(Material::Dielectric { ref_index: 1.5 }),
+        ))];
+        for _ in 0..200 {
+            list.push(Box::new(Sphere::new(
+                Vec3::new(
+                    rng.gen_range(-10.0, 10.0),
+                    rng.gen_range(0.0, 2.0),
+                    rng.gen_range(-10.0, 10.0),
+                ),
+                rng.gen_range(0.1, 0.5),
+                Arc::new(Material::Diffuse {
+                    albedo: Texture::Constant {
+                        color: Vec3::new(0.5, 0.5, 0.5),
+                    },
+                }),
+            )));
+        }
+        list
+    }
+
+    #[test]
+    fn test_builders_match_brute_force() {
+        let mut rng = StdRng::seed_from_u64(8);
+        let brute_force = HitableList {
+            list: spheres(&mut StdRng::seed_from_u64(1)),
+        };
+        let median =
+            BvhNode::with_builder(spheres(&mut StdRng::seed_from_u64(1)), BvhBuilder::Median)
+                .unwrap();
+        let sah =
+            BvhNode::with_builder(spheres(&mut StdRng::seed_from_u64(1)), BvhBuilder::Sah).unwrap();
+
+        for _ in 0..1000 {
+            let origin = Vec3::new(
+                rng.gen_range(-15.0, 15.0),
+                rng.gen_range(0.5, 5.0),
+                rng.gen_range(-15.0, 15.0),
+            );
+            let direction = Vec3::new(
+                rng.gen_range(-1.0, 1.0),
+                rng.gen_range(-1.0, 0.5),
+                rng.gen_range(-1.0, 1.0),
+            );
+            let r = Ray::new(origin, direction);
+            let expected = brute_force
+                .hit(&r, 0.001, f32::MAX, &mut rng)
+                .map(|rec| rec.t);
+            assert_eq!(
+                median.hit(&r, 0.001, f32::MAX, &mut rng).map(|rec| rec.t),
+                expected
+            );
+            assert_eq!(
+                sah.hit(&r, 0.001, f32::MAX, &mut rng).map(|rec| rec.t),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_sah_isolates_large_object() {
+        let items = || -> Items<Box<dyn Hitable>> {
+            spheres(&mut StdRng::seed_from_u64(1))
+                .into_iter()
+                .map(|h| (h.bounding_box().unwrap(), h))
+                .collect()
+        };
+        let (axis, left, right) = split_sah(items());
+        assert_eq!(axis, 1);
+        assert_eq!(left.len(), 1);
+        assert_eq!(right.len(), 200);
+        assert_eq!(left[0].0.min.y(), -2000.0);
+
+        // the same input always gives the same split
+        let (_, _, right_again) = split_sah(items());
+        let centroids = |items: &Items<Box<dyn Hitable>>| -> Vec<Vec3> {
+            items.iter().map(|(bbox, _)| bbox.centroid()).collect()
+        };
+        assert_eq!(centroids(&right), centroids(&right_again));
+    }
+
+    #[test]
+    fn test_deep_tree_fits_stack() {
+        // spheres at exponentially growing distances along the axes, SAH splits peel them
+        // off one or two at a time and would give a tree of 130 levels
+        let spheres = || -> Vec<Box<dyn Hitable>> {
+            (0..186)
+                .map(|i| {
+                    let mut c = Vec3::default();
+                    let sign = if i % 6 < 3 { 1.0 } else { -1.0 };
+                    c.set_i(i as usize % 3, sign * 17f32.powi(i / 6));
+                    Box::new(Sphere::new(
+                        c,
+                        0.1,
+                        Arc::new(Material::Dielectric { ref_index: 1.5 }),
+                    )) as Box<dyn Hitable>
+                })
+                .collect()
+        };
+        let brute_force = HitableList { list: spheres() };
+        let sah = BvhNode::with_builder(spheres(), BvhBuilder::Sah).unwrap();
+        let mut rng = StdRng::seed_from_u64(3);
+        for &z in &[-17.0, -1.0, 1.0, 17.0] {
+            // the spheres close to the origin sit deepest in the tree
+            let r = Ray::new(Vec3::new(10.0, 0.0, z), Vec3::new(-1.0, 0.0, 0.0));
+            assert_eq!(
+                sah.hit(&r, 0.001, f32::MAX, &mut rng).map(|rec| rec.t),
+                brute_force
+                    .hit(&r, 0.001, f32::MAX, &mut rng)
+                    .map(|rec| rec.t)
+            );
+        }
     }
 }
@@ -1,7 +1,7 @@
 use crate::ray::Ray;
 use crate::vec3::Vec3;
 
-use rand::Rng;
+use rand::{Rng, RngCore};
 
 pub struct Camera {
     origin: Vec3,
@@ -11,6 +11,8 @@ pub struct Camera {
     lens_radius: f32,
     u: Vec3,
     v: Vec3,
+    w: Vec3,
+    focus_dist: f32,
 }
 
 impl Camera {
@@ -42,11 +44,13 @@ impl Camera {
             lens_radius: aperture / 2.0,
             u,
             v,
+            w,
+            focus_dist,
         }
     }
 
-    pub fn get_ray(&self, s: f32, t: f32) -> Ray {
-        let rd = random_in_unit_disk() * self.lens_radius;
+    pub fn get_ray(&self, s: f32, t: f32, rng: &mut dyn RngCore) -> Ray {
+        let rd = random_in_unit_disk(rng) * self.lens_radius;
         let offset = rd.x() * self.u + rd.y() * self.v;
         Ray {
             origin: self.origin + offset,
@@ -55,10 +59,40 @@ impl Camera {
                 - offset,
         }
     }
+
+    /// Image coordinates (as passed to [`Camera::get_ray`]) of the point `p` seen from the
+    /// point `lens` on the lens, None if it is outside the image.
+    pub fn project(&self, lens: Vec3, p: Vec3) -> Option<(f32, f32)> {
+        let d = p - lens;
+        let cos = -Vec3::dot(d, self.w);
+        if cos <= 0.0 {
+            return None;
+        }
+        // where the ray through p meets the plane in focus
+        let f = lens + d * (self.focus_dist / cos) - self.lower_left;
+        let s = Vec3::dot(f, self.horizontal) / self.horizontal.len_squared();
+        let t = Vec3::dot(f, self.vertical) / self.vertical.len_squared();
+        if (0.0..=1.0).contains(&s) && (0.0..=1.0).contains(&t) {
+            Some((s, t))
+        } else {
+            None
+        }
+    }
+
+    /// Probability density per solid angle of [`Camera::get_ray`] with uniformly distributed
+    /// image coordinates choosing `direction` from the point `lens` on the lens, zero
+    /// outside the image.
+    pub fn pdf_direction(&self, lens: Vec3, direction: Vec3) -> f32 {
+        if self.project(lens, lens + direction).is_none() {
+            return 0.0;
+        }
+        let cos = -Vec3::dot(direction.normalize(), self.w);
+        let area = self.horizontal.len() * self.vertical.len();
+        self.focus_dist * self.focus_dist / (area * cos * cos * cos)
+    }
 }
 
-fn random_in_unit_disk() -> Vec3 {
-    let mut rng = rand::thread_rng();
+fn random_in_unit_disk(rng: &mut dyn RngCore) -> Vec3 {
     loop {
         let p = Vec3::new(rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0), 0.0);
         if Vec3::dot(p, p) < 1.0 {
@@ -66,3 +100,40 @@ fn random_in_unit_disk() -> Vec3 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_project_and_pdf() {
+        let cam = Camera::new(
+            Vec3::new(1.0, 2.0, 3.0),
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            40.0,
+            2.0,
+            0.5,
+            4.0,
+        );
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut solid_angle = 0.0;
+        for _ in 0..10_000 {
+            let (s, t) = (rng.gen::<f32>(), rng.gen::<f32>());
+            let r = cam.get_ray(s, t, &mut rng);
+            // any point along the ray projects back to where it was generated
+            let (ps, pt) = cam.project(r.origin, r.point(3.0)).unwrap();
+            assert!((ps - s).abs() < 1e-3 && (pt - t).abs() < 1e-3);
+            assert!(cam.project(r.origin, r.point(-1.0)).is_none());
+            solid_angle += 1.0 / cam.pdf_direction(r.origin, r.direction);
+        }
+        // integrating 1 / pdf estimates the solid angle of the image, a rectangle of
+        // 2a x 2b at distance 1
+        let b = 20.0_f32.to_radians().tan();
+        let a = 2.0 * b;
+        let expected = 4.0 * (a * b / ((1.0 + a * a) * (1.0 + b * b)).sqrt()).asin();
+        assert!((solid_angle / 10_000.0 - expected).abs() < 1e-2 * expected);
+    }
+}
@@ -0,0 +1,234 @@
+use crate::error::RaytraceError;
+use crate::framebuffer::Accumulator;
+use crate::render::RenderParams;
+use crate::vec3::Vec3;
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const MAGIC: &[u8; 8] = b"RTCKPT05";
+
+/// Bytes per pixel: sum and squared sum as f32, the number of samples as u64, splat as f32.
+const PIXEL_SIZE: usize = 36;
+
+/// State of an interrupted progressive render: the render parameters (including the seed
+/// all RNGs are derived from), the number of finished passes, the render time spent on them
+/// (counted against a `time_limit` when resuming) and the accumulated samples.
+///
+/// As every pass seeds its RNGs from the seed and the pass index, continuing from a
+/// checkpoint gives the same image as an uninterrupted render.
+pub struct Checkpoint {
+    pub params: RenderParams,
+    pub passes_done: usize,
+    pub elapsed: Duration,
+    pub acc: Accumulator,
+}
+
+/// Checkpoint file belonging to the output image `filename`.
+pub fn path_for(filename: &str) -> PathBuf {
+    PathBuf::from(format!("{}.checkpoint", filename))
+}
+
+impl Checkpoint {
+    /// Write the checkpoint to a temporary file first and rename it afterwards, so that a
+    /// crash while writing leaves the previous checkpoint intact.
+    pub fn save(&self, path: &Path) -> Result<(), RaytraceError> {
+        let io_error = |source| RaytraceError::Io {
+            path: path.to_path_buf(),
+            source,
+        };
+        let params = ron::ser::to_string(&self.params).map_err(|e| RaytraceError::Checkpoint {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        })?;
+
+        let tmp_path = path.with_extension("checkpoint.tmp");
+        let mut w = BufWriter::new(File::create(&tmp_path).map_err(io_error)?);
+        let mut write = |bytes: &[u8]| w.write_all(bytes).map_err(io_error);
+        write(MAGIC)?;
+        write(&(params.len() as u32).to_le_bytes())?;
+        write(params.as_bytes())?;
+        write(&(self.passes_done as u64).to_le_bytes())?;
+        write(&(self.elapsed.as_millis() as u64).to_le_bytes())?;
+        write(&self.acc.width.to_le_bytes())?;
+        write(&self.acc.height.to_le_bytes())?;
+        write(&self.acc.light_paths.to_le_bytes())?;
+        for k in 0..self.acc.sum.len() {
+            for i in 0..3 {
+                write(&self.acc.sum[k].i(i).to_le_bytes())?;
+            }
+            write(&self.acc.sum_sq[k].to_le_bytes())?;
+            write(&self.acc.samples[k].to_le_bytes())?;
+            for i in 0..3 {
+                write(&self.acc.splat[k].i(i).to_le_bytes())?;
+            }
+        }
+        w.flush().map_err(io_error)?;
+        drop(w);
+        std::fs::rename(&tmp_path, path).map_err(io_error)
+    }
+
+    pub fn load(path: &Path) -> Result<Checkpoint, RaytraceError> {
+        let invalid = |message: &str| RaytraceError::Checkpoint {
+            path: path.to_path_buf(),
+            message: message.to_string(),
+        };
+        let io_error = |source| RaytraceError::Io {
+            path: path.to_path_buf(),
+            source,
+        };
+        let file = File::open(path).map_err(io_error)?;
+        let file_len = file.metadata().map_err(io_error)?.len();
+        let mut r = BufReader::new(file);
+        let mut read = |n: usize| {
+            let mut buf = vec![0; n];
+            r.read_exact(&mut buf)
+                .map(|_| buf)
+                .map_err(|_| invalid("unexpected end of file"))
+        };
+        let read_u32 = |b: Vec<u8>| u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+
+        if read(MAGIC.len())? != MAGIC {
+            return Err(invalid("not a checkpoint file"));
+        }
+        let params_len = read_u32(read(4)?);
+        if u64::from(params_len) > file_len {
+            return Err(invalid("unexpected end of file"));
+        }
+        let params = String::from_utf8(read(params_len as usize)?)
+            .map_err(|_| invalid("invalid render parameters"))?;
+        let params: RenderParams =
+            ron::de::from_str(&params).map_err(|e| invalid(&e.to_string()))?;
+        params.validate()?;
+        let read_u64 =
+            |b: Vec<u8>| u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]);
+        let passes_done = read_u64(read(8)?) as usize;
+        let elapsed = Duration::from_millis(read_u64(read(8)?));
+        let width = read_u32(read(4)?);
+        let height = read_u32(read(4)?);
+        if (width, height) != (params.nx, params.ny) {
+            return Err(invalid("image size does not match the render parameters"));
+        }
+        // the header up to the pixels, which have to fill the rest of the file
+        let header_len = MAGIC.len() as u64 + 4 + u64::from(params_len) + 8 + 8 + 4 + 4 + 8;
+        let expected_len = u64::from(width)
+            .checked_mul(u64::from(height))
+            .and_then(|n| n.checked_mul(PIXEL_SIZE as u64))
+            .and_then(|n| n.checked_add(header_len));
+        if expected_len != Some(file_len) {
+            return Err(invalid("file size does not match the image size"));
+        }
+
+        let mut acc = Accumulator::new(width, height);
+        acc.light_paths = read_u64(read(8)?);
+        let pixels = read(acc.sum.len() * PIXEL_SIZE)?;
+        for (k, p) in pixels.chunks_exact(PIXEL_SIZE).enumerate() {
+            let f = |i: usize| f32::from_le_bytes([p[i], p[i + 1], p[i + 2], p[i + 3]]);
+            acc.sum[k] = Vec3::new(f(0), f(4), f(8));
+            acc.sum_sq[k] = f(12);
+            acc.samples[k] = read_u64(p[16..24].to_vec());
+            acc.splat[k] = Vec3::new(f(24), f(28), f(32));
+        }
+
+        Ok(Checkpoint {
+            params,
+            passes_done,
+            elapsed,
+            acc,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_load() {
+        let mut acc = Accumulator::new(3, 2);
+        acc.add(2, 1, Vec3::new(0.1, 2.5, 1e-7), 3.25, 17);
+        acc.splat(0, 1, Vec3::new(4.0, 0.0, 0.5));
+        acc.light_paths = 102;
+        let checkpoint = Checkpoint {
+            params: RenderParams {
+                nx: 3,
+                ny: 2,
+                seed: 42,
+                ..Default::default()
+            },
+            passes_done: 5,
+            elapsed: Duration::from_millis(90_061_001),
+            acc,
+        };
+        let path =
+            std::env::temp_dir().join(format!("raytrace_test_{}.checkpoint", std::process::id()));
+        checkpoint.save(&path).unwrap();
+        let loaded = Checkpoint::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.params.seed, 42);
+        assert_eq!(loaded.passes_done, 5);
+        assert_eq!(loaded.elapsed, checkpoint.elapsed);
+        assert_eq!(loaded.acc.sum, checkpoint.acc.sum);
+        assert_eq!(loaded.acc.sum_sq, checkpoint.acc.sum_sq);
+        assert_eq!(loaded.acc.samples, checkpoint.acc.samples);
+        assert_eq!(loaded.acc.splat, checkpoint.acc.splat);
+        assert_eq!(loaded.acc.light_paths, 102);
+    }
+
+    #[test]
+    fn test_invalid_size() {
+        let params = RenderParams {
+            nx: 65536,
+            ny: 65536,
+            ..Default::default()
+        };
+        // the size of a huge image, without its pixels
+        let checkpoint = Checkpoint {
+            params: params.clone(),
+            passes_done: 1,
+            elapsed: Duration::default(),
+            acc: Accumulator {
+                width: params.nx,
+                height: params.ny,
+                sum: vec![],
+                sum_sq: vec![],
+                samples: vec![],
+                splat: vec![],
+                light_paths: 0,
+            },
+        };
+        let path = std::env::temp_dir().join(format!(
+            "raytrace_test_invalid_size_{}.checkpoint",
+            std::process::id()
+        ));
+        checkpoint.save(&path).unwrap();
+        assert!(Checkpoint::load(&path).is_err());
+
+        // a huge length of the render parameters
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(Checkpoint::load(&path).is_err());
+
+        // a valid checkpoint cut short
+        Checkpoint {
+            params: RenderParams {
+                nx: 2,
+                ny: 2,
+                ..Default::default()
+            },
+            passes_done: 1,
+            elapsed: Duration::default(),
+            acc: Accumulator::new(2, 2),
+        }
+        .save(&path)
+        .unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(Checkpoint::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,306 @@
+use crate::aov::Aov;
+use crate::error::RaytraceError;
+use crate::framebuffer::Framebuffer;
+use crate::vec3::Vec3;
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Edge-avoiding à-trous wavelet filter (Dammertz et al. 2010): a 5x5 B-spline kernel
+/// applied with holes of 1, 2, 4, ... pixels, whose weights drop where the color, albedo,
+/// normal or depth of two pixels differ.
+///
+/// The color is divided by the albedo before filtering and multiplied afterwards, so
+/// textures stay sharp while the lighting gets smoothed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Denoiser {
+    /// number of filter passes, each doubling the spacing of the kernel taps
+    pub iterations: u32,
+    /// color difference tolerated in the first pass, halved in each following one
+    pub sigma_color: f32,
+    pub sigma_albedo: f32,
+    pub sigma_normal: f32,
+    /// depth difference tolerated per pixel of distance, relative to the depth
+    pub sigma_depth: f32,
+}
+
+impl Default for Denoiser {
+    fn default() -> Denoiser {
+        Denoiser {
+            iterations: 5,
+            sigma_color: 2.0,
+            sigma_albedo: 0.1,
+            sigma_normal: 0.2,
+            sigma_depth: 0.05,
+        }
+    }
+}
+
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Smallest albedo the color is divided by, to keep black surfaces from blowing up.
+const MIN_ALBEDO: f32 = 0.01;
+
+/// Most filter passes, the last one has holes of 32768 pixels.
+const MAX_ITERATIONS: u32 = 16;
+
+impl Denoiser {
+    /// Check that there are 1 to 16 iterations and all tolerances are positive.
+    pub fn validate(&self) -> Result<(), RaytraceError> {
+        let invalid = |name: &str, value: &dyn ToString| {
+            Err(RaytraceError::InvalidParameter {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+        };
+        let positive = |sigma: f32| sigma.is_finite() && sigma > 0.0;
+        if !(1..=MAX_ITERATIONS).contains(&self.iterations) {
+            invalid("iterations", &self.iterations)
+        } else if !positive(self.sigma_color) {
+            invalid("sigma-color", &self.sigma_color)
+        } else if !positive(self.sigma_albedo) {
+            invalid("sigma-albedo", &self.sigma_albedo)
+        } else if !positive(self.sigma_normal) {
+            invalid("sigma-normal", &self.sigma_normal)
+        } else if !positive(self.sigma_depth) {
+            invalid("sigma-depth", &self.sigma_depth)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Denoise `color`, guided by the `Albedo`, `Normal` and `Depth` AOVs among `features`.
+    /// Missing features are simply not taken into account.
+    pub fn denoise(&self, color: &Framebuffer, features: &[(Aov, Framebuffer)]) -> Framebuffer {
+        let feature = |aov| {
+            features
+                .iter()
+                .find(|(a, fb)| *a == aov && (fb.width, fb.height) == (color.width, color.height))
+                .map(|(_, fb)| fb)
+        };
+        let albedo = feature(Aov::Albedo);
+        let normal = feature(Aov::Normal);
+        let depth = feature(Aov::Depth);
+
+        let demodulate = |k: usize| match albedo {
+            Some(albedo) => color.data[k] / clamp_albedo(albedo.data[k]),
+            None => color.data[k],
+        };
+        let mut current = Framebuffer {
+            width: color.width,
+            height: color.height,
+            data: (0..color.data.len()).map(demodulate).collect(),
+        };
+        // holes wider than the image skip every tap but the center
+        let max_step = color.width.max(color.height).max(1);
+        for i in 0..self.iterations {
+            let step = 1u32
+                .checked_shl(i)
+                .map_or(max_step, |step| step.min(max_step));
+            current = self.filter_pass(&current, step as i32, albedo, normal, depth);
+        }
+        if let Some(albedo) = albedo {
+            for (c, &a) in current.data.iter_mut().zip(&albedo.data) {
+                *c *= clamp_albedo(a);
+            }
+        }
+        current
+    }
+
+    fn filter_pass(
+        &self,
+        fb: &Framebuffer,
+        step: i32,
+        albedo: Option<&Framebuffer>,
+        normal: Option<&Framebuffer>,
+        depth: Option<&Framebuffer>,
+    ) -> Framebuffer {
+        let (width, height) = (fb.width as i32, fb.height as i32);
+        let sigma_color = self.sigma_color / step as f32;
+        let rows: Vec<Vec<Vec3>> = (0..height)
+            .into_par_iter()
+            .map(|y| {
+                (0..width)
+                    .map(|x| {
+                        let p = (y * width + x) as usize;
+                        let cp = compress(fb.data[p]);
+                        let mut sum = Vec3::default();
+                        let mut weights = 0.0;
+                        for (j, ky) in KERNEL.iter().enumerate() {
+                            for (i, kx) in KERNEL.iter().enumerate() {
+                                let qx = x + (i as i32 - 2) * step;
+                                let qy = y + (j as i32 - 2) * step;
+                                if qx < 0 || qx >= width || qy < 0 || qy >= height {
+                                    continue;
+                                }
+                                let q = (qy * width + qx) as usize;
+                                let mut exponent =
+                                    distance_sq(cp, compress(fb.data[q])) / sq(sigma_color);
+                                if let Some(albedo) = albedo {
+                                    exponent += distance_sq(albedo.data[p], albedo.data[q])
+                                        / sq(self.sigma_albedo);
+                                }
+                                if let Some(normal) = normal {
+                                    exponent += distance_sq(normal.data[p], normal.data[q])
+                                        / sq(self.sigma_normal);
+                                }
+                                if let Some(depth) = depth {
+                                    let (zp, zq) = (depth.data[p].x(), depth.data[q].x());
+                                    let pixels = (step
+                                        * (i as i32 - 2).abs().max((j as i32 - 2).abs()))
+                                        as f32;
+                                    exponent += (zp - zq).abs()
+                                        / (self.sigma_depth * pixels * zp.abs()).max(1e-6);
+                                }
+                                let w = kx * ky * (-exponent).exp();
+                                sum += w * fb.data[q];
+                                weights += w;
+                            }
+                        }
+                        sum / weights
+                    })
+                    .collect()
+            })
+            .collect();
+        Framebuffer {
+            width: fb.width,
+            height: fb.height,
+            data: rows.into_iter().flatten().collect(),
+        }
+    }
+}
+
+fn clamp_albedo(a: Vec3) -> Vec3 {
+    Vec3::new(
+        a.x().max(MIN_ALBEDO),
+        a.y().max(MIN_ALBEDO),
+        a.z().max(MIN_ALBEDO),
+    )
+}
+
+/// Map radiance into 0 ... 1, so that color differences of bright pixels do not dominate.
+fn compress(c: Vec3) -> Vec3 {
+    Vec3::new(
+        c.x() / (1.0 + c.x()),
+        c.y() / (1.0 + c.y()),
+        c.z() / (1.0 + c.z()),
+    )
+}
+
+fn distance_sq(a: Vec3, b: Vec3) -> f32 {
+    (a - b).len_squared()
+}
+
+fn sq(x: f32) -> f32 {
+    x * x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_validate() {
+        assert!(Denoiser::default().validate().is_ok());
+        for &iterations in &[0, 17, 40] {
+            let denoiser = Denoiser {
+                iterations,
+                ..Denoiser::default()
+            };
+            assert!(denoiser.validate().is_err());
+        }
+        for &sigma in &[0.0, -1.0, f32::NAN, f32::INFINITY] {
+            let denoiser = Denoiser {
+                sigma_color: sigma,
+                ..Denoiser::default()
+            };
+            assert!(denoiser.validate().is_err());
+            let denoiser = Denoiser {
+                sigma_depth: sigma,
+                ..Denoiser::default()
+            };
+            assert!(denoiser.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_many_iterations() {
+        // the holes stop growing at the size of the image
+        let mut color = Framebuffer::new(4, 4);
+        color.set(1, 2, Vec3::new(1.0, 1.0, 1.0));
+        let denoiser = Denoiser {
+            iterations: 40,
+            ..Denoiser::default()
+        };
+        let denoised = denoiser.denoise(&color, &[]);
+        assert!(denoised.data.iter().all(|c| c.x().is_finite()));
+    }
+
+    fn variance(values: &[f32]) -> f32 {
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        values.iter().map(|v| sq(v - mean)).sum::<f32>() / values.len() as f32
+    }
+
+    #[test]
+    fn test_denoise_keeps_edges() {
+        // a noisy image of two surfaces, bright on the left facing +x, dark on the right
+        // facing +y
+        let (width, height) = (32, 16);
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut color = Framebuffer::new(width, height);
+        let mut normal = Framebuffer::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let left = x < width / 2;
+                let level = if left { 0.8 } else { 0.2 };
+                let l = level * rng.gen_range(0.5, 1.5);
+                color.set(x, y, Vec3::new(l, l, l));
+                let n = if left {
+                    Vec3::new(1.0, 0.0, 0.0)
+                } else {
+                    Vec3::new(0.0, 1.0, 0.0)
+                };
+                normal.set(x, y, n);
+            }
+        }
+
+        let denoised = Denoiser::default().denoise(&color, &[(Aov::Normal, normal)]);
+        let side = |fb: &Framebuffer, left: bool| -> Vec<f32> {
+            (0..height)
+                .flat_map(|y| (0..width).map(move |x| (x, y)))
+                .filter(|&(x, _)| (x < width / 2) == left)
+                .map(|(x, y)| fb.get(x, y).x())
+                .collect()
+        };
+        for &left in &[true, false] {
+            let level = if left { 0.8 } else { 0.2 };
+            let before = side(&color, left);
+            let after = side(&denoised, left);
+            assert!(variance(&after) < 0.1 * variance(&before));
+            // no bleeding across the edge
+            let mean = after.iter().sum::<f32>() / after.len() as f32;
+            assert!((mean - level).abs() < 0.05 * level, "{} {}", left, mean);
+        }
+    }
+
+    #[test]
+    fn test_denoise_keeps_texture() {
+        // a constant lighting on a checkerboard albedo stays as it is
+        let mut color = Framebuffer::new(8, 8);
+        let mut albedo = Framebuffer::new(8, 8);
+        for y in 0..8 {
+            for x in 0..8 {
+                let a = if (x + y) % 2 == 0 { 0.9 } else { 0.1 };
+                albedo.set(x, y, Vec3::new(a, a, a));
+                color.set(x, y, Vec3::new(2.0 * a, 2.0 * a, 2.0 * a));
+            }
+        }
+        let denoised = Denoiser::default().denoise(&color, &[(Aov::Albedo, albedo)]);
+        for (a, b) in color.data.iter().zip(&denoised.data) {
+            assert!((*a - *b).len() < 1e-4);
+        }
+    }
+}
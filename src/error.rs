@@ -0,0 +1,113 @@
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum RaytraceError {
+    /// a file could not be read or written
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// the scene file is not valid RON or does not match the scene description
+    SceneParse { path: PathBuf, source: ron::Error },
+    /// a `Named` material refers to an entry missing from the `materials` table
+    UnknownMaterial(String),
+    /// a `Named` material refers to another `Named` material
+    NestedMaterial(String),
+    /// an image could not be loaded or saved
+    Image {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+    /// a mesh file could not be loaded
+    MeshLoad {
+        path: PathBuf,
+        source: tobj::LoadError,
+    },
+    /// an OBJ file contains invalid data
+    InvalidObj { path: PathBuf, message: String },
+    /// a glTF file could not be loaded
+    Gltf {
+        path: PathBuf,
+        source: ::gltf::Error,
+    },
+    /// a glTF file contains invalid data
+    InvalidGltf { path: PathBuf, message: String },
+    /// a glTF file loaded as scene does not contain a camera
+    NoCamera { path: PathBuf },
+    /// a PLY file is malformed
+    PlyParse { path: PathBuf, message: String },
+    /// a checkpoint file is malformed
+    Checkpoint { path: PathBuf, message: String },
+    /// a float image cannot be read
+    InvalidImage { path: PathBuf, message: String },
+    /// an object without bounding box was put into a BVH
+    Unbounded,
+    /// a BVH was built from an empty list of objects
+    EmptyBvh,
+    /// a render parameter has an invalid value
+    InvalidParameter { name: String, value: String },
+}
+
+impl fmt::Display for RaytraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RaytraceError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            RaytraceError::SceneParse { path, source } => {
+                write!(f, "{}: invalid scene file: {}", path.display(), source)
+            }
+            RaytraceError::UnknownMaterial(name) => write!(f, "unknown material: {}", name),
+            RaytraceError::NestedMaterial(name) => write!(
+                f,
+                "material {}: named materials cannot refer to other names",
+                name
+            ),
+            RaytraceError::Image { path, source } => {
+                write!(f, "{}: {}", path.display(), source)
+            }
+            RaytraceError::MeshLoad { path, source } => {
+                write!(f, "{}: {}", path.display(), source)
+            }
+            RaytraceError::InvalidObj { path, message } => {
+                write!(f, "{}: invalid OBJ file: {}", path.display(), message)
+            }
+            RaytraceError::Gltf { path, source } => write!(f, "{}: {}", path.display(), source),
+            RaytraceError::InvalidGltf { path, message } => {
+                write!(f, "{}: invalid glTF file: {}", path.display(), message)
+            }
+            RaytraceError::NoCamera { path } => {
+                write!(f, "{}: the glTF scene has no camera", path.display())
+            }
+            RaytraceError::PlyParse { path, message } => {
+                write!(f, "{}: invalid PLY file: {}", path.display(), message)
+            }
+            RaytraceError::Checkpoint { path, message } => {
+                write!(f, "{}: invalid checkpoint: {}", path.display(), message)
+            }
+            RaytraceError::InvalidImage { path, message } => {
+                write!(f, "{}: cannot read image: {}", path.display(), message)
+            }
+            RaytraceError::Unbounded => {
+                write!(f, "objects without bounding box cannot be put into a BVH")
+            }
+            RaytraceError::EmptyBvh => write!(f, "cannot build a BVH from an empty scene"),
+            RaytraceError::InvalidParameter { name, value } => {
+                write!(f, "invalid value for {}: {}", name, value)
+            }
+        }
+    }
+}
+
+impl Error for RaytraceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RaytraceError::Io { source, .. } => Some(source),
+            RaytraceError::SceneParse { source, .. } => Some(source),
+            RaytraceError::Image { source, .. } => Some(source),
+            RaytraceError::MeshLoad { source, .. } => Some(source),
+            RaytraceError::Gltf { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
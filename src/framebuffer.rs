@@ -0,0 +1,200 @@
+use crate::tonemap::ToneMapping;
+use crate::vec3::Vec3;
+
+use image::{GrayImage, ImageBuffer, RgbImage};
+
+/// Linear (HDR) radiance of a rendered image, stored row by row starting top left.
+#[derive(Clone)]
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<Vec3>,
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            data: vec![Vec3::default(); (width * height) as usize],
+        }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> Vec3 {
+        self.data[self.index(x, y)]
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, c: Vec3) {
+        let index = self.index(x, y);
+        self.data[index] = c;
+    }
+
+    /// Convert to an 8 bit RGB image with the given tone mapping.
+    pub fn to_rgb8(&self, tone_mapping: &ToneMapping) -> RgbImage {
+        ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            image::Rgb(tone_mapping.map(self.get(x, y), x, y))
+        })
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        (y * self.width + x) as usize
+    }
+}
+
+/// Running sums of radiance samples and of their squared luminance together with the number
+/// of samples taken per pixel, row by row starting top left. The squared sums give the
+/// variance of every pixel, see [`Accumulator::relative_error`].
+#[derive(Clone)]
+pub struct Accumulator {
+    pub width: u32,
+    pub height: u32,
+    pub sum: Vec<Vec3>,
+    pub sum_sq: Vec<f32>,
+    pub samples: Vec<u64>,
+    /// light carried to the pixels by light subpaths of the bidirectional integrator
+    pub splat: Vec<Vec3>,
+    /// number of light subpaths traced, each of them contributes `splat` for the image as
+    /// a whole rather than a sample of a single pixel
+    pub light_paths: u64,
+}
+
+impl Accumulator {
+    pub fn new(width: u32, height: u32) -> Accumulator {
+        let n = width as usize * height as usize;
+        Accumulator {
+            width,
+            height,
+            sum: vec![Vec3::default(); n],
+            sum_sq: vec![0.0; n],
+            samples: vec![0; n],
+            splat: vec![Vec3::default(); n],
+            light_paths: 0,
+        }
+    }
+
+    /// Add the sum `c` of `count` samples, whose squared luminances sum up to `sum_sq`, to
+    /// pixel (x, y).
+    pub fn add(&mut self, x: u32, y: u32, c: Vec3, sum_sq: f32, count: u64) {
+        let index = (y * self.width + x) as usize;
+        self.sum[index] += c;
+        self.sum_sq[index] += sum_sq;
+        self.samples[index] += count;
+    }
+
+    /// Add light reaching pixel (x, y) from a light subpath.
+    pub fn splat(&mut self, x: u32, y: u32, c: Vec3) {
+        self.splat[(y * self.width + x) as usize] += c;
+    }
+
+    /// Standard error of the mean luminance of pixel (x, y) relative to the mean, infinite
+    /// while fewer than two samples are taken. Black pixels are compared to a small floor
+    /// instead, so that they count as converged once their samples agree.
+    ///
+    /// Only the samples of the pixel itself count: the `splat` of the bidirectional
+    /// integrator is an estimate for the whole image, not a sample of the pixel, so light
+    /// that only light subpaths carry to the pixel does not keep it from converging.
+    pub fn relative_error(&self, x: u32, y: u32) -> f32 {
+        let index = (y * self.width + x) as usize;
+        let n = self.samples[index] as f32;
+        if n < 2.0 {
+            return f32::INFINITY;
+        }
+        let mean = luminance(self.sum[index]) / n;
+        let variance = (self.sum_sq[index] / n - mean * mean).max(0.0) * n / (n - 1.0);
+        (variance / n).sqrt() / mean.max(1e-3)
+    }
+
+    /// The mean of the samples of every pixel, black where nothing was sampled yet, plus the
+    /// splatted light averaged over the light subpaths.
+    pub fn to_framebuffer(&self) -> Framebuffer {
+        let splat_scale = if self.light_paths > 0 {
+            self.sum.len() as f32 / self.light_paths as f32
+        } else {
+            0.0
+        };
+        Framebuffer {
+            width: self.width,
+            height: self.height,
+            data: self
+                .sum
+                .iter()
+                .zip(&self.samples)
+                .zip(&self.splat)
+                .map(|((&sum, &n), &splat)| {
+                    let mean = if n > 0 { sum / n as f32 } else { sum };
+                    mean + splat * splat_scale
+                })
+                .collect(),
+        }
+    }
+
+    /// Grayscale map of the samples taken per pixel, scaled so that the most sampled pixel
+    /// is white.
+    pub fn to_sample_map(&self) -> GrayImage {
+        let max = self.samples.iter().copied().max().unwrap_or(0).max(1) as f32;
+        ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            let n = self.samples[(y * self.width + x) as usize] as f32;
+            image::Luma([(255.0 * n / max).round() as u8])
+        })
+    }
+}
+
+/// Relative luminance of a linear RGB color (Rec. 709 primaries).
+pub fn luminance(c: Vec3) -> f32 {
+    0.2126 * c.x() + 0.7152 * c.y() + 0.0722 * c.z()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_rgb8() {
+        let mut fb = Framebuffer::new(2, 1);
+        fb.set(1, 0, Vec3::new(0.25, 1.0, 15.0));
+        let img = fb.to_rgb8(&ToneMapping {
+            gamma: Some(2.0),
+            ..Default::default()
+        });
+        assert_eq!(img.get_pixel(0, 0), &image::Rgb([0, 0, 0]));
+        assert_eq!(img.get_pixel(1, 0), &image::Rgb([128, 255, 255]));
+    }
+
+    #[test]
+    fn test_accumulator() {
+        let mut acc = Accumulator::new(2, 1);
+        acc.add(0, 0, Vec3::new(1.0, 2.0, 3.0), 0.0, 1);
+        acc.add(0, 0, Vec3::new(3.0, 2.0, 1.0), 0.0, 3);
+        let fb = acc.to_framebuffer();
+        assert_eq!(fb.get(0, 0), Vec3::new(1.0, 1.0, 1.0));
+        assert_eq!(fb.get(1, 0), Vec3::default());
+
+        // two light subpaths for two pixels
+        acc.splat(1, 0, Vec3::new(0.5, 0.0, 0.0));
+        acc.light_paths = 2;
+        assert_eq!(acc.to_framebuffer().get(1, 0), Vec3::new(0.5, 0.0, 0.0));
+
+        let map = acc.to_sample_map();
+        assert_eq!(map.get_pixel(0, 0), &image::Luma([255]));
+        assert_eq!(map.get_pixel(1, 0), &image::Luma([0]));
+    }
+
+    #[test]
+    fn test_relative_error() {
+        let gray = |l: f32| Vec3::new(l, l, l);
+        let mut acc = Accumulator::new(3, 1);
+        // constant samples
+        for _ in 0..4 {
+            acc.add(0, 0, gray(0.5), 0.25, 1);
+        }
+        assert!(acc.relative_error(0, 0) < 1e-3);
+        // samples 0 and 1: mean 0.5, variance 0.5, standard error 0.5
+        acc.add(1, 0, gray(0.0), 0.0, 1);
+        acc.add(1, 0, gray(1.0), 1.0, 1);
+        assert!((acc.relative_error(1, 0) - 1.0).abs() < 1e-3);
+        // black
+        acc.add(2, 0, gray(0.0), 0.0, 2);
+        assert_eq!(acc.relative_error(2, 0), 0.0);
+        assert_eq!(Accumulator::new(1, 1).relative_error(0, 0), f32::INFINITY);
+    }
+}
@@ -0,0 +1,486 @@
+use crate::error::RaytraceError;
+use crate::hitable::{Hitable, HitableList};
+use crate::material::Material;
+use crate::scenefile::CameraDesc;
+use crate::texture::Texture;
+use crate::transform::{RotateXYZ, Scale, Translate};
+use crate::triangle::TriangleMesh;
+use crate::vec3::Vec3;
+
+use image::RgbImage;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+struct Context<'a> {
+    path: &'a Path,
+    buffers: &'a [::gltf::buffer::Data],
+    materials: Vec<Arc<Material>>,
+}
+
+/// Load the meshes of the default scene (or the first scene) of a glTF 2.0 file.
+///
+/// Every mesh primitive becomes a triangle mesh, node transforms are applied by wrapping
+/// the node contents into `Scale`, `RotateXYZ` and `Translate`. The metallic-roughness
+/// materials are mapped onto [`Material`]:
+///
+/// * an emissive factor (times `KHR_materials_emissive_strength`) gives a `DiffuseLight`
+/// * transmission or alpha blending gives a `Dielectric` with the `KHR_materials_ior`
+///   refraction index
+/// * a rough non-metal gives a `Diffuse`, everything else a `Microfacet`
+///
+/// The base color texture is multiplied by the base color factor if present.
+pub fn load(path: &Path) -> Result<HitableList, RaytraceError> {
+    let (document, buffers, images) =
+        ::gltf::import(path).map_err(|source| gltf_error(path, source))?;
+
+    let mut textures = HashMap::new();
+    let materials = document
+        .materials()
+        .map(|m| Arc::new(convert_material(&m, &images, &mut textures)))
+        .collect();
+    let ctx = Context {
+        path,
+        buffers: &buffers,
+        materials,
+    };
+
+    let mut hl = HitableList { list: vec![] };
+    if let Some(scene) = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+    {
+        for node in scene.nodes() {
+            if let Some(h) = build_node(&node, &ctx)? {
+                hl.list.push(h);
+            }
+        }
+    }
+    Ok(hl)
+}
+
+/// Read the first perspective camera of the default scene (or the first scene).
+pub fn load_camera(path: &Path) -> Result<Option<CameraDesc>, RaytraceError> {
+    let gltf = ::gltf::Gltf::open(path).map_err(|source| gltf_error(path, source))?;
+    let scene = match gltf.default_scene().or_else(|| gltf.scenes().next()) {
+        Some(scene) => scene,
+        None => return Ok(None),
+    };
+    for node in scene.nodes() {
+        if let Some(camera) = find_camera(&node, IDENTITY) {
+            return Ok(Some(camera));
+        }
+    }
+    Ok(None)
+}
+
+fn gltf_error(path: &Path, source: ::gltf::Error) -> RaytraceError {
+    RaytraceError::Gltf {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+fn build_node(
+    node: &::gltf::Node,
+    ctx: &Context,
+) -> Result<Option<Box<dyn Hitable>>, RaytraceError> {
+    let mut list: Vec<Box<dyn Hitable>> = vec![];
+    if let Some(mesh) = node.mesh() {
+        for primitive in mesh.primitives() {
+            if let Some(mesh) = build_primitive(&primitive, ctx)? {
+                list.push(Box::new(mesh));
+            }
+        }
+    }
+    for child in node.children() {
+        if let Some(h) = build_node(&child, ctx)? {
+            list.push(h);
+        }
+    }
+
+    let mut h: Box<dyn Hitable> = match list.len() {
+        0 => return Ok(None),
+        1 => list.pop().unwrap(),
+        _ => Box::new(HitableList { list }.into_bvh()?),
+    };
+
+    // the local transform is T * R * S, so scale first and translate last
+    let (translation, rotation, scale) = node.transform().decomposed();
+    if scale.contains(&0.0) {
+        return Err(invalid(
+            ctx,
+            format!("node {} has a zero scale", node.index()),
+        ));
+    }
+    if scale != [1.0, 1.0, 1.0] {
+        h = Box::new(Scale::new(h, Vec3::new(scale[0], scale[1], scale[2])));
+    }
+    if rotation != [0.0, 0.0, 0.0, 1.0] {
+        h = Box::new(RotateXYZ::from_quaternion(h, rotation));
+    }
+    if translation != [0.0, 0.0, 0.0] {
+        h = Box::new(Translate::new(
+            h,
+            Vec3::new(translation[0], translation[1], translation[2]),
+        ));
+    }
+    Ok(Some(h))
+}
+
+fn invalid(ctx: &Context, message: String) -> RaytraceError {
+    RaytraceError::InvalidGltf {
+        path: ctx.path.to_path_buf(),
+        message,
+    }
+}
+
+fn build_primitive(
+    primitive: &::gltf::Primitive,
+    ctx: &Context,
+) -> Result<Option<TriangleMesh>, RaytraceError> {
+    if primitive.mode() != ::gltf::mesh::Mode::Triangles {
+        return Ok(None);
+    }
+    let reader = primitive.reader(|buffer| Some(&ctx.buffers[buffer.index()]));
+
+    let positions: Vec<Vec3> = match reader.read_positions() {
+        Some(positions) => positions.map(|p| Vec3::new(p[0], p[1], p[2])).collect(),
+        None => return Ok(None),
+    };
+    let normals = match reader.read_normals() {
+        Some(normals) => normals.map(|n| Vec3::new(n[0], n[1], n[2])).collect(),
+        None => vec![],
+    };
+    // glTF places the texture origin top left, the image texture expects it bottom left
+    let uvs = match reader.read_tex_coords(0) {
+        Some(uvs) => uvs.into_f32().map(|uv| (uv[0], 1.0 - uv[1])).collect(),
+        None => vec![],
+    };
+    let indices: Vec<u32> = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect(),
+        None => (0..positions.len() as u32).collect(),
+    };
+    if let Some(i) = indices.iter().find(|&&i| i as usize >= positions.len()) {
+        return Err(invalid(
+            ctx,
+            format!(
+                "vertex index {} out of range for {} vertices",
+                i,
+                positions.len()
+            ),
+        ));
+    }
+
+    let material = match primitive.material().index() {
+        Some(index) => ctx.materials[index].clone(),
+        None => Arc::new(default_material()),
+    };
+    let mesh = TriangleMesh::new(
+        positions,
+        normals,
+        uvs,
+        indices
+            .chunks_exact(3)
+            .map(|idx| [idx[0], idx[1], idx[2]])
+            .collect(),
+        material,
+    );
+    Ok(if mesh.is_empty() { None } else { Some(mesh) })
+}
+
+fn convert_material(
+    m: &::gltf::Material,
+    images: &[::gltf::image::Data],
+    textures: &mut HashMap<usize, Texture>,
+) -> Material {
+    let pbr = m.pbr_metallic_roughness();
+    let base_color = pbr.base_color_factor();
+    let emission = m.emissive_factor();
+    let emission =
+        m.emissive_strength().unwrap_or(1.0) * Vec3::new(emission[0], emission[1], emission[2]);
+    let transmission = m.transmission().map_or(0.0, |t| t.transmission_factor());
+
+    if emission.x().max(emission.y()).max(emission.z()) > 0.0 {
+        return Material::DiffuseLight {
+            emit: Texture::Constant { color: emission },
+        };
+    }
+    if transmission > 0.0 || m.alpha_mode() == ::gltf::material::AlphaMode::Blend {
+        return Material::Dielectric {
+            ref_index: m.ior().unwrap_or(1.5),
+        };
+    }
+
+    let base_color = Vec3::new(base_color[0], base_color[1], base_color[2]);
+    let albedo = match pbr.base_color_texture().and_then(|info| {
+        let index = info.texture().source().index();
+        if let Some(texture) = textures.get(&index) {
+            return Some(texture.clone());
+        }
+        let texture = Texture::Image {
+            image: Box::new(convert_image(images.get(index)?)),
+        };
+        textures.insert(index, texture.clone());
+        Some(texture)
+    }) {
+        Some(texture) if base_color == Vec3::new(1.0, 1.0, 1.0) => texture,
+        Some(texture) => Texture::Tinted {
+            color: base_color,
+            texture: Box::new(texture),
+        },
+        None => Texture::Constant { color: base_color },
+    };
+    let metallic = pbr.metallic_factor();
+    let roughness = pbr.roughness_factor();
+
+    if metallic <= 0.0 && roughness >= 1.0 {
+        Material::Diffuse { albedo }
+    } else {
+        Material::Microfacet {
+            albedo,
+            metallic,
+            roughness,
+        }
+    }
+}
+
+/// Convert decoded glTF image data of any channel layout into 8 bit RGB.
+fn convert_image(data: &::gltf::image::Data) -> RgbImage {
+    use ::gltf::image::Format;
+
+    let (channels, bytes) = match data.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 => (3, 1),
+        Format::R8G8B8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+        Format::R32G32B32FLOAT => (3, 4),
+        Format::R32G32B32A32FLOAT => (4, 4),
+    };
+    let channel = |c: &[u8]| -> u8 {
+        match c.len() {
+            1 => c[0],
+            // little endian 16 bit, keep the high byte
+            2 => c[1],
+            _ => (f32::from_le_bytes([c[0], c[1], c[2], c[3]]).clamp(0.0, 1.0) * 255.0) as u8,
+        }
+    };
+
+    let mut pixels = Vec::with_capacity((data.width * data.height * 3) as usize);
+    for pixel in data.pixels.chunks_exact(channels * bytes) {
+        let r = channel(&pixel[..bytes]);
+        match channels {
+            // two channel images are luminance + alpha
+            1 | 2 => pixels.extend_from_slice(&[r, r, r]),
+            _ => {
+                pixels.push(r);
+                pixels.push(channel(&pixel[bytes..2 * bytes]));
+                pixels.push(channel(&pixel[2 * bytes..3 * bytes]));
+            }
+        }
+    }
+    RgbImage::from_raw(data.width, data.height, pixels)
+        .unwrap_or_else(|| RgbImage::new(data.width.max(1), data.height.max(1)))
+}
+
+type Matrix = [[f32; 4]; 4];
+
+const IDENTITY: Matrix = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+/// Product of two column-major 4x4 matrices.
+fn mul(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut m = [[0.0; 4]; 4];
+    for (col, m_col) in m.iter_mut().enumerate() {
+        for (row, m_el) in m_col.iter_mut().enumerate() {
+            *m_el = (0..4).map(|k| a[k][row] * b[col][k]).sum();
+        }
+    }
+    m
+}
+
+/// Search the node tree depth first for a perspective camera, `parent` is the world matrix
+/// of the parent node.
+fn find_camera(node: &::gltf::Node, parent: Matrix) -> Option<CameraDesc> {
+    let world = mul(&parent, &node.transform().matrix());
+    if let Some(camera) = node.camera() {
+        if let ::gltf::camera::Projection::Perspective(p) = camera.projection() {
+            // glTF cameras look down -z with +y up in their local frame
+            let axis = |c: usize| Vec3::new(world[c][0], world[c][1], world[c][2]);
+            let look_from = axis(3);
+            return Some(CameraDesc {
+                look_from,
+                look_at: look_from - axis(2).normalize(),
+                vup: axis(1).normalize(),
+                vfov: p.yfov().to_degrees(),
+                aperture: 0.0,
+                focus_dist: 1.0,
+            });
+        }
+    }
+    node.children().find_map(|child| find_camera(&child, world))
+}
+
+fn default_material() -> Material {
+    Material::Diffuse {
+        albedo: Texture::Constant {
+            color: Vec3::new(0.73, 0.73, 0.73),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ray::Ray;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    const TRIANGLE: &str = r#"{
+        "asset": { "version": "2.0" },
+        "scene": 0,
+        "scenes": [{ "nodes": [0, 1] }],
+        "nodes": [
+            { "mesh": 0, "translation": [0.0, 0.0, -5.0], "scale": [2.0, 2.0, 2.0] },
+            { "camera": 0, "translation": [0.0, 0.0, 3.0] }
+        ],
+        "cameras": [{ "type": "perspective", "perspective": { "yfov": 0.5, "znear": 0.1 } }],
+        "materials": [{
+            "pbrMetallicRoughness": { "metallicFactor": 0.5, "roughnessFactor": 0.5 }
+        }],
+        "meshes": [{ "primitives": [{ "attributes": { "POSITION": 0 }, "material": 0 }] }],
+        "accessors": [{
+            "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+            "min": [0.0, 0.0, 0.0], "max": [1.0, 1.0, 0.0]
+        }],
+        "bufferViews": [{ "buffer": 0, "byteLength": 36 }],
+        "buffers": [{
+            "byteLength": 36,
+            "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAA"
+        }]
+    }"#;
+
+    #[test]
+    fn test_load_gltf() {
+        let path = std::env::temp_dir().join(format!(
+            "raytrace_test_triangle_{}.gltf",
+            std::process::id()
+        ));
+        std::fs::write(&path, TRIANGLE).unwrap();
+
+        let camera = load_camera(&path).unwrap().unwrap();
+        assert_eq!(camera.look_from, Vec3::new(0.0, 0.0, 3.0));
+        assert_eq!(camera.look_at, Vec3::new(0.0, 0.0, 2.0));
+        assert!((camera.vfov - 0.5f32.to_degrees()).abs() < 1e-4);
+
+        let world = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // the scaled triangle covers (1.5, 0.25), the unscaled one would not
+        let r = Ray::new(Vec3::new(1.5, 0.25, 10.0), Vec3::new(0.0, 0.0, -1.0));
+        let rec = world
+            .hit(&r, 0.001, f32::MAX, &mut StdRng::seed_from_u64(0))
+            .unwrap();
+        assert!((rec.t - 15.0).abs() < 1e-4);
+        match rec.material {
+            Material::Microfacet {
+                metallic,
+                roughness,
+                ..
+            } => assert_eq!((*metallic, *roughness), (0.5, 0.5)),
+            _ => panic!("expected a microfacet material"),
+        }
+    }
+
+    /// Write `TRIANGLE` with the replacements applied to a temporary file and load it.
+    fn load_modified(
+        name: &str,
+        replacements: &[(&str, &str)],
+    ) -> Result<HitableList, RaytraceError> {
+        let text = replacements
+            .iter()
+            .fold(TRIANGLE.to_string(), |text, (from, to)| {
+                assert!(text.contains(from));
+                text.replace(from, to)
+            });
+        let path = std::env::temp_dir().join(format!(
+            "raytrace_test_{}_{}.gltf",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, text).unwrap();
+        let world = load(&path);
+        std::fs::remove_file(&path).unwrap();
+        world
+    }
+
+    #[test]
+    fn test_invalid_gltf() {
+        let zero_scale = load_modified(
+            "zero_scale",
+            &[(r#""scale": [2.0, 2.0, 2.0]"#, r#""scale": [2.0, 0.0, 2.0]"#)],
+        );
+        assert!(matches!(zero_scale, Err(RaytraceError::InvalidGltf { .. })));
+
+        // the indices read the bytes of the second vertex, 1.0f32 as integer is far too large
+        let out_of_range = load_modified(
+            "index_range",
+            &[
+                (r#""POSITION": 0 }"#, r#""POSITION": 0 }, "indices": 1"#),
+                (
+                    r#""max": [1.0, 1.0, 0.0]
+        }]"#,
+                    r#""max": [1.0, 1.0, 0.0]
+        }, {
+            "bufferView": 0, "byteOffset": 12, "componentType": 5125, "count": 3,
+            "type": "SCALAR"
+        }]"#,
+                ),
+            ],
+        );
+        assert!(matches!(
+            out_of_range,
+            Err(RaytraceError::InvalidGltf { .. })
+        ));
+    }
+
+    #[test]
+    fn test_base_color_factor_tints_texture() {
+        let world = load_modified(
+            "base_color",
+            &[
+                (
+                    r#""metallicFactor": 0.5"#,
+                    r#""baseColorFactor": [0.5, 0.25, 1.0, 1.0],
+                "baseColorTexture": { "index": 0 }, "metallicFactor": 0.5"#,
+                ),
+                (
+                    r#""buffers": ["#,
+                    r#""textures": [{ "source": 0 }],
+        "images": [{
+            "uri": "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAIAAACQd1PeAAAADElEQVR4nGP4//8/AAX+Av4N70a4AAAAAElFTkSuQmCC"
+        }],
+        "buffers": ["#,
+                ),
+            ],
+        )
+        .unwrap();
+        let r = Ray::new(Vec3::new(1.5, 0.25, 10.0), Vec3::new(0.0, 0.0, -1.0));
+        let rec = world
+            .hit(&r, 0.001, f32::MAX, &mut StdRng::seed_from_u64(0))
+            .unwrap();
+        // the texture is white
+        assert_eq!(
+            rec.material.albedo(rec.u, rec.v, &rec.p),
+            Vec3::new(0.5, 0.25, 1.0)
+        );
+    }
+}
@@ -1,12 +1,18 @@
 use crate::boundingbox;
 use crate::boundingbox::BoundingBox;
 use crate::bvhnode;
+use crate::bvhnode::BvhBuilder;
+use crate::error::RaytraceError;
+use crate::light::Light;
 use crate::material::Material;
 use crate::ray::Ray;
 use crate::vec3::Vec3;
 
+use rand::RngCore;
+
 use std::ops::{Add, AddAssign};
 
+#[derive(Clone, Copy)]
 pub struct HitRecord<'a> {
     pub t: f32,
     pub p: Vec3,
@@ -14,34 +20,64 @@ pub struct HitRecord<'a> {
     pub u: f32,
     pub v: f32,
     pub material: &'a Material,
+    /// vertex color interpolated over a mesh, multiplying the albedo of the material;
+    /// white for surfaces without vertex colors
+    ///
+    /// A texture only sees (u, v) and the hit point. Barycentric coordinates do not tell
+    /// which triangle, and so which three colors, a hit belongs to, and using them as (u, v)
+    /// would take the texture coordinates away from the mesh's own textures.
+    pub color: Vec3,
+    /// index of the hit primitive in the lights of the scene if its light is sampled
+    /// directly, see [`Hitable::collect_lights`]
+    pub light: Option<usize>,
 }
 
-pub trait Hitable {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
+pub trait Hitable: Send + Sync {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rng: &mut dyn RngCore) -> Option<HitRecord<'_>>;
     fn bounding_box(&self) -> Option<BoundingBox>;
+
+    /// Append the emissive primitives to sample directly to `lights`, see [`Light`]. Each
+    /// primitive remembers its index to report it in its hit records. Objects whose lights
+    /// cannot be sampled (e.g. rotated or inside a volume) add none, their light is still
+    /// found by scattered rays.
+    fn collect_lights(&mut self, _lights: &mut Vec<Light>) {}
 }
 
 pub struct HitableList {
     pub list: Vec<Box<dyn Hitable>>,
 }
 
-unsafe impl Sync for HitableList {}
-unsafe impl Send for HitableList {}
-
 impl HitableList {
-    pub fn into_bvh(self) -> HitableList {
-        HitableList {
-            list: vec![Box::new(bvhnode::BvhNode::new(self.list))],
+    pub fn into_bvh(self) -> Result<HitableList, RaytraceError> {
+        self.into_bvh_with(BvhBuilder::default())
+    }
+
+    /// Put the bounded objects into a BVH, objects without bounding box (like an infinite
+    /// plane) stay in the list next to it and are tested one by one.
+    pub fn into_bvh_with(self, builder: BvhBuilder) -> Result<HitableList, RaytraceError> {
+        if self.list.is_empty() {
+            return Err(RaytraceError::EmptyBvh);
         }
+        let (bounded, mut unbounded): (Vec<_>, Vec<_>) = self
+            .list
+            .into_iter()
+            .partition(|item| item.bounding_box().is_some());
+
+        let mut list: Vec<Box<dyn Hitable>> = vec![];
+        if !bounded.is_empty() {
+            list.push(Box::new(bvhnode::BvhNode::with_builder(bounded, builder)?));
+        }
+        list.append(&mut unbounded);
+        Ok(HitableList { list })
     }
 }
 
 impl Hitable for HitableList {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rng: &mut dyn RngCore) -> Option<HitRecord<'_>> {
         let mut hit_record: Option<HitRecord> = None;
         let mut closest_so_far = t_max;
         for item in &self.list {
-            if let Some(temp_record) = item.hit(r, t_min, closest_so_far) {
+            if let Some(temp_record) = item.hit(r, t_min, closest_so_far, rng) {
                 closest_so_far = temp_record.t;
                 hit_record = Some(temp_record);
             }
@@ -65,6 +101,12 @@ impl Hitable for HitableList {
 
         bbox
     }
+
+    fn collect_lights(&mut self, lights: &mut Vec<Light>) {
+        for item in &mut self.list {
+            item.collect_lights(lights);
+        }
+    }
 }
 
 impl Add for HitableList {
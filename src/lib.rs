@@ -0,0 +1,36 @@
+//! A Rust implementation of the "Ray Tracing in One Weekend" series by Peter Shirley.
+//!
+//! A [`Scene`](render::Scene) is built either from a scene file (see [`scenefile`]) or
+//! directly from the primitives in this crate, and rendered with [`render::render`],
+//! or into an in-memory [`Framebuffer`](framebuffer::Framebuffer) with
+//! [`render::render_framebuffer`].
+//! Custom shapes can be added by implementing the [`Hitable`](hitable::Hitable) trait.
+
+pub mod aov;
+pub mod bdpt;
+pub mod boundingbox;
+pub mod bvhnode;
+pub mod camera;
+pub mod checkpoint;
+pub mod denoise;
+pub mod error;
+pub mod framebuffer;
+pub mod gltf;
+pub mod hitable;
+pub mod light;
+pub mod material;
+pub mod obj;
+pub mod output;
+pub mod plane;
+pub mod ply;
+pub mod ray;
+pub mod rect;
+pub mod render;
+pub mod scenefile;
+pub mod sphere;
+pub mod texture;
+pub mod tonemap;
+pub mod transform;
+pub mod triangle;
+pub mod vec3;
+pub mod volume;
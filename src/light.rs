@@ -0,0 +1,316 @@
+use crate::material::Material;
+use crate::rect::Axes;
+use crate::texture::Texture;
+use crate::vec3::Vec3;
+
+use rand::{Rng, RngCore};
+use std::f32::consts::PI;
+
+/// Emissive primitive whose light is sampled explicitly at every diffuse bounce
+/// (next-event estimation), instead of waiting for a scattered ray to hit it.
+#[derive(Clone)]
+pub struct Light {
+    pub shape: LightShape,
+    emit: Texture,
+}
+
+/// Light leaving a light source, as chosen by [`Light::sample_emission`].
+pub struct Emission {
+    pub p: Vec3,
+    /// unit normal of the light at `p`, on the side the light leaves
+    pub n: Vec3,
+    /// unit direction the light leaves in
+    pub direction: Vec3,
+    pub radiance: Vec3,
+    /// probability density of `p` per area
+    pub pdf_position: f32,
+    /// probability density of `direction` per solid angle
+    pub pdf_direction: f32,
+}
+
+#[derive(Clone)]
+pub enum LightShape {
+    Rect { a: Axes },
+    Sphere { center: Vec3, radius: f32 },
+}
+
+impl Light {
+    /// Light of the given shape, emitting through `material`.
+    pub fn new(shape: LightShape, material: &Material) -> Light {
+        Light {
+            shape,
+            emit: match material {
+                Material::DiffuseLight { emit } => emit.clone(),
+                _ => Texture::Constant {
+                    color: Vec3::default(),
+                },
+            },
+        }
+    }
+
+    pub fn translate(self, offset: Vec3) -> Light {
+        let shape = match self.shape {
+            LightShape::Rect { a } => LightShape::Rect {
+                a: match a {
+                    Axes::XY { x, y, z } => Axes::XY {
+                        x: (x.0 + offset.x(), x.1 + offset.x()),
+                        y: (y.0 + offset.y(), y.1 + offset.y()),
+                        z: z + offset.z(),
+                    },
+                    Axes::XZ { x, y, z } => Axes::XZ {
+                        x: (x.0 + offset.x(), x.1 + offset.x()),
+                        y: y + offset.y(),
+                        z: (z.0 + offset.z(), z.1 + offset.z()),
+                    },
+                    Axes::YZ { x, y, z } => Axes::YZ {
+                        x: x + offset.x(),
+                        y: (y.0 + offset.y(), y.1 + offset.y()),
+                        z: (z.0 + offset.z(), z.1 + offset.z()),
+                    },
+                },
+            },
+            LightShape::Sphere { center, radius } => LightShape::Sphere {
+                center: center + offset,
+                radius,
+            },
+        };
+        Light { shape, ..self }
+    }
+
+    /// Sample a unit direction from `p` towards the light, together with its probability
+    /// density with respect to solid angle. None if the light cannot be seen from `p`
+    /// (edge on, or `p` inside the sphere).
+    pub fn sample(&self, p: Vec3, rng: &mut dyn RngCore) -> Option<(Vec3, f32)> {
+        match &self.shape {
+            LightShape::Rect { a } => {
+                let (u, v): (f32, f32) = (rng.gen(), rng.gen());
+                let q = match *a {
+                    Axes::XY { x, y, z } => Vec3::new(lerp(x, u), lerp(y, v), z),
+                    Axes::XZ { x, y, z } => Vec3::new(lerp(x, u), y, lerp(z, v)),
+                    Axes::YZ { x, y, z } => Vec3::new(x, lerp(y, u), lerp(z, v)),
+                };
+                let pdf = self.pdf(p, q);
+                if pdf > 0.0 && pdf.is_finite() {
+                    Some(((q - p).normalize(), pdf))
+                } else {
+                    None
+                }
+            }
+            LightShape::Sphere { center, radius } => {
+                // uniform direction in the cone of the sphere as seen from p
+                let d = *center - p;
+                let dist_sq = d.len_squared();
+                if dist_sq <= radius * radius {
+                    return None;
+                }
+                let cos_max = (1.0 - radius * radius / dist_sq).sqrt();
+                let cos_theta = 1.0 - rng.gen::<f32>() * (1.0 - cos_max);
+                let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+                let phi = 2.0 * PI * rng.gen::<f32>();
+                let w = d / dist_sq.sqrt();
+                let (t, b) = crate::material::orthonormal_basis(w);
+                let wi = sin_theta * phi.cos() * t + sin_theta * phi.sin() * b + cos_theta * w;
+                Some((wi, 1.0 / (2.0 * PI * (1.0 - cos_max))))
+            }
+        }
+    }
+
+    /// Probability density per solid angle of [`Light::sample`] choosing the direction from
+    /// `p` to the point `q` on the light.
+    pub fn pdf(&self, p: Vec3, q: Vec3) -> f32 {
+        match &self.shape {
+            LightShape::Rect { a } => {
+                let n = rect_normal(a);
+                let area = 1.0 / self.pdf_position();
+                let d = q - p;
+                let dist_sq = d.len_squared();
+                // the emission does not depend on the side of the rect
+                let cos_light = Vec3::dot(d, n).abs() / dist_sq.sqrt();
+                dist_sq / (cos_light * area)
+            }
+            LightShape::Sphere { center, radius } => {
+                let dist_sq = (*center - p).len_squared();
+                if dist_sq <= radius * radius {
+                    return 0.0;
+                }
+                let cos_max = (1.0 - radius * radius / dist_sq).sqrt();
+                1.0 / (2.0 * PI * (1.0 - cos_max))
+            }
+        }
+    }
+
+    /// Probability density per area of [`Light::sample_emission`] choosing a point, which is
+    /// uniform over the surface.
+    pub fn pdf_position(&self) -> f32 {
+        match &self.shape {
+            LightShape::Rect { a } => match *a {
+                Axes::XY { x, y, .. } => 1.0 / ((x.1 - x.0) * (y.1 - y.0)),
+                Axes::XZ { x, z, .. } => 1.0 / ((x.1 - x.0) * (z.1 - z.0)),
+                Axes::YZ { y, z, .. } => 1.0 / ((y.1 - y.0) * (z.1 - z.0)),
+            },
+            LightShape::Sphere { radius, .. } => 1.0 / (4.0 * PI * radius * radius),
+        }
+    }
+
+    /// Sample light leaving the light source: a uniform point on its surface and a cosine
+    /// weighted direction around the normal there (on either side of a rect, which emits on
+    /// both), for tracing paths from the light.
+    pub fn sample_emission(&self, rng: &mut dyn RngCore) -> Emission {
+        let (p, n, (u, v)) = match &self.shape {
+            LightShape::Rect { a } => {
+                let (u, v): (f32, f32) = (rng.gen(), rng.gen());
+                let p = match *a {
+                    Axes::XY { x, y, z } => Vec3::new(lerp(x, u), lerp(y, v), z),
+                    Axes::XZ { x, y, z } => Vec3::new(lerp(x, u), y, lerp(z, v)),
+                    Axes::YZ { x, y, z } => Vec3::new(x, lerp(y, u), lerp(z, v)),
+                };
+                let n = rect_normal(a);
+                let n = if rng.gen::<bool>() { n } else { -n };
+                (p, n, (u, v))
+            }
+            LightShape::Sphere { center, radius } => {
+                let n = crate::material::random_in_unit_sphere(rng).normalize();
+                (*center + *radius * n, n, crate::sphere::sphere_uv(&n))
+            }
+        };
+        let (t, b) = crate::material::orthonormal_basis(n);
+        let (x, y, z) = crate::material::random_cosine_direction(rng);
+        let direction = x * t + y * b + z * n;
+        Emission {
+            p,
+            n,
+            direction,
+            radiance: self.emit.value(u, v, &p),
+            pdf_position: self.pdf_position(),
+            pdf_direction: self.pdf_direction(p, direction),
+        }
+    }
+
+    /// Probability density per solid angle of [`Light::sample_emission`] choosing the unit
+    /// `direction` at the point `p` on the light.
+    pub fn pdf_direction(&self, p: Vec3, direction: Vec3) -> f32 {
+        match &self.shape {
+            // either side with probability 1/2
+            LightShape::Rect { a } => Vec3::dot(direction, rect_normal(a)).abs() / (2.0 * PI),
+            LightShape::Sphere { center, .. } => {
+                let n = (p - *center).normalize();
+                Vec3::dot(direction, n).max(0.0) / PI
+            }
+        }
+    }
+}
+
+fn rect_normal(a: &Axes) -> Vec3 {
+    match a {
+        Axes::XY { .. } => Vec3::new(0.0, 0.0, 1.0),
+        Axes::XZ { .. } => Vec3::new(0.0, 1.0, 0.0),
+        Axes::YZ { .. } => Vec3::new(1.0, 0.0, 0.0),
+    }
+}
+
+fn lerp(range: (f32, f32), t: f32) -> f32 {
+    range.0 + t * (range.1 - range.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_sample_pdf() {
+        // integrating 1 / pdf over the samples estimates the solid angle of the light
+        let material = Material::DiffuseLight {
+            emit: Texture::Constant {
+                color: Vec3::new(1.0, 1.0, 1.0),
+            },
+        };
+        let rect = Light::new(
+            LightShape::Rect {
+                a: Axes::XZ {
+                    x: (-0.01, 0.01),
+                    y: 1.0,
+                    z: (-0.01, 0.01),
+                },
+            },
+            &material,
+        );
+        let sphere = Light::new(
+            LightShape::Sphere {
+                center: Vec3::new(0.0, 0.0, 10.0),
+                radius: 1.0,
+            },
+            &material,
+        )
+        .translate(Vec3::new(0.0, 0.0, -5.0));
+        let mut rng = StdRng::seed_from_u64(1);
+        let p = Vec3::default();
+
+        let solid_angle = |light: &Light, rng: &mut dyn RngCore| {
+            (0..1000)
+                .map(|_| {
+                    let (wi, pdf) = light.sample(p, rng).unwrap();
+                    assert!((wi.len() - 1.0).abs() < 1e-4);
+                    // a point along wi in the plane of the rect, the density of the sphere only
+                    // depends on p
+                    let q = p + wi / wi.y().max(wi.z());
+                    assert!((light.pdf(p, q) - pdf).abs() < 1e-3 * pdf);
+                    1.0 / pdf
+                })
+                .sum::<f32>()
+                / 1000.0
+        };
+        // small rect: area / distance^2
+        assert!((solid_angle(&rect, &mut rng) - 0.0004).abs() < 1e-5);
+        // sphere of radius 1 at distance 5
+        let expected = 2.0 * PI * (1.0 - (1.0 - 1.0 / 25.0_f32).sqrt());
+        assert!((solid_angle(&sphere, &mut rng) - expected).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_sample_emission() {
+        let material = Material::DiffuseLight {
+            emit: Texture::Constant {
+                color: Vec3::new(2.0, 2.0, 2.0),
+            },
+        };
+        let rect = Light::new(
+            LightShape::Rect {
+                a: Axes::YZ {
+                    x: 1.0,
+                    y: (0.0, 2.0),
+                    z: (0.0, 0.5),
+                },
+            },
+            &material,
+        );
+        let sphere = Light::new(
+            LightShape::Sphere {
+                center: Vec3::new(0.0, 0.0, 1.0),
+                radius: 2.0,
+            },
+            &material,
+        );
+        assert_eq!(rect.pdf_position(), 1.0);
+        assert!((sphere.pdf_position() - 1.0 / (16.0 * PI)).abs() < 1e-6);
+
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut sides = 0;
+        for _ in 0..100 {
+            let e = rect.sample_emission(&mut rng);
+            assert_eq!(e.p.x(), 1.0);
+            assert_eq!(e.radiance, Vec3::new(2.0, 2.0, 2.0));
+            assert!(Vec3::dot(e.n, e.direction) >= 0.0);
+            assert!((e.pdf_direction - rect.pdf_direction(e.p, e.direction)).abs() < 1e-6);
+            sides += (e.n.x() > 0.0) as i32;
+
+            let e = sphere.sample_emission(&mut rng);
+            assert!(((e.p - Vec3::new(0.0, 0.0, 1.0)).len() - 2.0).abs() < 1e-4);
+            assert!(Vec3::dot(e.n, e.direction) >= 0.0);
+            assert!((e.pdf_direction - Vec3::dot(e.n, e.direction) / PI).abs() < 1e-4);
+        }
+        // the rect emits on both sides
+        assert!(sides > 0 && sides < 100);
+    }
+}
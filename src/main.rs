@@ -1,38 +1,28 @@
-mod boundingbox;
-mod bvhnode;
-mod camera;
-mod hitable;
-mod material;
-mod ray;
-mod rect;
-mod render;
-mod sphere;
-mod texture;
-mod transform;
-mod vec3;
-mod volume;
+use raytrace::checkpoint;
+use raytrace::checkpoint::Checkpoint;
+use raytrace::denoise::Denoiser;
+use raytrace::error::RaytraceError;
+use raytrace::tonemap::ToneMapping;
+use raytrace::{output, render, scenefile};
 
-use rand::prelude::*;
-
-use crate::camera::Camera;
-use crate::hitable::HitableList;
-use crate::material::Material;
-use crate::rect::{Axes, Cuboid, Rect};
-use crate::render::{Background, RenderParams, Scene};
-use crate::sphere::Sphere;
-use crate::texture::{Perlin, Texture};
-use crate::transform::{RotateXYZ, Translate};
-use crate::vec3::Vec3;
-use crate::volume::ConstantMedium;
+use std::str::FromStr;
 
 fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), RaytraceError> {
     // command line argument
     let clap_matches = clap::App::new("raytrace")
         .version("0.1")
         .author("Peter Helbing <peter@abulafia.org>")
+        .setting(clap::AppSettings::SubcommandsNegateReqs)
         .arg(
             clap::Arg::with_name("SCENE")
-                .help("scene to render")
+                .help("scene file (RON) to render")
                 .required(true)
                 .index(1),
         )
@@ -68,443 +58,346 @@ fn main() {
                 .help("name of the output file")
                 .takes_value(true),
         )
+        .arg(
+            clap::Arg::with_name("bvh")
+                .long("bvh")
+                .value_name("BUILDER")
+                .help("BVH construction: sah (default) or median")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("tile-size")
+                .long("tile-size")
+                .value_name("N")
+                .help("edge length of the tiles rendered in parallel")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("integrator")
+                .long("integrator")
+                .value_name("INTEGRATOR")
+                .help("light transport algorithm: path (default) or bdpt (bidirectional)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("max-depth")
+                .long("max-depth")
+                .value_name("N")
+                .help("maximum number of bounces of a path (64 by default)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("roulette-depth")
+                .long("roulette-depth")
+                .value_name("N")
+                .help("bounces after which paths are ended by Russian roulette (3 by default)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("seed")
+                .long("seed")
+                .value_name("SEED")
+                .help("seed of the random number generators, for reproducible images")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("progressive")
+                .long("progressive")
+                .help("render in passes of increasing sample count, saving the image after each"),
+        )
+        .arg(
+            clap::Arg::with_name("snapshot-interval")
+                .long("snapshot-interval")
+                .value_name("DURATION")
+                .help("minimum time between intermediate images, e.g. 30s (implies --progressive)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("checkpoint-interval")
+                .long("checkpoint-interval")
+                .value_name("DURATION")
+                .help("save the render state next to the image this often, e.g. 15m")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("time-limit")
+                .long("time-limit")
+                .value_name("DURATION")
+                .help("keep adding samples until this time is used up, e.g. 10m (overrides -s)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("adaptive")
+                .long("adaptive")
+                .value_name("ERROR")
+                .help("stop sampling pixels once their relative error is below this, e.g. 0.02")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("sample-map")
+                .long("sample-map")
+                .value_name("FILE")
+                .help("save an image of the samples taken per pixel")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("tonemap")
+                .long("tonemap")
+                .value_name("OPERATOR")
+                .help("tone mapping: clamp (default), reinhard, reinhard-extended, aces or hable")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("exposure")
+                .long("exposure")
+                .value_name("EV")
+                .help("exposure correction in stops, applied before tone mapping")
+                .allow_hyphen_values(true)
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("white-point")
+                .long("white-point")
+                .value_name("L")
+                .help("radiance mapped to white by reinhard-extended and hable")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("gamma")
+                .long("gamma")
+                .value_name("GAMMA")
+                .help("use a pure power law with this gamma instead of the sRGB curve")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("dither")
+                .long("dither")
+                .help("dither before quantizing to 8 bits"),
+        )
+        .arg(
+            clap::Arg::with_name("aov")
+                .long("aov")
+                .value_name("AOV")
+                .help("also output depth, position, normal, albedo, uv or id, comma separated")
+                .takes_value(true)
+                .use_delimiter(true)
+                .multiple(true),
+        )
+        .arg(
+            clap::Arg::with_name("denoise")
+                .long("denoise")
+                .help("denoise the image, guided by albedo, normal and depth AOVs"),
+        )
+        .arg(
+            clap::Arg::with_name("no-light-sampling")
+                .long("no-light-sampling")
+                .help("only find lights by scattered rays instead of sampling them directly"),
+        )
+        .arg(
+            clap::Arg::with_name("resume")
+                .long("resume")
+                .help("continue the render from the checkpoint next to the output image"),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("denoise")
+                .about("denoise a float image saved with albedo, normal and depth AOVs")
+                .arg(
+                    clap::Arg::with_name("IMAGE")
+                        .help("image (.exr with AOV layers, or .pfm with AOV files) to denoise")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::with_name("out-filename")
+                        .short("o")
+                        .long("out-filename")
+                        .value_name("FILE")
+                        .help("name of the output file, by default <image>.denoised.<ext>")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("iterations")
+                        .long("iterations")
+                        .value_name("N")
+                        .help("number of filter passes, 1 to 16, 5 by default")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("sigma-color")
+                        .long("sigma-color")
+                        .value_name("SIGMA")
+                        .help("color difference that still gets smoothed, larger values blur more")
+                        .takes_value(true),
+                ),
+        )
         .get_matches();
 
-    // render parameters
-    let params = RenderParams {
-        nx: clap_matches
-            .value_of("width")
-            .unwrap_or("320")
-            .parse::<u32>()
-            .unwrap(),
-        ny: clap_matches
-            .value_of("height")
-            .unwrap_or("200")
-            .parse::<u32>()
-            .unwrap(),
-        ns: clap_matches
-            .value_of("samples")
-            .unwrap_or("256")
-            .parse::<usize>()
-            .unwrap(),
-        filename: clap_matches
-            .value_of("out-filename")
-            .unwrap_or("image.png")
-            .to_string(),
-    };
+    if let Some(denoise_matches) = clap_matches.subcommand_matches("denoise") {
+        return denoise(denoise_matches);
+    }
 
-    // define the camera
-    let look_from = Vec3::new(13.0, 2.0, 3.0);
-    let look_at = Vec3::new(0.0, 0.0, 0.0);
-    let dist_to_focus = 10.0;
-    let cam_random_scene = Camera::new(
-        look_from,
-        look_at,
-        Vec3::new(0.0, 1.0, 0.0),
-        20.0,
-        params.nx as f32 / params.ny as f32,
-        0.1,
-        dist_to_focus,
-    );
+    // load the scene description, command line parameters take precedence
+    let mut scene_file = scenefile::load(std::path::Path::new(
+        clap_matches.value_of("SCENE").unwrap(),
+    ))?;
+    if let Some(nx) = parse_arg(&clap_matches, "width")? {
+        scene_file.params.nx = nx;
+    }
+    if let Some(ny) = parse_arg(&clap_matches, "height")? {
+        scene_file.params.ny = ny;
+    }
+    if let Some(ns) = parse_arg(&clap_matches, "samples")? {
+        scene_file.params.ns = ns;
+    }
+    if let Some(filename) = clap_matches.value_of("out-filename") {
+        scene_file.params.filename = filename.to_string();
+    }
+    if let Some(bvh) = parse_arg(&clap_matches, "bvh")? {
+        scene_file.params.bvh = bvh;
+    }
+    if let Some(tile_size) = parse_arg(&clap_matches, "tile-size")? {
+        scene_file.params.tile_size = tile_size;
+    }
+    if let Some(integrator) = parse_arg(&clap_matches, "integrator")? {
+        scene_file.params.integrator = integrator;
+    }
+    if let Some(max_depth) = parse_arg(&clap_matches, "max-depth")? {
+        scene_file.params.max_depth = max_depth;
+    }
+    if let Some(roulette_depth) = parse_arg(&clap_matches, "roulette-depth")? {
+        scene_file.params.roulette_depth = roulette_depth;
+    }
+    if let Some(seed) = parse_arg(&clap_matches, "seed")? {
+        scene_file.params.seed = seed;
+    }
+    if clap_matches.is_present("progressive") {
+        scene_file.params.progressive = true;
+    }
+    if let Some(interval) = parse_seconds(&clap_matches, "snapshot-interval")? {
+        scene_file.params.progressive = true;
+        scene_file.params.snapshot_interval = interval;
+    }
 
-    let look_from = Vec3::new(13.0, 2.0, 3.0);
-    let look_at = Vec3::new(0.0, 0.0, 0.0);
-    let dist_to_focus = 10.0;
-    let cam_two_spheres = Camera::new(
-        look_from,
-        look_at,
-        Vec3::new(0.0, 1.0, 0.0),
-        40.0,
-        params.nx as f32 / params.ny as f32,
-        0.1,
-        dist_to_focus,
-    );
+    if let Some(interval) = parse_seconds(&clap_matches, "checkpoint-interval")? {
+        scene_file.params.checkpoint_interval = interval;
+    }
+    if let Some(limit) = parse_seconds(&clap_matches, "time-limit")? {
+        scene_file.params.time_limit = limit;
+    }
+    if let Some(threshold) = parse_arg(&clap_matches, "adaptive")? {
+        scene_file.params.adaptive_threshold = threshold;
+    }
+    if let Some(filename) = clap_matches.value_of("sample-map") {
+        scene_file.params.sample_map = Some(filename.to_string());
+    }
+    if let Some(values) = clap_matches.values_of("aov") {
+        scene_file.params.aovs = values
+            .map(|value| {
+                value.parse().map_err(|_| RaytraceError::InvalidParameter {
+                    name: "aov".to_string(),
+                    value: value.to_string(),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+    }
+    let tone_mapping = &mut scene_file.params.tone_mapping;
+    if let Some(operator) = parse_arg(&clap_matches, "tonemap")? {
+        tone_mapping.operator = operator;
+    }
+    if let Some(exposure) = parse_arg(&clap_matches, "exposure")? {
+        tone_mapping.exposure = exposure;
+    }
+    if let Some(white_point) = parse_arg(&clap_matches, "white-point")? {
+        tone_mapping.white_point = white_point;
+    }
+    if let Some(gamma) = parse_arg(&clap_matches, "gamma")? {
+        tone_mapping.gamma = Some(gamma);
+    }
+    if clap_matches.is_present("dither") {
+        tone_mapping.dither = true;
+    }
 
-    let cam_cornell = Camera::new(
-        Vec3::new(278.0, 278.0, -800.0),
-        Vec3::new(278.0, 278.0, 0.0),
-        Vec3::new(0.0, 1.0, 0.0),
-        40.0,
-        params.nx as f32 / params.ny as f32,
-        0.0,
-        10.0,
-    );
+    if clap_matches.is_present("denoise") {
+        scene_file.params.denoise = Some(Denoiser::default());
+    }
+    if clap_matches.is_present("no-light-sampling") {
+        scene_file.params.light_sampling = false;
+    }
 
-    let scene = match clap_matches.value_of("SCENE").unwrap() {
-        "cornell_blocks" => Scene {
-            world: (cornell_box_base() + cornell_box_blocks()).into_bvh(),
-            cam: cam_cornell,
-            background: Background::Color(Vec3::default()),
-            params,
-        },
-        "cornell_blocks_volume" => Scene {
-            world: (cornell_box_base() + cornell_box_blocks_volume()).into_bvh(),
-            cam: cam_cornell,
-            background: Background::Color(Vec3::default()),
-            params,
-        },
-        "cornell_balls" => Scene {
-            world: (cornell_box_base() + cornell_box_balls()).into_bvh(),
-            cam: cam_cornell,
-            background: Background::Color(Vec3::default()),
-            params,
-        },
-        "earth_perlin" => Scene {
-            world: (earth_perlin()).into_bvh(),
-            cam: cam_two_spheres,
-            background: Background::Color(Vec3::default()),
-            params,
-        },
-        "random_scene" => Scene {
-            world: (random_scene()).into_bvh(),
-            cam: cam_random_scene,
-            background: Background::BlendY(Vec3::new(1.0, 1.0, 1.0), Vec3::new(0.5, 0.7, 1.0)),
-            params,
-        },
-        _ => {
-            panic!("Unknown scene: choose one from (cornell_blocks | cornell_blocks_volume | cornell_balls | earth_perlin | random_scene)");
+    if clap_matches.is_present("resume") {
+        // the checkpoint holds the parameters the render was started with
+        let checkpoint = Checkpoint::load(&checkpoint::path_for(&scene_file.params.filename))?;
+        let checkpoint_interval = scene_file.params.checkpoint_interval;
+        let time_limit = scene_file.params.time_limit;
+        scene_file.params = checkpoint.params.clone();
+        if checkpoint_interval > 0 {
+            scene_file.params.checkpoint_interval = checkpoint_interval;
         }
-    };
-
-    render::render(scene);
-}
-
-fn random_scene() -> HitableList {
-    let mut rng = rand::thread_rng();
-
-    let mut hl = HitableList {
-        list: vec![Box::new(Sphere {
-            center: Vec3::new(0.0, -1000.0, 0.0),
-            radius: 1000.0,
-            material: Material::Diffuse {
-                albedo: Texture::Checker {
-                    odd: Box::new(Texture::Constant {
-                        color: Vec3::new(0.2, 0.3, 0.1),
-                    }),
-                    even: Box::new(Texture::Constant {
-                        color: Vec3::new(0.9, 0.9, 0.9),
-                    }),
-                },
-            },
-        })],
-    };
-
-    for a in -11..11 {
-        for b in -11..11 {
-            let choose_mat: f32 = rng.gen();
-            let center = Vec3::new(
-                a as f32 + 0.9 * rng.gen::<f32>(),
-                0.2,
-                b as f32 + 0.9 * rng.gen::<f32>(),
-            );
-            if (center - Vec3::new(4.0, 0.2, 0.0)).len() > 0.9 {
-                if choose_mat < 0.8 {
-                    // diffuse
-                    hl.list.push(Box::new(Sphere {
-                        center,
-                        radius: 0.2,
-                        material: Material::Diffuse {
-                            albedo: Texture::Constant {
-                                color: Vec3::new(
-                                    rng.gen::<f32>() * rng.gen::<f32>(),
-                                    rng.gen::<f32>() * rng.gen::<f32>(),
-                                    rng.gen::<f32>() * rng.gen::<f32>(),
-                                ),
-                            },
-                        },
-                    }))
-                } else if choose_mat < 0.95 {
-                    // metal
-                    hl.list.push(Box::new(Sphere {
-                        center,
-                        radius: 0.2,
-                        material: Material::Metal {
-                            albedo: Vec3::new(
-                                rng.gen_range(0.5, 1.0),
-                                rng.gen_range(0.5, 1.0),
-                                rng.gen_range(0.5, 1.0),
-                            ),
-                            fuzz: rng.gen_range(0.0, 0.5) * rng.gen_range(0.0, 0.5),
-                        },
-                    }))
-                } else {
-                    // glass
-                    hl.list.push(Box::new(Sphere {
-                        center,
-                        radius: 0.2,
-                        material: Material::Dielectric { ref_index: 1.5 },
-                    }))
-                }
-            }
+        if time_limit > 0 {
+            scene_file.params.time_limit = time_limit;
         }
+        render::resume(scene_file.into_scene()?, checkpoint)
+    } else {
+        render::render(scene_file.into_scene()?)
     }
-
-    hl.list.push(Box::new(Sphere {
-        center: Vec3::new(0.0, 1.0, 0.0),
-        radius: 1.0,
-        material: Material::Dielectric { ref_index: 1.5 },
-    }));
-    hl.list.push(Box::new(Sphere {
-        center: Vec3::new(-4.0, 1.0, 0.0),
-        radius: 1.0,
-        material: Material::Diffuse {
-            albedo: Texture::Constant {
-                color: Vec3::new(0.4, 0.2, 0.1),
-            },
-        },
-    }));
-    hl.list.push(Box::new(Sphere {
-        center: Vec3::new(4.0, 1.0, 0.0),
-        radius: 1.0,
-        material: Material::Metal {
-            albedo: Vec3::new(0.7, 0.6, 0.5),
-            fuzz: 0.1,
-        },
-    }));
-
-    hl
 }
 
-fn earth_perlin() -> HitableList {
-    let text1 = Texture::PerlinNoise {
-        perlin: Box::new(Perlin::new()),
-        scale: 5.0,
-    };
-
-    let img = image::open("res/earth.jpg").unwrap();
-    let text2 = Texture::Image {
-        image: Box::new(img.as_rgb8().unwrap().clone()),
+fn denoise(clap_matches: &clap::ArgMatches) -> Result<(), RaytraceError> {
+    let filename = clap_matches.value_of("IMAGE").unwrap();
+    let out_filename = match clap_matches.value_of("out-filename") {
+        Some(out_filename) => out_filename.to_string(),
+        None => {
+            let path = std::path::Path::new(filename);
+            let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+            path.with_extension(format!("denoised.{}", extension))
+                .to_string_lossy()
+                .into_owned()
+        }
     };
-
-    HitableList {
-        list: vec![
-            Box::new(Sphere {
-                center: Vec3::new(0.0, -1000.0, 0.0),
-                radius: 1000.0,
-                material: Material::Diffuse { albedo: text1 },
-            }),
-            Box::new(Sphere {
-                center: Vec3::new(0.0, 2.0, 0.0),
-                radius: 2.0,
-                material: Material::Diffuse { albedo: text2 },
-            }),
-            Box::new(Rect {
-                a: Axes::XY {
-                    x: (3.0, 5.0),
-                    y: (1.0, 3.0),
-                    z: -2.0,
-                },
-                flip_normal: false,
-                material: Material::DiffuseLight {
-                    emit: Texture::Constant {
-                        color: Vec3::new(4.0, 4.0, 4.0),
-                    },
-                },
-            }),
-            Box::new(Sphere {
-                center: Vec3::new(0.0, 7.0, 0.0),
-                radius: 2.0,
-                material: Material::DiffuseLight {
-                    emit: Texture::Constant {
-                        color: Vec3::new(4.0, 4.0, 4.0),
-                    },
-                },
-            }),
-        ],
+    let mut denoiser = Denoiser::default();
+    if let Some(iterations) = parse_arg(clap_matches, "iterations")? {
+        denoiser.iterations = iterations;
     }
-}
-
-fn cornell_box_base() -> HitableList {
-    let red = Material::Diffuse {
-        albedo: Texture::Constant {
-            color: Vec3::new(0.65, 0.05, 0.05),
-        },
-    };
-    let white = Material::Diffuse {
-        albedo: Texture::Constant {
-            color: Vec3::new(0.73, 0.73, 0.73),
-        },
-    };
-    let green = Material::Diffuse {
-        albedo: Texture::Constant {
-            color: Vec3::new(0.12, 0.45, 0.15),
-        },
-    };
-    let light = Material::DiffuseLight {
-        emit: Texture::Constant {
-            color: Vec3::new(15.0, 15.0, 15.0),
-        },
-    };
-
-    HitableList {
-        list: vec![
-            Box::new(Rect {
-                a: Axes::YZ {
-                    x: 555.0,
-                    y: (0.0, 555.0),
-                    z: (0.0, 555.0),
-                },
-                flip_normal: true,
-                material: green,
-            }),
-            Box::new(Rect {
-                a: Axes::YZ {
-                    x: 0.0,
-                    y: (0.0, 555.0),
-                    z: (0.0, 555.0),
-                },
-                flip_normal: false,
-                material: red,
-            }),
-            Box::new(Rect {
-                a: Axes::XZ {
-                    x: (213.0, 343.0),
-                    y: 554.0,
-                    z: (227.0, 332.0),
-                },
-                flip_normal: false,
-                material: light,
-            }),
-            Box::new(Rect {
-                a: Axes::XZ {
-                    x: (0.0, 555.0),
-                    y: 555.0,
-                    z: (0.0, 555.0),
-                },
-                flip_normal: true,
-                material: white.clone(),
-            }),
-            Box::new(Rect {
-                a: Axes::XZ {
-                    x: (0.0, 555.0),
-                    y: 0.0,
-                    z: (0.0, 555.0),
-                },
-                flip_normal: false,
-                material: white.clone(),
-            }),
-            Box::new(Rect {
-                a: Axes::XY {
-                    x: (0.0, 555.0),
-                    y: (0.0, 555.0),
-                    z: 555.0,
-                },
-                flip_normal: true,
-                material: white,
-            }),
-        ],
+    if let Some(sigma) = parse_arg(clap_matches, "sigma-color")? {
+        denoiser.sigma_color = sigma;
     }
-}
+    denoiser.validate()?;
 
-fn cornell_box_blocks() -> HitableList {
-    let white = Material::Diffuse {
-        albedo: Texture::Constant {
-            color: Vec3::new(0.73, 0.73, 0.73),
-        },
-    };
-
-    HitableList {
-        list: vec![
-            Box::new(Translate::new(
-                Box::new(RotateXYZ::new(
-                    Box::new(Cuboid::new(
-                        Vec3::default(),
-                        Vec3::new(165.0, 165.0, 165.0),
-                        white.clone(),
-                    )),
-                    Vec3::new(0.0, -18.0, 0.0),
-                )),
-                Vec3::new(130.0, 0.0, 65.0),
-            )),
-            Box::new(Translate::new(
-                Box::new(RotateXYZ::new(
-                    Box::new(Cuboid::new(
-                        Vec3::default(),
-                        Vec3::new(165.0, 330.0, 165.0),
-                        white,
-                    )),
-                    Vec3::new(0.0, 15.0, 0.0),
-                )),
-                Vec3::new(265.0, 0.0, 295.0),
-            )),
-        ],
-    }
+    let (fb, aovs) = output::load(filename)?;
+    let denoised = denoiser.denoise(&fb, &aovs);
+    output::save(&denoised, &out_filename, &ToneMapping::default())
 }
 
-fn cornell_box_blocks_volume() -> HitableList {
-    let white = Material::Diffuse {
-        albedo: Texture::Constant {
-            color: Vec3::new(0.73, 0.73, 0.73),
+fn parse_arg<T: FromStr>(
+    clap_matches: &clap::ArgMatches,
+    name: &str,
+) -> Result<Option<T>, RaytraceError> {
+    match clap_matches.value_of(name) {
+        Some(value) => match value.parse::<T>() {
+            Ok(v) => Ok(Some(v)),
+            Err(_) => Err(RaytraceError::InvalidParameter {
+                name: name.to_string(),
+                value: value.to_string(),
+            }),
         },
-    };
-
-    let b1 = Box::new(Translate::new(
-        Box::new(RotateXYZ::new(
-            Box::new(Cuboid::new(
-                Vec3::default(),
-                Vec3::new(165.0, 165.0, 165.0),
-                white.clone(),
-            )),
-            Vec3::new(0.0, -18.0, 0.0),
-        )),
-        Vec3::new(130.0, 0.0, 65.0),
-    ));
-    let b2 = Box::new(Translate::new(
-        Box::new(RotateXYZ::new(
-            Box::new(Cuboid::new(
-                Vec3::default(),
-                Vec3::new(165.0, 330.0, 165.0),
-                white,
-            )),
-            Vec3::new(0.0, 15.0, 0.0),
-        )),
-        Vec3::new(265.0, 0.0, 295.0),
-    ));
-
-    HitableList {
-        list: vec![
-            Box::new(ConstantMedium::new(
-                b1,
-                0.01,
-                Texture::Constant {
-                    color: Vec3::new(1.0, 1.0, 1.0),
-                },
-            )),
-            Box::new(ConstantMedium::new(
-                b2,
-                0.01,
-                Texture::Constant {
-                    color: Vec3::new(0.0, 0.0, 0.0),
-                },
-            )),
-        ],
+        None => Ok(None),
     }
 }
 
-fn cornell_box_balls() -> HitableList {
-    let glass = Material::Dielectric { ref_index: 1.5 };
-    let metal = Material::Metal {
-        albedo: Vec3::new(0.7, 0.6, 0.5),
-        fuzz: 0.0,
-    };
-
-    HitableList {
-        list: vec![
-            Box::new(Sphere {
-                center: Vec3::new(212.5, 82.5, 147.5),
-                radius: 82.5,
-                material: glass.clone(),
-            }),
-            Box::new(Sphere {
-                center: Vec3::new(212.5, 82.5, 147.5),
-                radius: -72.5,
-                material: glass,
-            }),
-            Box::new(Sphere {
-                center: Vec3::new(347.5, 82.5, 377.5),
-                radius: 82.5,
-                material: metal,
-            }),
-        ],
+/// Parse a duration like `10m` into whole seconds. Zero means "off" for all durations, so
+/// anything shorter than a second is rejected instead of being rounded down to it.
+fn parse_seconds(
+    clap_matches: &clap::ArgMatches,
+    name: &str,
+) -> Result<Option<u64>, RaytraceError> {
+    match parse_arg::<humantime::Duration>(clap_matches, name)? {
+        Some(d) if d.as_secs() == 0 => Err(RaytraceError::InvalidParameter {
+            name: name.to_string(),
+            value: format!(
+                "{} (less than a second)",
+                clap_matches.value_of(name).unwrap()
+            ),
+        }),
+        d => Ok(d.map(|d| d.as_secs())),
     }
 }
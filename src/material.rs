@@ -3,30 +3,63 @@ use crate::ray::Ray;
 use crate::texture::Texture;
 use crate::vec3::Vec3;
 
-use rand::Rng;
+use rand::{Rng, RngCore};
 
+/// Direction sampled by [`Material::scatter`], the light arriving from it is scattered
+/// along the incoming ray.
 pub struct Scatter {
+    /// unit direction pointing away from the hit point
+    pub direction: Vec3,
+    /// probability density per solid angle of `direction`, None for the discrete directions
+    /// of mirrors and glass
+    pub pdf: Option<f32>,
+    /// weight of the sample, the BSDF times the cosine divided by `pdf`
     pub att: Vec3,
-    pub ray: Ray,
 }
 
 #[derive(Clone)]
 pub enum Material {
-    Diffuse { albedo: Texture },
-    Metal { albedo: Vec3, fuzz: f32 },
-    Dielectric { ref_index: f32 },
-    DiffuseLight { emit: Texture },
-    Isotropic { albedo: Texture },
+    Diffuse {
+        albedo: Texture,
+    },
+    Metal {
+        albedo: Vec3,
+        fuzz: f32,
+    },
+    Dielectric {
+        ref_index: f32,
+    },
+    DiffuseLight {
+        emit: Texture,
+    },
+    Isotropic {
+        albedo: Texture,
+    },
+    /// metallic-roughness surface: a GGX specular lobe on top of a diffuse base,
+    /// metals tint the specular reflection with the albedo and have no diffuse part
+    Microfacet {
+        albedo: Texture,
+        metallic: f32,
+        roughness: f32,
+    },
 }
 
 impl Material {
-    pub fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<Scatter> {
+    /// Sample the direction the light scattered along `r_in` arrives from, None if the ray
+    /// is absorbed.
+    pub fn scatter(&self, r_in: &Ray, rec: &HitRecord, rng: &mut dyn RngCore) -> Option<Scatter> {
+        let wo = -r_in.direction.normalize();
         match self {
-            Material::Diffuse { albedo } => scatter_diffuse(rec, &albedo),
-            Material::Metal { albedo, fuzz } => scatter_metal(r_in, rec, &albedo, *fuzz),
-            Material::Dielectric { ref_index } => scatter_dielectric(r_in, rec, *ref_index),
+            Material::Diffuse { albedo } => scatter_diffuse(rec, albedo, wo, rng),
+            Material::Metal { albedo, fuzz } => scatter_metal(rec, albedo, *fuzz, wo, rng),
+            Material::Dielectric { ref_index } => scatter_dielectric(rec, *ref_index, wo, rng),
             Material::DiffuseLight { .. } => None,
-            Material::Isotropic { albedo } => scatter_isotropic(rec, &albedo),
+            Material::Isotropic { albedo } => scatter_isotropic(rec, albedo, rng),
+            Material::Microfacet {
+                albedo,
+                metallic,
+                roughness,
+            } => scatter_microfacet(rec, albedo, *metallic, *roughness, wo, rng),
         }
     }
 
@@ -36,89 +69,314 @@ impl Material {
             _ => Vec3::new(0.0, 0.0, 0.0),
         }
     }
+
+    /// Whether the material scatters into a continuum of directions (diffuse, fuzzy metal,
+    /// microfacet and volumes), so that [`Material::eval`] and [`Material::pdf`] are defined
+    /// and its direct light can be sampled from the lights. Mirrors, glass and lights are not.
+    pub fn is_rough(&self) -> bool {
+        match self {
+            Material::Diffuse { .. } | Material::Isotropic { .. } | Material::Microfacet { .. } => {
+                true
+            }
+            Material::Metal { fuzz, .. } => *fuzz > 0.0,
+            Material::Dielectric { .. } | Material::DiffuseLight { .. } => false,
+        }
+    }
+
+    /// Fraction of the light arriving from the unit direction `wi` that leaves towards the
+    /// unit direction `wo` (the BSDF times the cosine), both pointing away from the hit
+    /// point. Zero for materials that are not rough.
+    pub fn eval(&self, rec: &HitRecord, wi: Vec3, wo: Vec3) -> Vec3 {
+        match self {
+            Material::Diffuse { albedo } => {
+                let cos = Vec3::dot(facing(rec.n, wo), wi).max(0.0);
+                base_color(albedo, rec) * (cos / std::f32::consts::PI)
+            }
+            Material::Metal { albedo, fuzz } if *fuzz > 0.0 => {
+                if Vec3::dot(wi, rec.n) > 0.0 {
+                    rec.color * *albedo * metal_pdf(rec, *fuzz, wi, wo)
+                } else {
+                    Vec3::default()
+                }
+            }
+            Material::Isotropic { albedo } => {
+                base_color(albedo, rec) / (4.0 * std::f32::consts::PI)
+            }
+            Material::Microfacet {
+                albedo,
+                metallic,
+                roughness,
+            } => eval_microfacet(rec, albedo, *metallic, *roughness, wi, wo)
+                .map_or_else(Vec3::default, |(f, _)| f),
+            _ => Vec3::default(),
+        }
+    }
+
+    /// Probability density per solid angle of [`Material::scatter`] choosing the unit
+    /// direction `wi` for a ray leaving towards `wo`, zero for materials that are not rough.
+    pub fn pdf(&self, rec: &HitRecord, wi: Vec3, wo: Vec3) -> f32 {
+        match self {
+            Material::Diffuse { .. } => {
+                Vec3::dot(facing(rec.n, wo), wi).max(0.0) / std::f32::consts::PI
+            }
+            Material::Metal { fuzz, .. } if *fuzz > 0.0 => metal_pdf(rec, *fuzz, wi, wo),
+            Material::Isotropic { .. } => 1.0 / (4.0 * std::f32::consts::PI),
+            Material::Microfacet {
+                albedo,
+                metallic,
+                roughness,
+            } => eval_microfacet(rec, albedo, *metallic, *roughness, wi, wo)
+                .map_or(0.0, |(_, pdf)| pdf),
+            _ => 0.0,
+        }
+    }
+
+    /// Base color of the surface at the hit point, as used for the albedo AOV: the texture
+    /// value for textured materials, white for glass and the emission clamped to 1 for lights.
+    /// Vertex colors (see [`HitRecord::color`]) are not included.
+    pub fn albedo(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
+        match self {
+            Material::Diffuse { albedo }
+            | Material::Isotropic { albedo }
+            | Material::Microfacet { albedo, .. } => albedo.value(u, v, p),
+            Material::Metal { albedo, .. } => *albedo,
+            Material::Dielectric { .. } => Vec3::new(1.0, 1.0, 1.0),
+            Material::DiffuseLight { emit } => {
+                let c = emit.value(u, v, p);
+                Vec3::new(c.x().min(1.0), c.y().min(1.0), c.z().min(1.0))
+            }
+        }
+    }
+}
+
+/// Texture value at the hit point tinted by its vertex color.
+fn base_color(albedo: &Texture, rec: &HitRecord) -> Vec3 {
+    rec.color * albedo.value(rec.u, rec.v, &rec.p)
 }
 
-fn scatter_diffuse(rec: &HitRecord, albedo: &Texture) -> Option<Scatter> {
-    let target = rec.p + rec.n + random_in_unit_sphere();
+fn scatter_diffuse(
+    rec: &HitRecord,
+    albedo: &Texture,
+    wo: Vec3,
+    rng: &mut dyn RngCore,
+) -> Option<Scatter> {
+    // cosine weighted around the normal on the side the ray comes from
+    let n = facing(rec.n, wo);
+    let (t, b) = orthonormal_basis(n);
+    let (x, y, z) = random_cosine_direction(rng);
     Some(Scatter {
-        att: albedo.value(rec.u, rec.v, &rec.p),
-        ray: Ray {
-            origin: rec.p,
-            direction: target - rec.p,
-        },
+        direction: x * t + y * b + z * n,
+        pdf: Some(z / std::f32::consts::PI),
+        att: base_color(albedo, rec),
     })
 }
 
-fn scatter_metal(r_in: &Ray, rec: &HitRecord, albedo: &Vec3, fuzz: f32) -> Option<Scatter> {
-    let reflected = reflect(r_in.direction.normalize(), rec.n) + fuzz * random_in_unit_sphere();
+fn scatter_metal(
+    rec: &HitRecord,
+    albedo: &Vec3,
+    fuzz: f32,
+    wo: Vec3,
+    rng: &mut dyn RngCore,
+) -> Option<Scatter> {
+    let reflected = reflect(-wo, rec.n) + fuzz * random_in_unit_sphere(rng);
     if Vec3::dot(reflected, rec.n) > 0.0 {
+        let direction = reflected.normalize();
         Some(Scatter {
-            att: *albedo,
-            ray: Ray {
-                origin: rec.p,
-                direction: reflected,
+            direction,
+            pdf: if fuzz > 0.0 {
+                Some(metal_pdf(rec, fuzz, direction, wo))
+            } else {
+                None
             },
+            att: rec.color * *albedo,
         })
     } else {
         None
     }
 }
 
-fn scatter_dielectric(r_in: &Ray, rec: &HitRecord, ref_index: f32) -> Option<Scatter> {
-    let din = Vec3::dot(r_in.direction, rec.n);
+fn scatter_dielectric(
+    rec: &HitRecord,
+    ref_index: f32,
+    wo: Vec3,
+    rng: &mut dyn RngCore,
+) -> Option<Scatter> {
+    let d = -wo;
+    let din = Vec3::dot(d, rec.n);
     let outward_normal = if din > 0.0 { -rec.n } else { rec.n };
     let ni_over_nt = if din > 0.0 {
         ref_index
     } else {
         1.0 / ref_index
     };
-    let cosine = if din > 0.0 {
-        ref_index * din / r_in.direction.len()
-    } else {
-        -din / r_in.direction.len()
-    };
+    let cosine = if din > 0.0 { ref_index * din } else { -din };
 
-    let refracted_opt = refract(r_in.direction, outward_normal, ni_over_nt);
+    let refracted_opt = refract(d, outward_normal, ni_over_nt);
     let reflect_prob = if refracted_opt.is_some() {
         schlick(cosine, ref_index)
     } else {
         1.0
     };
 
-    let mut rng = rand::thread_rng();
-    if rng.gen::<f32>() < reflect_prob {
-        let reflected = reflect(r_in.direction, rec.n);
-        Some(Scatter {
-            att: Vec3::new(1.0, 1.0, 1.0),
-            ray: Ray {
-                origin: rec.p,
-                direction: reflected,
-            },
-        })
+    let direction = if rng.gen::<f32>() < reflect_prob {
+        reflect(d, rec.n)
     } else {
-        Some(Scatter {
-            att: Vec3::new(1.0, 1.0, 1.0),
-            ray: Ray {
-                origin: rec.p,
-                direction: refracted_opt.unwrap(),
-            },
-        })
-    }
+        refracted_opt.unwrap()
+    };
+    Some(Scatter {
+        direction: direction.normalize(),
+        pdf: None,
+        att: Vec3::new(1.0, 1.0, 1.0),
+    })
 }
 
-fn scatter_isotropic(rec: &HitRecord, albedo: &Texture) -> Option<Scatter> {
+fn scatter_isotropic(rec: &HitRecord, albedo: &Texture, rng: &mut dyn RngCore) -> Option<Scatter> {
     Some(Scatter {
-        att: albedo.value(rec.u, rec.v, &rec.p),
-        ray: Ray {
-            origin: rec.p,
-            direction: random_in_unit_sphere(),
-        },
+        direction: random_in_unit_sphere(rng).normalize(),
+        pdf: Some(1.0 / (4.0 * std::f32::consts::PI)),
+        att: base_color(albedo, rec),
     })
 }
 
-fn random_in_unit_sphere() -> Vec3 {
-    let mut rng = rand::thread_rng();
+fn scatter_microfacet(
+    rec: &HitRecord,
+    albedo: &Texture,
+    metallic: f32,
+    roughness: f32,
+    wo: Vec3,
+    rng: &mut dyn RngCore,
+) -> Option<Scatter> {
+    let alpha = (roughness * roughness).max(1e-3);
+    let alpha2 = alpha * alpha;
 
+    // work in a frame where the normal faces the incoming ray
+    let n = facing(rec.n, wo);
+    let (t, b) = orthonormal_basis(n);
+
+    // pick the specular lobe more often for metals, the direction is then weighted
+    // with the combined pdf of both lobes
+    let wi = if rng.gen::<f32>() < specular_probability(metallic) {
+        // sample the GGX distribution of visible normals approximately by its D(h) cos
+        let u1: f32 = rng.gen();
+        let phi = 2.0 * std::f32::consts::PI * rng.gen::<f32>();
+        let cos_theta = ((1.0 - u1) / (1.0 + (alpha2 - 1.0) * u1)).sqrt();
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let h = sin_theta * phi.cos() * t + sin_theta * phi.sin() * b + cos_theta * n;
+        reflect(-wo, h)
+    } else {
+        let (x, y, z) = random_cosine_direction(rng);
+        x * t + y * b + z * n
+    };
+
+    let (f, pdf) = eval_microfacet(rec, albedo, metallic, roughness, wi, wo)?;
+    Some(Scatter {
+        direction: wi,
+        pdf: Some(pdf),
+        att: f / pdf,
+    })
+}
+
+fn specular_probability(metallic: f32) -> f32 {
+    0.5 + 0.5 * metallic.clamp(0.0, 1.0)
+}
+
+/// BSDF times cosine of the microfacet material for light from `wi` leaving towards `wo`
+/// and the pdf of [`scatter_microfacet`] choosing `wi`, None if either is below the surface.
+fn eval_microfacet(
+    rec: &HitRecord,
+    albedo: &Texture,
+    metallic: f32,
+    roughness: f32,
+    wi: Vec3,
+    wo: Vec3,
+) -> Option<(Vec3, f32)> {
+    let albedo = base_color(albedo, rec);
+    let metallic = metallic.clamp(0.0, 1.0);
+    let alpha = (roughness * roughness).max(1e-3);
+    let alpha2 = alpha * alpha;
+
+    let n = facing(rec.n, wo);
+    let cos_i = Vec3::dot(n, wi);
+    let cos_o = Vec3::dot(n, wo);
+    if cos_i <= 0.0 || cos_o <= 0.0 {
+        return None;
+    }
+    let h = (wi + wo).normalize();
+    let cos_h = Vec3::dot(n, h);
+    let wi_h = Vec3::dot(wi, h).max(1e-6);
+
+    let d = {
+        let k = cos_h * cos_h * (alpha2 - 1.0) + 1.0;
+        alpha2 / (std::f32::consts::PI * k * k)
+    };
+    let g1 = |c: f32| 2.0 * c / (c + (alpha2 + (1.0 - alpha2) * c * c).sqrt());
+    let f0 = (1.0 - metallic) * Vec3::new(0.04, 0.04, 0.04) + metallic * albedo;
+    let f = f0 + (Vec3::new(1.0, 1.0, 1.0) - f0) * (1.0 - wi_h).powi(5);
+
+    let specular = d * g1(cos_o) * g1(cos_i) / (4.0 * cos_o * cos_i) * f;
+    let diffuse = (1.0 - metallic) / std::f32::consts::PI * (Vec3::new(1.0, 1.0, 1.0) - f) * albedo;
+    let spec_prob = specular_probability(metallic);
+    let pdf =
+        spec_prob * d * cos_h / (4.0 * wi_h) + (1.0 - spec_prob) * cos_i / std::f32::consts::PI;
+    Some(((specular + diffuse) * cos_i, pdf))
+}
+
+/// Probability density per solid angle of a fuzzy metal reflection of the ray leaving
+/// towards `wo` pointing along `wi`, see [`scatter_metal`].
+fn metal_pdf(rec: &HitRecord, fuzz: f32, wi: Vec3, wo: Vec3) -> f32 {
+    ball_direction_pdf(reflect(-wo, rec.n), fuzz, wi)
+}
+
+/// Probability density per solid angle of the direction of `center + radius * p`, with `p`
+/// uniformly distributed in the unit sphere, to point along the unit vector `w`.
+fn ball_direction_pdf(center: Vec3, radius: f32, w: Vec3) -> f32 {
+    // the points t * w inside the ball lie between t0 and t1, the density of the direction
+    // is the volume of the cone section between them divided by the volume of the ball
+    let c = Vec3::dot(w, center);
+    let disc = c * c - center.len_squared() + radius * radius;
+    if disc <= 0.0 {
+        return 0.0;
+    }
+    let t0 = (c - disc.sqrt()).max(0.0);
+    let t1 = c + disc.sqrt();
+    if t1 <= 0.0 {
+        return 0.0;
+    }
+    (t1.powi(3) - t0.powi(3)) / (4.0 * std::f32::consts::PI * radius.powi(3))
+}
+
+/// The unit normal `n` flipped to the side of the unit direction `w`.
+fn facing(n: Vec3, w: Vec3) -> Vec3 {
+    let n = n.normalize();
+    if Vec3::dot(n, w) < 0.0 {
+        -n
+    } else {
+        n
+    }
+}
+
+/// Two tangent vectors completing `n` (unit length) to an orthonormal basis.
+pub(crate) fn orthonormal_basis(n: Vec3) -> (Vec3, Vec3) {
+    let a = if n.x().abs() > 0.9 {
+        Vec3::new(0.0, 1.0, 0.0)
+    } else {
+        Vec3::new(1.0, 0.0, 0.0)
+    };
+    let t = Vec3::cross(n, a).normalize();
+    let b = Vec3::cross(n, t);
+    (t, b)
+}
+
+/// Cosine weighted direction on the hemisphere around +z.
+pub(crate) fn random_cosine_direction(rng: &mut dyn RngCore) -> (f32, f32, f32) {
+    let r1: f32 = rng.gen();
+    let r2: f32 = rng.gen();
+    let phi = 2.0 * std::f32::consts::PI * r1;
+    let r = r2.sqrt();
+    (r * phi.cos(), r * phi.sin(), (1.0 - r2).sqrt())
+}
+
+pub(crate) fn random_in_unit_sphere(rng: &mut dyn RngCore) -> Vec3 {
     loop {
         let p = Vec3::new(
             rng.gen_range(-1.0, 1.0),
@@ -151,3 +409,109 @@ fn schlick(cosine: f32, ref_index: f32) -> f32 {
     let r0 = r0 * r0;
     r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_pdf_matches_scatter() {
+        let gray = Texture::Constant {
+            color: Vec3::new(0.5, 0.5, 0.5),
+        };
+        let materials = vec![
+            Material::Diffuse {
+                albedo: gray.clone(),
+            },
+            Material::Metal {
+                albedo: Vec3::new(0.8, 0.8, 0.8),
+                fuzz: 0.3,
+            },
+            Material::Isotropic {
+                albedo: gray.clone(),
+            },
+            Material::Microfacet {
+                albedo: gray,
+                metallic: 0.5,
+                roughness: 0.4,
+            },
+        ];
+        let r_in = Ray::new(Vec3::new(-1.0, 1.0, 0.0), Vec3::new(1.0, -1.0, 0.5));
+        let wo = -r_in.direction.normalize();
+        let mut rng = StdRng::seed_from_u64(1);
+        for material in &materials {
+            let rec = HitRecord {
+                t: 1.0,
+                p: Vec3::new(0.0, 0.0, 0.5),
+                n: Vec3::new(0.0, 1.0, 0.0),
+                u: 0.0,
+                v: 0.0,
+                material,
+                color: Vec3::new(1.0, 1.0, 1.0),
+                light: None,
+            };
+            assert!(material.is_rough());
+            for _ in 0..100 {
+                if let Some(s) = material.scatter(&r_in, &rec, &mut rng) {
+                    assert!((s.direction.len() - 1.0).abs() < 1e-4);
+                    let pdf = material.pdf(&rec, s.direction, wo);
+                    assert!((s.pdf.unwrap() - pdf).abs() < 1e-3 * pdf);
+                    let att = material.eval(&rec, s.direction, wo) / pdf;
+                    assert!((att - s.att).len() < 1e-3 * s.att.len());
+                }
+            }
+
+            // the density integrates to at most 1 over the sphere, less where rays are lost
+            let n = 200;
+            let mut total = 0.0;
+            for i in 0..n {
+                let cos_theta = 1.0 - 2.0 * (i as f32 + 0.5) / n as f32;
+                let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+                for j in 0..2 * n {
+                    let phi = std::f32::consts::PI * (j as f32 + 0.5) / n as f32;
+                    let wi = Vec3::new(sin_theta * phi.cos(), cos_theta, sin_theta * phi.sin());
+                    total += material.pdf(&rec, wi, wo);
+                }
+            }
+            total *= 4.0 * std::f32::consts::PI / (2 * n * n) as f32;
+            assert!(total > 0.9 && total < 1.01, "{}", total);
+        }
+        assert!(!Material::Dielectric { ref_index: 1.5 }.is_rough());
+    }
+
+    #[test]
+    fn test_diffuse_is_cosine_weighted() {
+        let material = Material::Diffuse {
+            albedo: Texture::Constant {
+                color: Vec3::new(0.5, 0.5, 0.5),
+            },
+        };
+        let rec = HitRecord {
+            t: 1.0,
+            p: Vec3::default(),
+            n: Vec3::new(0.0, 0.0, 1.0),
+            u: 0.0,
+            v: 0.0,
+            material: &material,
+            color: Vec3::new(1.0, 1.0, 1.0),
+            light: None,
+        };
+        let mut rng = StdRng::seed_from_u64(2);
+        // the mean cosine of a cosine weighted direction is 2/3, on the side the ray comes from
+        for &side in &[1.0, -1.0] {
+            let r_in = Ray::new(Vec3::new(0.0, 1.0, side), Vec3::new(0.0, -1.0, -side));
+            let n = 10000;
+            let mut sum = 0.0;
+            for _ in 0..n {
+                let s = material.scatter(&r_in, &rec, &mut rng).unwrap();
+                let cos = side * s.direction.z();
+                assert!(cos >= 0.0);
+                assert!((s.pdf.unwrap() - cos / std::f32::consts::PI).abs() < 1e-4);
+                sum += cos;
+            }
+            assert!((sum / n as f32 - 2.0 / 3.0).abs() < 0.01);
+        }
+    }
+}
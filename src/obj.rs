@@ -0,0 +1,256 @@
+use crate::error::RaytraceError;
+use crate::hitable::HitableList;
+use crate::material::Material;
+use crate::texture::Texture;
+use crate::triangle::TriangleMesh;
+use crate::vec3::Vec3;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Load a Wavefront OBJ file, creating one triangle mesh per object / material group.
+///
+/// The MTL materials referenced by the file are mapped onto [`Material`] and shared by the
+/// meshes using them, unless `material` is given, which is then used for all meshes instead.
+pub fn load(path: &Path, material: Option<Arc<Material>>) -> Result<HitableList, RaytraceError> {
+    let options = tobj::LoadOptions {
+        triangulate: true,
+        single_index: true,
+        ..Default::default()
+    };
+    let load_error = |source| RaytraceError::MeshLoad {
+        path: path.to_path_buf(),
+        source,
+    };
+    let (models, obj_materials) = tobj::load_obj(path, &options).map_err(load_error)?;
+
+    let materials = match material {
+        Some(_) => vec![],
+        None => {
+            let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+            let mut textures = HashMap::new();
+            obj_materials
+                .map_err(load_error)?
+                .iter()
+                .map(|m| convert_material(m, base_dir, &mut textures).map(Arc::new))
+                .collect::<Result<Vec<_>, _>>()?
+        }
+    };
+
+    let mut hl = HitableList { list: vec![] };
+    for model in models {
+        let (name, mesh) = (model.name, model.mesh);
+        let material = match (&material, mesh.material_id) {
+            (Some(material), _) => material.clone(),
+            (None, Some(id)) if id < materials.len() => materials[id].clone(),
+            _ => Arc::new(default_material()),
+        };
+        let positions: Vec<Vec3> = mesh
+            .positions
+            .chunks_exact(3)
+            .map(|p| Vec3::new(p[0], p[1], p[2]))
+            .collect();
+        let normals: Vec<Vec3> = mesh
+            .normals
+            .chunks_exact(3)
+            .map(|n| Vec3::new(n[0], n[1], n[2]))
+            .collect();
+        let uvs: Vec<(f32, f32)> = mesh
+            .texcoords
+            .chunks_exact(2)
+            .map(|uv| (uv[0], uv[1]))
+            .collect();
+        let indices: Vec<[u32; 3]> = mesh
+            .indices
+            .chunks_exact(3)
+            .map(|idx| [idx[0], idx[1], idx[2]])
+            .collect();
+
+        let invalid = |message: String| RaytraceError::InvalidObj {
+            path: path.to_path_buf(),
+            message: format!("{}: {}", name, message),
+        };
+        let n = positions.len();
+        if indices.iter().flatten().any(|&i| i as usize >= n) {
+            return Err(invalid("vertex index out of range".to_string()));
+        }
+        // the attributes are indexed like the positions, for all vertices or none
+        for (name, len) in &[
+            ("normals", normals.len()),
+            ("texture coordinates", uvs.len()),
+        ] {
+            if *len != 0 && *len != n {
+                return Err(invalid(format!("{} {} for {} vertices", len, name, n)));
+            }
+        }
+
+        let mesh = TriangleMesh::new(positions, normals, uvs, indices, material);
+        if !mesh.is_empty() {
+            hl.list.push(Box::new(mesh));
+        }
+    }
+    Ok(hl)
+}
+
+/// Map an MTL material onto the closest [`Material`] variant:
+///
+/// * an emissive color (`Ke`) gives a `DiffuseLight`
+/// * transparency (`d` < 1) or a refracting illumination model gives a `Dielectric`
+///   with the optical density (`Ni`) as refraction index
+/// * a reflecting illumination model (3 / 5) or a specular color (`Ks`) brighter than the
+///   diffuse color gives a `Metal`, the fuzz is derived from the shininess (`Ns`)
+/// * everything else is `Diffuse`, using the diffuse texture (`map_Kd`) if present
+fn convert_material(
+    m: &tobj::Material,
+    base_dir: &Path,
+    textures: &mut HashMap<PathBuf, Texture>,
+) -> Result<Material, RaytraceError> {
+    let diffuse = Vec3::new(m.diffuse[0], m.diffuse[1], m.diffuse[2]);
+    let specular = Vec3::new(m.specular[0], m.specular[1], m.specular[2]);
+    let emission = m
+        .unknown_param
+        .get("Ke")
+        .and_then(|ke| parse_color(ke))
+        .unwrap_or_default();
+    let illum = m.illumination_model.unwrap_or(2);
+
+    Ok(if max_component(emission) > 0.0 {
+        Material::DiffuseLight {
+            emit: match m.unknown_param.get("map_Ke") {
+                Some(file) => load_texture(&base_dir.join(file), textures)?,
+                None => Texture::Constant { color: emission },
+            },
+        }
+    } else if m.dissolve < 1.0 || [4, 6, 7, 9].contains(&illum) {
+        Material::Dielectric {
+            ref_index: if m.optical_density > 1.0 {
+                m.optical_density
+            } else {
+                1.5
+            },
+        }
+    } else if illum == 3 || illum == 5 || max_component(specular) > max_component(diffuse) {
+        Material::Metal {
+            albedo: specular,
+            fuzz: (2.0 / (m.shininess.max(0.0) + 2.0)).sqrt(),
+        }
+    } else {
+        Material::Diffuse {
+            albedo: if m.diffuse_texture.is_empty() {
+                Texture::Constant { color: diffuse }
+            } else {
+                load_texture(&base_dir.join(&m.diffuse_texture), textures)?
+            },
+        }
+    })
+}
+
+fn load_texture(
+    path: &Path,
+    textures: &mut HashMap<PathBuf, Texture>,
+) -> Result<Texture, RaytraceError> {
+    if let Some(texture) = textures.get(path) {
+        return Ok(texture.clone());
+    }
+    let img = image::open(path).map_err(|source| RaytraceError::Image {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let texture = Texture::Image {
+        image: Box::new(img.into_rgb()),
+    };
+    textures.insert(path.to_path_buf(), texture.clone());
+    Ok(texture)
+}
+
+fn parse_color(s: &str) -> Option<Vec3> {
+    let c = s
+        .split_whitespace()
+        .map(|w| w.parse::<f32>().ok())
+        .collect::<Option<Vec<f32>>>()?;
+    match c.len() {
+        1 => Some(Vec3::new(c[0], c[0], c[0])),
+        3 => Some(Vec3::new(c[0], c[1], c[2])),
+        _ => None,
+    }
+}
+
+fn max_component(v: Vec3) -> f32 {
+    v.x().max(v.y()).max(v.z())
+}
+
+fn default_material() -> Material {
+    Material::Diffuse {
+        albedo: Texture::Constant {
+            color: Vec3::new(0.73, 0.73, 0.73),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mtl(source: &str) -> tobj::Material {
+        let (materials, _) =
+            tobj::load_mtl_buf(&mut std::io::BufReader::new(source.as_bytes())).unwrap();
+        materials.into_iter().next().unwrap()
+    }
+
+    fn load_str(name: &str, source: &str) -> Result<HitableList, RaytraceError> {
+        let path =
+            std::env::temp_dir().join(format!("raytrace_test_{}_{}.obj", name, std::process::id()));
+        std::fs::write(&path, source).unwrap();
+        let result = load(&path, None);
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn test_missing_normals() {
+        let quad = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvn 0 0 1\n";
+        let valid = load_str(
+            "valid",
+            &format!("{}f 1//1 2//1 3//1\nf 1//1 3//1 4//1\n", quad),
+        );
+        assert_eq!(valid.unwrap().list.len(), 1);
+        // the second face has no normals
+        let missing = load_str(
+            "missing_normals",
+            &format!("{}f 1//1 2//1 3//1\nf 1 3 4\n", quad),
+        );
+        assert!(matches!(missing, Err(RaytraceError::InvalidObj { .. })));
+    }
+
+    fn convert(source: &str) -> Material {
+        convert_material(&mtl(source), Path::new(""), &mut HashMap::new()).unwrap()
+    }
+
+    #[test]
+    fn test_convert_material() {
+        match convert("newmtl m\nKd 0.5 0.4 0.3\nKs 0.1 0.1 0.1\nillum 2\n") {
+            Material::Diffuse {
+                albedo: Texture::Constant { color },
+            } => assert_eq!(color, Vec3::new(0.5, 0.4, 0.3)),
+            _ => panic!("expected a diffuse material"),
+        }
+        match convert("newmtl m\nKd 0 0 0\nKs 0.9 0.8 0.7\nNs 998\nillum 3\n") {
+            Material::Metal { albedo, fuzz } => {
+                assert_eq!(albedo, Vec3::new(0.9, 0.8, 0.7));
+                assert!(fuzz < 0.05);
+            }
+            _ => panic!("expected a metal material"),
+        }
+        match convert("newmtl m\nKd 1 1 1\nNi 1.33\nd 0.2\n") {
+            Material::Dielectric { ref_index } => assert_eq!(ref_index, 1.33),
+            _ => panic!("expected a dielectric material"),
+        }
+        match convert("newmtl m\nKd 0.5 0.5 0.5\nKe 15 15 15\n") {
+            Material::DiffuseLight {
+                emit: Texture::Constant { color },
+            } => assert_eq!(color, Vec3::new(15.0, 15.0, 15.0)),
+            _ => panic!("expected a light material"),
+        }
+    }
+}
@@ -0,0 +1,585 @@
+use crate::aov::Aov;
+use crate::error::RaytraceError;
+use crate::framebuffer::Framebuffer;
+use crate::tonemap::ToneMapping;
+use crate::vec3::Vec3;
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Save the framebuffer to `filename`, in a format chosen by the file extension.
+///
+/// `.exr`, `.hdr` and `.pfm` files keep the linear float radiance as it is, any other
+/// extension is passed to the `image` crate as 8 bit image mapped with `tone_mapping`.
+pub fn save(
+    fb: &Framebuffer,
+    filename: &str,
+    tone_mapping: &ToneMapping,
+) -> Result<(), RaytraceError> {
+    let path = Path::new(filename);
+    match extension(path).as_deref() {
+        Some("exr") => write_file(path, |w| write_exr(fb, &[], w)),
+        Some("pfm") => write_file(path, |w| write_pfm(fb, w)),
+        Some("hdr") => write_file(path, |w| write_hdr(fb, w)),
+        _ => fb
+            .to_rgb8(tone_mapping)
+            .save(path)
+            .map_err(|source| RaytraceError::Image {
+                path: path.to_path_buf(),
+                source,
+            }),
+    }
+}
+
+/// Save the framebuffer like [`save`], together with AOVs: as further layers of an `.exr`
+/// file, otherwise as separate files named after the image (see [`aov_path`]). 8 bit AOV
+/// images show the values mapped by [`Aov::to_display`].
+pub fn save_with_aovs(
+    fb: &Framebuffer,
+    aovs: &[(Aov, Framebuffer)],
+    filename: &str,
+    tone_mapping: &ToneMapping,
+) -> Result<(), RaytraceError> {
+    let path = Path::new(filename);
+    let extension = extension(path);
+    if extension.as_deref() == Some("exr") {
+        return write_file(path, |w| write_exr(fb, aovs, w));
+    }
+    save(fb, filename, tone_mapping)?;
+    for (aov, buffer) in aovs {
+        let aov_filename = aov_path(filename, *aov);
+        match extension.as_deref() {
+            Some("hdr") | Some("pfm") => save(buffer, &aov_filename, tone_mapping)?,
+            _ => {
+                // only the albedo is a color, the other values are stored linearly
+                let encoding = ToneMapping {
+                    gamma: if *aov == Aov::Albedo { None } else { Some(1.0) },
+                    ..Default::default()
+                };
+                save(&aov.to_display(buffer), &aov_filename, &encoding)?
+            }
+        }
+    }
+    Ok(())
+}
+
+/// File name of an AOV saved next to the image `filename`, e.g. `image.depth.png` for
+/// `image.png`.
+pub fn aov_path(filename: &str, aov: Aov) -> String {
+    let path = Path::new(filename);
+    let name = match extension(path) {
+        Some(ext) => format!("{}.{}", aov.name(), ext),
+        None => aov.name().to_string(),
+    };
+    path.with_extension(name).to_string_lossy().into_owned()
+}
+
+/// Load a float image saved by [`save_with_aovs`] together with its AOVs: the layers of an
+/// `.exr` file (uncompressed with 32 bit float channels only), or the files next to a
+/// `.pfm` image.
+pub fn load(filename: &str) -> Result<(Framebuffer, Vec<(Aov, Framebuffer)>), RaytraceError> {
+    let path = Path::new(filename);
+    let read = |path: &Path| {
+        std::fs::read(path).map_err(|source| RaytraceError::Io {
+            path: path.to_path_buf(),
+            source,
+        })
+    };
+    let invalid = |path: &Path, message: String| RaytraceError::InvalidImage {
+        path: path.to_path_buf(),
+        message,
+    };
+    match extension(path).as_deref() {
+        Some("exr") => read_exr(&read(path)?).map_err(|e| invalid(path, e)),
+        Some("pfm") => {
+            let fb = read_pfm(&read(path)?).map_err(|e| invalid(path, e))?;
+            let mut aovs = vec![];
+            for &aov in &Aov::ALL {
+                let aov_filename = aov_path(filename, aov);
+                let aov_path = Path::new(&aov_filename);
+                if aov_path.exists() {
+                    let buffer = read_pfm(&read(aov_path)?).map_err(|e| invalid(aov_path, e))?;
+                    aovs.push((aov, buffer));
+                }
+            }
+            Ok((fb, aovs))
+        }
+        _ => Err(invalid(
+            path,
+            "only .exr and .pfm images can be loaded".to_string(),
+        )),
+    }
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+}
+
+fn write_file<F>(path: &Path, write: F) -> Result<(), RaytraceError>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<(), image::ImageError>,
+{
+    let io_error = |source| RaytraceError::Io {
+        path: path.to_path_buf(),
+        source,
+    };
+    let mut w = BufWriter::new(File::create(path).map_err(io_error)?);
+    write(&mut w).map_err(|e| match e {
+        image::ImageError::IoError(source) => io_error(source),
+        source => RaytraceError::Image {
+            path: path.to_path_buf(),
+            source,
+        },
+    })?;
+    w.flush().map_err(io_error)
+}
+
+/// Write an uncompressed single part scanline OpenEXR image with 32 bit float channels: R, G
+/// and B of the framebuffer followed by the channels of the AOVs as layers, e.g. `normal.X`.
+pub fn write_exr<W: Write>(
+    fb: &Framebuffer,
+    aovs: &[(Aov, Framebuffer)],
+    w: &mut W,
+) -> Result<(), image::ImageError> {
+    // name, framebuffer and component of every channel, stored in alphabetical order
+    let mut channels: Vec<(String, &Framebuffer, usize)> = ["R", "G", "B"]
+        .iter()
+        .enumerate()
+        .map(|(i, name)| (name.to_string(), fb, i))
+        .collect();
+    for (aov, buffer) in aovs {
+        for (i, name) in aov.channels().iter().enumerate() {
+            channels.push((format!("{}.{}", aov.name(), name), buffer, i));
+        }
+    }
+    channels.sort_by(|a, b| a.0.cmp(&b.0));
+
+    // magic number and version 2 without any flags
+    let mut header = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+    let mut attribute = |name: &str, kind: &str, value: &[u8]| {
+        header.extend_from_slice(name.as_bytes());
+        header.push(0);
+        header.extend_from_slice(kind.as_bytes());
+        header.push(0);
+        header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+        header.extend_from_slice(value);
+    };
+    let mut chlist = vec![];
+    for (name, _, _) in &channels {
+        chlist.extend_from_slice(name.as_bytes());
+        chlist.push(0);
+        // pixel type FLOAT, not linear, reserved
+        chlist.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0]);
+        // x and y sampling
+        chlist.extend_from_slice(&[1, 0, 0, 0, 1, 0, 0, 0]);
+    }
+    chlist.push(0);
+    let mut window = vec![];
+    for v in &[0, 0, fb.width as i32 - 1, fb.height as i32 - 1] {
+        window.extend_from_slice(&v.to_le_bytes());
+    }
+    attribute("channels", "chlist", &chlist);
+    attribute("compression", "compression", &[0]);
+    attribute("dataWindow", "box2i", &window);
+    attribute("displayWindow", "box2i", &window);
+    attribute("lineOrder", "lineOrder", &[0]);
+    attribute("pixelAspectRatio", "float", &1.0_f32.to_le_bytes());
+    attribute("screenWindowCenter", "v2f", &[0; 8]);
+    attribute("screenWindowWidth", "float", &1.0_f32.to_le_bytes());
+    header.push(0);
+    w.write_all(&header)?;
+
+    // offset table, followed by one block per scanline: y, data size and the channels
+    // one after another
+    let data_size = 4 * channels.len() * fb.width as usize;
+    let first_block = header.len() + 8 * fb.height as usize;
+    for y in 0..fb.height as usize {
+        let offset = first_block + y * (8 + data_size);
+        w.write_all(&(offset as u64).to_le_bytes())?;
+    }
+    for y in 0..fb.height {
+        w.write_all(&(y as i32).to_le_bytes())?;
+        w.write_all(&(data_size as i32).to_le_bytes())?;
+        for (_, buffer, i) in &channels {
+            for x in 0..fb.width {
+                w.write_all(&buffer.get(x, y).i(*i).to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write a color Portable Float Map, little endian with the bottom row first.
+pub fn write_pfm<W: Write>(fb: &Framebuffer, w: &mut W) -> Result<(), image::ImageError> {
+    write!(w, "PF\n{} {}\n-1.0\n", fb.width, fb.height)?;
+    for y in (0..fb.height).rev() {
+        for x in 0..fb.width {
+            let c = fb.get(x, y);
+            for i in 0..3 {
+                w.write_all(&c.i(i).to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read an image written by [`write_exr`], or any other single part scanline OpenEXR image
+/// without compression and with 32 bit float channels. Channels other than R, G, B and the
+/// AOV layers are ignored.
+pub fn read_exr(bytes: &[u8]) -> Result<(Framebuffer, Vec<(Aov, Framebuffer)>), String> {
+    let mut pos: usize = 0;
+    let mut take = |n: usize| {
+        let slice = pos
+            .checked_add(n)
+            .and_then(|end| bytes.get(pos..end))
+            .ok_or_else(|| "unexpected end of file".to_string())?;
+        pos += n;
+        Ok::<_, String>(slice)
+    };
+    if take(4)? != [0x76, 0x2f, 0x31, 0x01] {
+        return Err("not an OpenEXR file".to_string());
+    }
+    if take(4)? != [2, 0, 0, 0] {
+        return Err("only single part scanline images are supported".to_string());
+    }
+
+    let mut channels = vec![];
+    let mut window = None;
+    loop {
+        let name = read_string(&mut take)?;
+        if name.is_empty() {
+            break;
+        }
+        let kind = read_string(&mut take)?;
+        let size = i32_at(take(4)?, 0) as usize;
+        let value = take(size)?;
+        match (name.as_str(), kind.as_str()) {
+            ("channels", "chlist") => {
+                let mut k = 0;
+                while k < value.len() && value[k] != 0 {
+                    let end = k + value[k..].iter().position(|&b| b == 0).unwrap_or(0);
+                    let channel = String::from_utf8_lossy(&value[k..end]).into_owned();
+                    if value.len() < end + 17 {
+                        return Err("invalid channel list".to_string());
+                    }
+                    if i32_at(value, end + 1) != 2 {
+                        return Err(format!("channel {} is not 32 bit float", channel));
+                    }
+                    channels.push(channel);
+                    k = end + 17;
+                }
+            }
+            ("compression", _) if value != [0] => {
+                return Err("only uncompressed images are supported".to_string());
+            }
+            ("dataWindow", "box2i") if size == 16 => {
+                window = Some([0, 4, 8, 12].map(|i| i32_at(value, i)));
+            }
+            _ => (),
+        }
+    }
+    let [x_min, y_min, x_max, y_max] = window.ok_or_else(|| "no data window".to_string())?;
+    let extent = |min: i32, max: i32| -> Result<u32, String> {
+        (i64::from(max) - i64::from(min) + 1)
+            .try_into()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| "invalid data window".to_string())
+    };
+    let (width, height) = (extent(x_min, x_max)?, extent(y_min, y_max)?);
+    if channels.is_empty() {
+        return Err("no channels".to_string());
+    }
+    // the offset table and every scanline with its y coordinate and size have to be present
+    // before anything is allocated for them
+    let line_size = (width as usize)
+        .checked_mul(4 * channels.len())
+        .and_then(|n| n.checked_add(8));
+    let size = line_size
+        .and_then(|n| n.checked_add(8))
+        .and_then(|n| n.checked_mul(height as usize));
+    if size.unwrap_or(usize::MAX) > bytes.len() {
+        return Err("image size exceeds the file".to_string());
+    }
+
+    // target framebuffer and component of every channel
+    let mut fb = Framebuffer::new(width, height);
+    let mut layers: HashMap<Aov, Framebuffer> = HashMap::new();
+    let targets: Vec<Option<(Option<Aov>, usize)>> = channels
+        .iter()
+        .map(|name| match name.as_str() {
+            "R" => Some((None, 0)),
+            "G" => Some((None, 1)),
+            "B" => Some((None, 2)),
+            _ => {
+                let (layer, channel) = name.split_at(name.find('.')?);
+                let aov = Aov::from_str(layer).ok()?;
+                let i = aov.channels().iter().position(|&c| c == &channel[1..])?;
+                layers
+                    .entry(aov)
+                    .or_insert_with(|| Framebuffer::new(width, height));
+                Some((Some(aov), i))
+            }
+        })
+        .collect();
+
+    let offsets = take(8 * height as usize)?.to_vec();
+    for line in 0..height as usize {
+        let offset = u64::from_le_bytes(offsets[8 * line..8 * line + 8].try_into().unwrap());
+        let block = bytes
+            .get(offset as usize..)
+            .ok_or_else(|| "invalid offset table".to_string())?;
+        if block.len() < 8 + 4 * channels.len() * width as usize {
+            return Err("unexpected end of file".to_string());
+        }
+        let y = i64::from(i32_at(block, 0)) - i64::from(y_min);
+        if y < 0 || y >= i64::from(height) {
+            return Err("invalid scanline".to_string());
+        }
+        let y = y as u32;
+        for (c, target) in targets.iter().enumerate() {
+            let target_fb = match target {
+                Some((None, _)) => &mut fb,
+                Some((Some(aov), _)) => layers.get_mut(aov).unwrap(),
+                None => continue,
+            };
+            let i = target.unwrap().1;
+            for x in 0..width {
+                let k = 8 + 4 * (c * width as usize + x as usize);
+                let mut v = target_fb.get(x, y);
+                v.set_i(i, f32::from_le_bytes(block[k..k + 4].try_into().unwrap()));
+                target_fb.set(x, y, v);
+            }
+        }
+    }
+
+    let aovs = Aov::ALL
+        .iter()
+        .filter_map(|aov| layers.remove(aov).map(|layer| (*aov, layer)))
+        .collect();
+    Ok((fb, aovs))
+}
+
+/// Read a color or grayscale Portable Float Map.
+pub fn read_pfm(bytes: &[u8]) -> Result<Framebuffer, String> {
+    // three header lines: type, size and scale (negative for little endian)
+    let mut header = vec![];
+    let mut pos = 0;
+    while header.len() < 3 {
+        let end = pos
+            + bytes[pos..]
+                .iter()
+                .position(|&b| b == b'\n')
+                .ok_or_else(|| "invalid header".to_string())?;
+        header.push(String::from_utf8_lossy(&bytes[pos..end]).trim().to_string());
+        pos = end + 1;
+    }
+    let channels = match header[0].as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err("not a PFM file".to_string()),
+    };
+    let size: Vec<u32> = header[1]
+        .split_whitespace()
+        .map(|s| s.parse().map_err(|_| "invalid size".to_string()))
+        .collect::<Result<_, _>>()?;
+    if size.len() != 2 {
+        return Err("invalid size".to_string());
+    }
+    let scale: f32 = header[2].parse().map_err(|_| "invalid scale".to_string())?;
+
+    let (width, height) = (size[0], size[1]);
+    let data = &bytes[pos..];
+    let data_size = (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|n| n.checked_mul(4 * channels));
+    if data_size.unwrap_or(usize::MAX) > data.len() {
+        return Err("image size exceeds the file".to_string());
+    }
+    let value = |k: usize| {
+        let b = [
+            data[4 * k],
+            data[4 * k + 1],
+            data[4 * k + 2],
+            data[4 * k + 3],
+        ];
+        if scale < 0.0 {
+            f32::from_le_bytes(b)
+        } else {
+            f32::from_be_bytes(b)
+        }
+    };
+    let mut fb = Framebuffer::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            // bottom row first
+            let k = channels * ((height - y - 1) * width + x) as usize;
+            let c = if channels == 3 {
+                Vec3::new(value(k), value(k + 1), value(k + 2))
+            } else {
+                Vec3::new(value(k), value(k), value(k))
+            };
+            fb.set(x, y, c);
+        }
+    }
+    Ok(fb)
+}
+
+fn read_string<'a, F>(take: &mut F) -> Result<String, String>
+where
+    F: FnMut(usize) -> Result<&'a [u8], String>,
+{
+    let mut s = vec![];
+    loop {
+        match take(1)?[0] {
+            0 => return Ok(String::from_utf8_lossy(&s).into_owned()),
+            b => s.push(b),
+        }
+    }
+}
+
+fn i32_at(bytes: &[u8], offset: usize) -> i32 {
+    i32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Write a Radiance RGBE image.
+pub fn write_hdr<W: Write>(fb: &Framebuffer, w: &mut W) -> Result<(), image::ImageError> {
+    let data: Vec<image::Rgb<f32>> = fb
+        .data
+        .iter()
+        .map(|c| image::Rgb([c.x(), c.y(), c.z()]))
+        .collect();
+    image::hdr::HDREncoder::new(w).encode(&data, fb.width as usize, fb.height as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_framebuffer() -> Framebuffer {
+        let mut fb = Framebuffer::new(3, 2);
+        fb.set(0, 0, Vec3::new(15.0, 0.5, 0.0));
+        fb.set(2, 1, Vec3::new(0.125, 1.0, 1000.0));
+        fb
+    }
+
+    fn f32_at(bytes: &[u8], offset: usize) -> f32 {
+        let mut b = [0; 4];
+        b.copy_from_slice(&bytes[offset..offset + 4]);
+        f32::from_le_bytes(b)
+    }
+
+    #[test]
+    fn test_write_pfm() {
+        let mut bytes = vec![];
+        write_pfm(&test_framebuffer(), &mut bytes).unwrap();
+        let header = b"PF\n3 2\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header);
+        assert_eq!(bytes.len(), header.len() + 3 * 2 * 12);
+        // bottom row first
+        assert_eq!(f32_at(&bytes, header.len() + 2 * 12 + 8), 1000.0);
+        assert_eq!(f32_at(&bytes, header.len() + 3 * 12), 15.0);
+    }
+
+    #[test]
+    fn test_write_exr() {
+        let mut bytes = vec![];
+        write_exr(&test_framebuffer(), &[], &mut bytes).unwrap();
+        assert_eq!(&bytes[..4], &[0x76, 0x2f, 0x31, 0x01]);
+        let data_size = 4 + 4 + 3 * 3 * 4;
+        let first_block = bytes.len() - 2 * data_size;
+        // the offset table points at the scanline blocks
+        let table = &bytes[first_block - 16..first_block];
+        assert_eq!(table[..8], (first_block as u64).to_le_bytes());
+        assert_eq!(table[8..], ((first_block + data_size) as u64).to_le_bytes());
+        // channels B, G, R of the first line, the second line with y = 1
+        assert_eq!(f32_at(&bytes, first_block + 8 + 24), 15.0);
+        assert_eq!(f32_at(&bytes, first_block + 8 + 12), 0.5);
+        assert_eq!(bytes[first_block + data_size], 1);
+        assert_eq!(f32_at(&bytes, first_block + data_size + 8 + 8), 1000.0);
+    }
+
+    #[test]
+    fn test_write_exr_layers() {
+        let fb = test_framebuffer();
+        let mut depth = Framebuffer::new(3, 2);
+        depth.set(1, 0, Vec3::new(7.0, 7.0, 7.0));
+        let mut bytes = vec![];
+        write_exr(&fb, &[(Aov::Depth, depth)], &mut bytes).unwrap();
+        // channels B, G, R, depth.Z
+        let data_size = 4 + 4 + 4 * 3 * 4;
+        let first_block = bytes.len() - 2 * data_size;
+        let header = String::from_utf8_lossy(&bytes[..first_block]);
+        assert!(header.contains("R\0\u{2}"));
+        assert!(header.contains("depth.Z\0\u{2}"));
+        assert_eq!(f32_at(&bytes, first_block + 8 + 24), 15.0);
+        assert_eq!(f32_at(&bytes, first_block + 8 + 36 + 4), 7.0);
+    }
+
+    #[test]
+    fn test_read_back() {
+        let fb = test_framebuffer();
+        let mut normal = Framebuffer::new(3, 2);
+        normal.set(2, 0, Vec3::new(0.0, -1.0, 0.0));
+        let aovs = vec![(Aov::Normal, normal.clone())];
+
+        let mut bytes = vec![];
+        write_exr(&fb, &aovs, &mut bytes).unwrap();
+        let (loaded, loaded_aovs) = read_exr(&bytes).unwrap();
+        assert_eq!(loaded.data, fb.data);
+        assert_eq!(loaded_aovs.len(), 1);
+        assert_eq!(loaded_aovs[0].0, Aov::Normal);
+        assert_eq!(loaded_aovs[0].1.data, normal.data);
+
+        let mut bytes = vec![];
+        write_pfm(&fb, &mut bytes).unwrap();
+        assert_eq!(read_pfm(&bytes).unwrap().data, fb.data);
+    }
+
+    #[test]
+    fn test_read_invalid_size() {
+        let mut exr = vec![];
+        write_exr(&test_framebuffer(), &[], &mut exr).unwrap();
+        let name = b"dataWindow\0box2i\0";
+        let window = name.len()
+            + 4
+            + (0..exr.len())
+                .find(|&i| exr[i..].starts_with(name))
+                .unwrap();
+        for (x_min, x_max) in &[(2, 0), (i32::MIN, i32::MAX), (0, 1 << 30)] {
+            let mut bytes = exr.clone();
+            bytes[window..window + 4].copy_from_slice(&x_min.to_le_bytes());
+            bytes[window + 8..window + 12].copy_from_slice(&x_max.to_le_bytes());
+            assert!(read_exr(&bytes).is_err());
+        }
+
+        for size in &["3 3", "65536 65536", "4294967295 4294967295"] {
+            let mut bytes = format!("PF\n{}\n-1.0\n", size).into_bytes();
+            bytes.extend_from_slice(&[0; 3 * 2 * 12]);
+            assert!(read_pfm(&bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn test_aov_path() {
+        assert_eq!(aov_path("out/image.png", Aov::Depth), "out/image.depth.png");
+        assert_eq!(aov_path("image.PFM", Aov::MaterialId), "image.id.pfm");
+        assert_eq!(aov_path("image", Aov::Normal), "image.normal");
+    }
+
+    #[test]
+    fn test_write_hdr() {
+        let mut bytes = vec![];
+        write_hdr(&test_framebuffer(), &mut bytes).unwrap();
+        let decoder = image::hdr::HdrDecoder::new(std::io::Cursor::new(bytes)).unwrap();
+        let data = decoder.read_image_hdr().unwrap();
+        assert_eq!(data[0].0, [15.0, 0.5, 0.0]);
+        assert!((data[5].0[2] - 1000.0).abs() < 10.0);
+    }
+}
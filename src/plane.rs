@@ -0,0 +1,131 @@
+use crate::boundingbox::BoundingBox;
+use crate::hitable::{HitRecord, Hitable};
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+use rand::RngCore;
+
+use std::sync::Arc;
+
+/// Infinite plane through `point` with the given `normal`.
+///
+/// The plane has no bounding box, `HitableList::into_bvh` keeps it outside of the BVH.
+/// The (u, v) coordinates are the distances from `point` along two tangent directions,
+/// so they are not limited to [0, 1].
+pub struct Plane {
+    pub point: Vec3,
+    pub normal: Vec3,
+    pub material: Arc<Material>,
+}
+
+impl Hitable for Plane {
+    fn hit(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+        _rng: &mut dyn RngCore,
+    ) -> Option<HitRecord<'_>> {
+        let n = self.normal.normalize();
+        let denom = Vec3::dot(n, r.direction);
+        if denom.abs() < 1e-8 {
+            return None;
+        }
+        let t = Vec3::dot(self.point - r.origin, n) / denom;
+        if t < t_min || t > t_max {
+            return None;
+        }
+
+        let p = r.point(t);
+        let tangent = if n.x().abs() > 0.9 {
+            Vec3::cross(n, Vec3::new(0.0, 1.0, 0.0))
+        } else {
+            Vec3::cross(n, Vec3::new(1.0, 0.0, 0.0))
+        }
+        .normalize();
+        let bitangent = Vec3::cross(n, tangent);
+        Some(HitRecord {
+            t,
+            p,
+            n,
+            u: Vec3::dot(p - self.point, tangent),
+            v: Vec3::dot(p - self.point, bitangent),
+            material: &self.material,
+            color: Vec3::new(1.0, 1.0, 1.0),
+            light: None,
+        })
+    }
+
+    fn bounding_box(&self) -> Option<BoundingBox> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hitable::HitableList;
+    use crate::sphere::Sphere;
+    use crate::texture::Texture;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn diffuse() -> Arc<Material> {
+        Arc::new(Material::Diffuse {
+            albedo: Texture::Constant {
+                color: Vec3::new(0.5, 0.5, 0.5),
+            },
+        })
+    }
+
+    #[test]
+    fn test_plane_in_bvh() {
+        let world = HitableList {
+            list: vec![
+                Box::new(Plane {
+                    point: Vec3::new(0.0, -1.0, 0.0),
+                    normal: Vec3::new(0.0, 1.0, 0.0),
+                    material: diffuse(),
+                }),
+                Box::new(Sphere::new(Vec3::new(0.0, 0.0, 0.0), 0.5, diffuse())),
+                Box::new(Sphere::new(Vec3::new(2.0, 0.0, 0.0), 0.5, diffuse())),
+            ],
+        }
+        .into_bvh()
+        .unwrap();
+        // the BVH over both spheres and the plane
+        assert_eq!(world.list.len(), 2);
+        assert!(world.bounding_box().is_none());
+
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        let sphere_hit = world.hit(
+            &Ray::new(Vec3::new(0.0, 5.0, 0.0), down),
+            0.001,
+            f32::MAX,
+            &mut StdRng::seed_from_u64(0),
+        );
+        assert!((sphere_hit.unwrap().t - 4.5).abs() < 1e-4);
+        let plane_hit = world.hit(
+            &Ray::new(Vec3::new(100.0, 5.0, 7.0), down),
+            0.001,
+            f32::MAX,
+            &mut StdRng::seed_from_u64(0),
+        );
+        let rec = plane_hit.unwrap();
+        assert!((rec.t - 6.0).abs() < 1e-4);
+        assert_eq!(rec.n, Vec3::new(0.0, 1.0, 0.0));
+
+        // a scene made only of unbounded objects needs no BVH at all
+        let planes = HitableList {
+            list: vec![Box::new(Plane {
+                point: Vec3::default(),
+                normal: Vec3::new(0.0, 0.0, 1.0),
+                material: diffuse(),
+            })],
+        }
+        .into_bvh()
+        .unwrap();
+        assert_eq!(planes.list.len(), 1);
+    }
+}
@@ -0,0 +1,447 @@
+use crate::error::RaytraceError;
+use crate::material::Material;
+use crate::texture::Texture;
+use crate::triangle::TriangleMesh;
+use crate::vec3::Vec3;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Load a PLY file (ASCII or binary) into a triangle mesh.
+///
+/// Polygons are triangulated as fans. If `material` is given it is used for the whole
+/// mesh, otherwise a white diffuse material for meshes with vertex colors and a light grey
+/// one for meshes without. Vertex colors (`red`, `green`, `blue`) are interpolated over the
+/// faces and tint the material.
+pub fn load(path: &Path, material: Option<Arc<Material>>) -> Result<TriangleMesh, RaytraceError> {
+    let file = File::open(path).map_err(|source| RaytraceError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let ply = parse(&mut BufReader::new(file)).map_err(|message| RaytraceError::PlyParse {
+        path: path.to_path_buf(),
+        message,
+    })?;
+
+    let material = material.unwrap_or_else(|| {
+        let grey = if ply.colors.is_empty() { 0.73 } else { 1.0 };
+        Arc::new(Material::Diffuse {
+            albedo: Texture::Constant {
+                color: Vec3::new(grey, grey, grey),
+            },
+        })
+    });
+    let mesh = TriangleMesh::new(ply.positions, ply.normals, ply.uvs, ply.indices, material);
+    Ok(if ply.colors.is_empty() {
+        mesh
+    } else {
+        mesh.with_vertex_colors(ply.colors)
+    })
+}
+
+/// vertex and face data read from a PLY file, optional attributes are empty if missing
+#[derive(Default)]
+struct PlyMesh {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<(f32, f32)>,
+    colors: Vec<Vec3>,
+    indices: Vec<[u32; 3]>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone, Copy)]
+enum ScalarType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+enum PropertyType {
+    Scalar(ScalarType),
+    List(ScalarType, ScalarType),
+}
+
+struct Property {
+    name: String,
+    ty: PropertyType,
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+impl ScalarType {
+    fn parse(s: &str) -> Result<ScalarType, String> {
+        match s {
+            "char" | "int8" => Ok(ScalarType::I8),
+            "uchar" | "uint8" => Ok(ScalarType::U8),
+            "short" | "int16" => Ok(ScalarType::I16),
+            "ushort" | "uint16" => Ok(ScalarType::U16),
+            "int" | "int32" => Ok(ScalarType::I32),
+            "uint" | "uint32" => Ok(ScalarType::U32),
+            "float" | "float32" => Ok(ScalarType::F32),
+            "double" | "float64" => Ok(ScalarType::F64),
+            _ => Err(format!("unknown property type: {}", s)),
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            ScalarType::I8 | ScalarType::U8 => 1,
+            ScalarType::I16 | ScalarType::U16 => 2,
+            ScalarType::I32 | ScalarType::U32 | ScalarType::F32 => 4,
+            ScalarType::F64 => 8,
+        }
+    }
+
+    /// scale factor mapping a color component of this type to [0, 1]
+    fn color_scale(self) -> f64 {
+        match self {
+            ScalarType::U8 => 1.0 / 255.0,
+            ScalarType::U16 => 1.0 / 65535.0,
+            _ => 1.0,
+        }
+    }
+}
+
+/// Reads the values of a PLY body, either as whitespace separated words or binary data.
+struct ValueReader<'a, R: BufRead> {
+    reader: &'a mut R,
+    format: Format,
+    words: Vec<String>,
+    /// binary body, read completely so that sizes can be checked against it
+    body: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a, R: BufRead> ValueReader<'a, R> {
+    fn new(reader: &'a mut R, format: Format) -> Result<ValueReader<'a, R>, String> {
+        let mut body = vec![];
+        if format != Format::Ascii {
+            reader.read_to_end(&mut body).map_err(|e| e.to_string())?;
+        }
+        Ok(ValueReader {
+            reader,
+            format,
+            words: vec![],
+            body,
+            pos: 0,
+        })
+    }
+
+    /// Read a list of `count` values, which must fit into the rest of a binary body.
+    fn read_list(&mut self, count: usize, ty: ScalarType) -> Result<Vec<f64>, String> {
+        if self.format != Format::Ascii
+            && count.saturating_mul(ty.size()) > self.body.len() - self.pos
+        {
+            return Err("unexpected end of file".to_string());
+        }
+        (0..count).map(|_| self.read(ty)).collect()
+    }
+
+    fn read(&mut self, ty: ScalarType) -> Result<f64, String> {
+        if self.format == Format::Ascii {
+            return self.read_word()?.parse::<f64>().map_err(|e| e.to_string());
+        }
+
+        let mut buf = [0u8; 8];
+        let buf = &mut buf[..ty.size()];
+        let bytes = self
+            .body
+            .get(self.pos..self.pos + buf.len())
+            .ok_or("unexpected end of file")?;
+        buf.copy_from_slice(bytes);
+        self.pos += buf.len();
+        if self.format == Format::BinaryBigEndian {
+            buf.reverse();
+        }
+        let mut b = [0u8; 8];
+        b[..buf.len()].copy_from_slice(buf);
+        Ok(match ty {
+            ScalarType::I8 => f64::from(b[0] as i8),
+            ScalarType::U8 => f64::from(b[0]),
+            ScalarType::I16 => f64::from(i16::from_le_bytes([b[0], b[1]])),
+            ScalarType::U16 => f64::from(u16::from_le_bytes([b[0], b[1]])),
+            ScalarType::I32 => f64::from(i32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            ScalarType::U32 => f64::from(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            ScalarType::F32 => f64::from(f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            ScalarType::F64 => f64::from_le_bytes(b),
+        })
+    }
+
+    fn read_word(&mut self) -> Result<String, String> {
+        while self.words.is_empty() {
+            let mut line = String::new();
+            if self
+                .reader
+                .read_line(&mut line)
+                .map_err(|e| e.to_string())?
+                == 0
+            {
+                return Err("unexpected end of file".to_string());
+            }
+            self.words = line.split_whitespace().rev().map(String::from).collect();
+        }
+        Ok(self.words.pop().unwrap())
+    }
+}
+
+fn parse_header<R: BufRead>(reader: &mut R) -> Result<(Format, Vec<Element>), String> {
+    let mut read_line = || {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) => Err("unexpected end of header".to_string()),
+            Ok(_) => Ok(line),
+            Err(e) => Err(e.to_string()),
+        }
+    };
+
+    if read_line()?.trim() != "ply" {
+        return Err("not a PLY file".to_string());
+    }
+
+    let mut format = None;
+    let mut elements: Vec<Element> = vec![];
+    loop {
+        let line = read_line()?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["end_header"] => break,
+            ["format", f, _] => {
+                format = Some(match *f {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::BinaryLittleEndian,
+                    "binary_big_endian" => Format::BinaryBigEndian,
+                    _ => return Err(format!("unknown format: {}", f)),
+                })
+            }
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .map_err(|_| format!("invalid count: {}", count))?,
+                properties: vec![],
+            }),
+            ["property", "list", count_ty, item_ty, name] => elements
+                .last_mut()
+                .ok_or("property before element")?
+                .properties
+                .push(Property {
+                    name: name.to_string(),
+                    ty: PropertyType::List(
+                        ScalarType::parse(count_ty)?,
+                        ScalarType::parse(item_ty)?,
+                    ),
+                }),
+            ["property", ty, name] => elements
+                .last_mut()
+                .ok_or("property before element")?
+                .properties
+                .push(Property {
+                    name: name.to_string(),
+                    ty: PropertyType::Scalar(ScalarType::parse(ty)?),
+                }),
+            ["comment", ..] | ["obj_info", ..] | [] => (),
+            _ => return Err(format!("invalid header line: {}", line.trim())),
+        }
+    }
+
+    Ok((format.ok_or("missing format")?, elements))
+}
+
+fn parse<R: BufRead>(reader: &mut R) -> Result<PlyMesh, String> {
+    let (format, elements) = parse_header(reader)?;
+    let mut values = ValueReader::new(reader, format)?;
+
+    let mut mesh = PlyMesh::default();
+    for element in &elements {
+        let index_of = |name: &str| element.properties.iter().position(|p| p.name == name);
+        let xyz = [index_of("x"), index_of("y"), index_of("z")];
+        let nxyz = [index_of("nx"), index_of("ny"), index_of("nz")];
+        let rgb = [index_of("red"), index_of("green"), index_of("blue")];
+        let uv = match (index_of("u"), index_of("v")) {
+            (Some(u), Some(v)) => [Some(u), Some(v)],
+            _ => [index_of("s"), index_of("t")],
+        };
+        let faces = index_of("vertex_indices").or_else(|| index_of("vertex_index"));
+
+        for _ in 0..element.count {
+            let mut scalars = vec![0.0; element.properties.len()];
+            let mut list = vec![];
+            for (i, property) in element.properties.iter().enumerate() {
+                match property.ty {
+                    PropertyType::Scalar(ty) => scalars[i] = values.read(ty)?,
+                    PropertyType::List(count_ty, item_ty) => {
+                        let count = values.read(count_ty)?;
+                        if count < 0.0 || count.fract() != 0.0 {
+                            return Err(format!("invalid list length: {}", count));
+                        }
+                        let items = values.read_list(count as usize, item_ty)?;
+                        if Some(i) == faces {
+                            list = items;
+                        }
+                    }
+                }
+            }
+
+            let vec3 = |idx: &[Option<usize>; 3], scale: f64| {
+                Some(Vec3::new(
+                    (scalars[idx[0]?] * scale) as f32,
+                    (scalars[idx[1]?] * scale) as f32,
+                    (scalars[idx[2]?] * scale) as f32,
+                ))
+            };
+            if element.name == "vertex" {
+                mesh.positions
+                    .push(vec3(&xyz, 1.0).ok_or("vertex without position")?);
+                if let Some(n) = vec3(&nxyz, 1.0) {
+                    mesh.normals.push(n);
+                }
+                if let (Some(u), Some(v)) = (uv[0], uv[1]) {
+                    mesh.uvs.push((scalars[u] as f32, scalars[v] as f32));
+                }
+                if let Some(r) = rgb[0] {
+                    let scale = match element.properties[r].ty {
+                        PropertyType::Scalar(ty) => ty.color_scale(),
+                        PropertyType::List(..) => 1.0,
+                    };
+                    if let Some(c) = vec3(&rgb, scale) {
+                        mesh.colors.push(c);
+                    }
+                }
+            } else if element.name == "face" {
+                if let Some(&i) = list
+                    .iter()
+                    .find(|&&i| i < 0.0 || i.fract() != 0.0 || i > f64::from(u32::MAX))
+                {
+                    return Err(format!("invalid vertex index: {}", i));
+                }
+                for k in 2..list.len() {
+                    mesh.indices
+                        .push([list[0] as u32, list[k - 1] as u32, list[k] as u32]);
+                }
+            }
+        }
+    }
+
+    let n = mesh.positions.len();
+    if mesh.indices.iter().flatten().any(|&i| i as usize >= n) {
+        return Err("vertex index out of range".to_string());
+    }
+    // optional attributes are given for all vertices or none
+    for (name, len) in &[
+        ("normals", mesh.normals.len()),
+        ("texture coordinates", mesh.uvs.len()),
+        ("colors", mesh.colors.len()),
+    ] {
+        if *len != 0 && *len != n {
+            return Err(format!("{} {} for {} vertices", len, name, n));
+        }
+    }
+    Ok(mesh)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUAD_ASCII: &str = "ply
+format ascii 1.0
+comment a colored unit quad
+element vertex 4
+property float x
+property float y
+property float z
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+end_header
+0 0 0 255 0 0
+1 0 0 0 255 0
+1 1 0 0 0 255
+0 1 0 255 255 255
+4 0 1 2 3
+";
+
+    #[test]
+    fn test_parse_ascii() {
+        let mesh = parse(&mut QUAD_ASCII.as_bytes()).unwrap();
+        assert_eq!(mesh.positions.len(), 4);
+        assert_eq!(mesh.positions[2], Vec3::new(1.0, 1.0, 0.0));
+        assert_eq!(mesh.colors[1], Vec3::new(0.0, 1.0, 0.0));
+        assert!(mesh.normals.is_empty());
+        assert_eq!(mesh.indices, vec![[0, 1, 2], [0, 2, 3]]);
+    }
+
+    #[test]
+    fn test_parse_binary() {
+        for (format, big_endian) in &[("binary_little_endian", false), ("binary_big_endian", true)]
+        {
+            let mut data = format!(
+                "ply\nformat {} 1.0\nelement vertex 3\nproperty float x\nproperty float y\n\
+                 property float z\nelement face 1\nproperty list uchar uint vertex_indices\n\
+                 end_header\n",
+                format
+            )
+            .into_bytes();
+            for v in &[0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 2.0, 0.5] {
+                data.extend_from_slice(&if *big_endian {
+                    v.to_be_bytes()
+                } else {
+                    v.to_le_bytes()
+                });
+            }
+            data.push(3);
+            for i in &[0u32, 1, 2] {
+                data.extend_from_slice(&if *big_endian {
+                    i.to_be_bytes()
+                } else {
+                    i.to_le_bytes()
+                });
+            }
+
+            let mesh = parse(&mut data.as_slice()).unwrap();
+            assert_eq!(mesh.positions[2], Vec3::new(0.0, 2.0, 0.5));
+            assert_eq!(mesh.indices, vec![[0, 1, 2]]);
+        }
+    }
+
+    #[test]
+    fn test_invalid_index() {
+        for face in &["3 0 1 4", "3 0 1 -1", "3 0 1 1.5", "-3 0 1 2"] {
+            let data = QUAD_ASCII.replace("4 0 1 2 3", face);
+            assert!(parse(&mut data.as_bytes()).is_err(), "{}", face);
+        }
+    }
+
+    #[test]
+    fn test_list_longer_than_file() {
+        let mut data = b"ply\nformat binary_little_endian 1.0\nelement face 1\n\
+            property list uint uint vertex_indices\nend_header\n"
+            .to_vec();
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        data.extend_from_slice(&[0; 8]);
+        assert_eq!(
+            parse(&mut data.as_slice()).err().unwrap(),
+            "unexpected end of file"
+        );
+    }
+}
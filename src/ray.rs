@@ -13,4 +13,13 @@ impl Ray {
     pub fn point(&self, t: f32) -> Vec3 {
         self.origin + t * self.direction
     }
+
+    /// Component-wise reciprocal of the direction, as used by `BoundingBox::hit`.
+    pub fn inv_direction(&self) -> Vec3 {
+        Vec3::new(
+            1.0 / self.direction.x(),
+            1.0 / self.direction.y(),
+            1.0 / self.direction.z(),
+        )
+    }
 }
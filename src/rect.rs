@@ -1,16 +1,35 @@
 use crate::boundingbox::BoundingBox;
 use crate::hitable::{HitRecord, Hitable, HitableList};
+use crate::light::{Light, LightShape};
 use crate::material::Material;
 use crate::ray::Ray;
 use crate::vec3::Vec3;
 
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use std::sync::Arc;
+
 pub struct Rect {
     pub a: Axes,
     pub flip_normal: bool,
-    pub material: Material,
+    pub material: Arc<Material>,
+    /// index of the rect in the lights of the scene, see [`HitRecord::light`]
+    light: Option<usize>,
+}
+
+impl Rect {
+    pub fn new(a: Axes, flip_normal: bool, material: Arc<Material>) -> Rect {
+        Rect {
+            a,
+            flip_normal,
+            material,
+            light: None,
+        }
+    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum Axes {
     XY {
         x: (f32, f32),
@@ -30,7 +49,13 @@ pub enum Axes {
 }
 
 impl Hitable for Rect {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+        _rng: &mut dyn RngCore,
+    ) -> Option<HitRecord<'_>> {
         // check if ray intersects the rect plane
         let t = match self.a {
             Axes::XY { z, .. } => (z - r.origin.z()) / r.direction.z(),
@@ -84,6 +109,8 @@ impl Hitable for Rect {
                     u,
                     v,
                     material: &self.material,
+                    color: Vec3::new(1.0, 1.0, 1.0),
+                    light: self.light,
                 })
             } else {
                 None
@@ -107,6 +134,16 @@ impl Hitable for Rect {
             }),
         }
     }
+
+    fn collect_lights(&mut self, lights: &mut Vec<Light>) {
+        if let Material::DiffuseLight { .. } = *self.material {
+            self.light = Some(lights.len());
+            lights.push(Light::new(
+                LightShape::Rect { a: self.a.clone() },
+                &self.material,
+            ));
+        }
+    }
 }
 
 pub struct Cuboid {
@@ -116,66 +153,66 @@ pub struct Cuboid {
 }
 
 impl Cuboid {
-    pub fn new(p_min: Vec3, p_max: Vec3, material: Material) -> Cuboid {
+    pub fn new(p_min: Vec3, p_max: Vec3, material: Arc<Material>) -> Cuboid {
         Cuboid {
             p_min,
             p_max,
             faces: Box::new(HitableList {
                 list: vec![
-                    Box::new(Rect {
-                        a: Axes::XY {
+                    Box::new(Rect::new(
+                        Axes::XY {
                             x: (p_min.x(), p_max.x()),
                             y: (p_min.y(), p_max.y()),
                             z: p_max.z(),
                         },
-                        flip_normal: false,
-                        material: material.clone(),
-                    }),
-                    Box::new(Rect {
-                        a: Axes::XY {
+                        false,
+                        material.clone(),
+                    )),
+                    Box::new(Rect::new(
+                        Axes::XY {
                             x: (p_min.x(), p_max.x()),
                             y: (p_min.y(), p_max.y()),
                             z: p_min.z(),
                         },
-                        flip_normal: true,
-                        material: material.clone(),
-                    }),
-                    Box::new(Rect {
-                        a: Axes::XZ {
+                        true,
+                        material.clone(),
+                    )),
+                    Box::new(Rect::new(
+                        Axes::XZ {
                             x: (p_min.x(), p_max.x()),
                             y: p_max.y(),
                             z: (p_min.z(), p_max.z()),
                         },
-                        flip_normal: false,
-                        material: material.clone(),
-                    }),
-                    Box::new(Rect {
-                        a: Axes::XZ {
+                        false,
+                        material.clone(),
+                    )),
+                    Box::new(Rect::new(
+                        Axes::XZ {
                             x: (p_min.x(), p_max.x()),
                             y: p_min.y(),
                             z: (p_min.z(), p_max.z()),
                         },
-                        flip_normal: true,
-                        material: material.clone(),
-                    }),
-                    Box::new(Rect {
-                        a: Axes::YZ {
+                        true,
+                        material.clone(),
+                    )),
+                    Box::new(Rect::new(
+                        Axes::YZ {
                             x: p_max.x(),
                             y: (p_min.y(), p_max.y()),
                             z: (p_min.z(), p_max.z()),
                         },
-                        flip_normal: false,
-                        material: material.clone(),
-                    }),
-                    Box::new(Rect {
-                        a: Axes::YZ {
+                        false,
+                        material.clone(),
+                    )),
+                    Box::new(Rect::new(
+                        Axes::YZ {
                             x: p_min.x(),
                             y: (p_min.y(), p_max.y()),
                             z: (p_min.z(), p_max.z()),
                         },
-                        flip_normal: true,
+                        true,
                         material,
-                    }),
+                    )),
                 ],
             }),
         }
@@ -183,8 +220,8 @@ impl Cuboid {
 }
 
 impl Hitable for Cuboid {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        self.faces.hit(r, t_min, t_max)
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rng: &mut dyn RngCore) -> Option<HitRecord<'_>> {
+        self.faces.hit(r, t_min, t_max, rng)
     }
 
     fn bounding_box(&self) -> Option<BoundingBox> {
@@ -193,4 +230,8 @@ impl Hitable for Cuboid {
             max: self.p_max,
         })
     }
+
+    fn collect_lights(&mut self, lights: &mut Vec<Light>) {
+        self.faces.collect_lights(lights);
+    }
 }
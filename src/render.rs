@@ -1,29 +1,176 @@
+use crate::aov::{self, Aov};
+use crate::bdpt;
+use crate::bvhnode::BvhBuilder;
 use crate::camera::Camera;
-use crate::hitable::{Hitable, HitableList};
+use crate::checkpoint;
+use crate::checkpoint::Checkpoint;
+use crate::denoise::Denoiser;
+use crate::error::RaytraceError;
+use crate::framebuffer::{luminance, Accumulator, Framebuffer};
+use crate::hitable::{HitRecord, Hitable, HitableList};
+use crate::light::Light;
+use crate::material::Material;
+use crate::output;
 use crate::ray::Ray;
+use crate::tonemap::ToneMapping;
 use crate::vec3::Vec3;
 
-use image::ImageBuffer;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
 
 use rayon::prelude::*;
 
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct RenderParams {
     pub nx: u32,
     pub ny: u32,
     pub ns: usize,
     pub filename: String,
+    /// how the BVH over the scene objects is built
+    pub bvh: BvhBuilder,
+    /// edge length of the square tiles rendered in parallel
+    pub tile_size: u32,
+    /// seed of all random decisions, the same seed gives the same image
+    pub seed: u64,
+    /// render in passes of 1, 2, 4, ... samples per pixel, saving the image in between
+    pub progressive: bool,
+    /// minimum number of seconds between two intermediate images of a progressive render
+    pub snapshot_interval: u64,
+    /// minimum number of seconds between two checkpoints, 0 disables checkpoints
+    pub checkpoint_interval: u64,
+    /// render passes until this many seconds are used up instead of taking `ns` samples,
+    /// 0 disables the limit
+    pub time_limit: u64,
+    /// stop sampling pixels whose relative standard error drops below this threshold and
+    /// spend their share of the `ns` samples per pixel on the others, 0 disables it
+    pub adaptive_threshold: f32,
+    /// file to save a grayscale map of the samples taken per pixel to
+    pub sample_map: Option<String>,
+    /// conversion of the radiance to 8 bit images, not used for float image formats
+    pub tone_mapping: ToneMapping,
+    /// auxiliary images rendered after the image, see [`output::save_with_aovs`]
+    pub aovs: Vec<Aov>,
+    /// denoise the finished image, guided by albedo, normal and depth AOVs
+    pub denoise: Option<Denoiser>,
+    /// sample the lights directly at every diffuse bounce (next-event estimation) instead
+    /// of relying on scattered rays to hit them
+    pub light_sampling: bool,
+    /// algorithm estimating the light arriving along the camera rays
+    pub integrator: Integrator,
+    /// maximum number of surfaces or volumes a path visits
+    pub max_depth: u32,
+    /// number of bounces after which paths are randomly ended with a probability growing as
+    /// their contribution drops (Russian roulette), the survivors are weighted up to match
+    pub roulette_depth: u32,
+}
+
+/// Algorithm estimating the light arriving along the camera rays.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Integrator {
+    /// paths traced from the camera, sampling the lights at every bounce
+    #[default]
+    Path,
+    /// bidirectional path tracing: paths from the camera and from the lights connected in
+    /// every possible way, see [`bdpt::color`]
+    Bdpt,
 }
 
+impl FromStr for Integrator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Integrator, String> {
+        match s {
+            "path" => Ok(Integrator::Path),
+            "bdpt" => Ok(Integrator::Bdpt),
+            _ => Err(format!("unknown integrator: {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub enum Background {
     Color(Vec3),
     BlendY(Vec3, Vec3),
 }
 
+impl Default for RenderParams {
+    fn default() -> RenderParams {
+        RenderParams {
+            nx: 320,
+            ny: 200,
+            ns: 256,
+            filename: String::from("image.png"),
+            bvh: BvhBuilder::default(),
+            tile_size: 32,
+            seed: 0,
+            progressive: false,
+            snapshot_interval: 0,
+            checkpoint_interval: 0,
+            time_limit: 0,
+            adaptive_threshold: 0.0,
+            sample_map: None,
+            tone_mapping: ToneMapping::default(),
+            aovs: vec![],
+            denoise: None,
+            light_sampling: true,
+            integrator: Integrator::default(),
+            max_depth: 64,
+            roulette_depth: 3,
+        }
+    }
+}
+
+impl RenderParams {
+    pub fn validate(&self) -> Result<(), RaytraceError> {
+        let invalid = |name: &str, value: &dyn ToString| {
+            Err(RaytraceError::InvalidParameter {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+        };
+        if self.nx == 0 {
+            invalid("width", &self.nx)
+        } else if self.ny == 0 {
+            invalid("height", &self.ny)
+        } else if self.ns == 0 {
+            invalid("samples", &self.ns)
+        } else if self.tile_size == 0 {
+            invalid("tile-size", &self.tile_size)
+        } else if self.max_depth == 0 {
+            invalid("max-depth", &self.max_depth)
+        } else if self.adaptive_threshold.is_nan() || self.adaptive_threshold < 0.0 {
+            invalid("adaptive", &self.adaptive_threshold)
+        } else if !self.tone_mapping.white_point.is_finite() || self.tone_mapping.white_point <= 0.0
+        {
+            invalid("white-point", &self.tone_mapping.white_point)
+        } else if self
+            .tone_mapping
+            .gamma
+            .is_some_and(|g| !g.is_finite() || g <= 0.0)
+        {
+            invalid("gamma", &self.tone_mapping.gamma.unwrap_or_default())
+        } else if let Some(denoiser) = &self.denoise {
+            denoiser.validate()
+        } else {
+            Ok(())
+        }
+    }
+}
+
 pub struct Scene {
     pub world: HitableList,
+    /// emissive primitives of `world` sampled directly, see [`Hitable::collect_lights`]
+    pub lights: Vec<Light>,
+    /// materials numbered by [`Aov::MaterialId`], the ID of a material is its index plus 1
+    pub materials: Vec<Arc<Material>>,
     pub cam: Camera,
     pub background: Background,
     pub params: RenderParams,
@@ -42,76 +189,707 @@ impl Background {
     }
 }
 
-pub fn render(scene: Scene) {
+/// Rectangular part of the image, rendered as one unit of parallel work.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Split a `width` x `height` image into tiles of at most `size` x `size` pixels, row by row
+/// starting top left.
+pub fn tiles(width: u32, height: u32, size: u32) -> Vec<Tile> {
+    let mut tiles = vec![];
+    for y in (0..height).step_by(size as usize) {
+        for x in (0..width).step_by(size as usize) {
+            tiles.push(Tile {
+                x,
+                y,
+                width: size.min(width - x),
+                height: size.min(height - y),
+            });
+        }
+    }
+    tiles
+}
+
+/// Upper limit of the samples per pixel of a single pass, so that long renders still show
+/// (and checkpoint) progress regularly.
+const MAX_PASS_SAMPLES: usize = 64;
+
+/// Endless sequence of pass sample counts: each pass doubles the samples of the previous one
+/// up to `MAX_PASS_SAMPLES`.
+fn pass_sizes() -> impl Iterator<Item = usize> {
+    std::iter::successors(Some(1), |spp| Some((2 * spp).min(MAX_PASS_SAMPLES)))
+}
+
+/// Samples an adaptively sampled pixel takes at least, before its error is estimated.
+const MIN_ADAPTIVE_SAMPLES: u64 = 16;
+
+/// Upper limit of the samples of an adaptively sampled pixel, as a multiple of `ns`.
+const MAX_ADAPTIVE_FACTOR: usize = 16;
+
+/// Sample counts of the passes of a progressive render with `ns` samples in total, following
+/// [`pass_sizes`] with the last pass taking what is left.
+pub fn passes(ns: usize) -> Vec<usize> {
+    let mut done = 0;
+    pass_sizes()
+        .map_while(|spp| {
+            let n = spp.min(ns - done);
+            done += n;
+            if n > 0 {
+                Some(n)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Render the scene into a linear float framebuffer.
+pub fn render_framebuffer(scene: &Scene) -> Framebuffer {
+    render_framebuffer_with_progress(scene, || ())
+}
+
+/// Render the scene into a linear float framebuffer, calling `progress` after each tile.
+///
+/// The tiles are rendered in parallel, so `progress` may be called from any thread. The
+/// samples are taken in the same passes as by [`render_progressive`], so both give the
+/// same image.
+pub fn render_framebuffer_with_progress<F>(scene: &Scene, progress: F) -> Framebuffer
+where
+    F: Fn() + Sync,
+{
+    match render_progressive(scene, None, progress, |_, _| Ok(())) {
+        Ok(fb) => fb,
+        Err(_) => unreachable!("the snapshot callback never fails"),
+    }
+}
+
+/// Render the scene in passes of increasing sample counts (see [`passes`]), calling
+/// `snapshot` with the samples so far and the number of finished passes after each pass.
+///
+/// With a checkpoint given, the render continues after its last finished pass. With a
+/// `time_limit`, passes are added as long as the next one is expected to finish in time,
+/// judging by the duration of the previous pass; at least one pass is always rendered. The
+/// time spent before a checkpoint counts against the limit.
+pub fn render_progressive<F, S>(
+    scene: &Scene,
+    resume: Option<Checkpoint>,
+    progress: F,
+    snapshot: S,
+) -> Result<Framebuffer, RaytraceError>
+where
+    F: Fn() + Sync,
+    S: FnMut(&Accumulator, usize) -> Result<(), RaytraceError>,
+{
+    render_accumulator(scene, resume, progress, snapshot).map(|acc| acc.to_framebuffer())
+}
+
+/// Like [`render_progressive`], but return the accumulated samples instead of their mean.
+///
+/// With an `adaptive_threshold`, converged pixels (see [`Accumulator::relative_error`]) are
+/// left out of the following passes, which go on until `ns` samples per pixel are spent
+/// on the image as a whole or every pixel has converged.
+pub fn render_accumulator<F, S>(
+    scene: &Scene,
+    resume: Option<Checkpoint>,
+    progress: F,
+    mut snapshot: S,
+) -> Result<Accumulator, RaytraceError>
+where
+    F: Fn() + Sync,
+    S: FnMut(&Accumulator, usize) -> Result<(), RaytraceError>,
+{
+    let params = &scene.params;
+    let resumed = resume.is_some();
+    let (mut acc, passes_done, elapsed_before) = match resume {
+        Some(checkpoint) => (checkpoint.acc, checkpoint.passes_done, checkpoint.elapsed),
+        None => (
+            Accumulator::new(params.nx, params.ny),
+            0,
+            Duration::default(),
+        ),
+    };
+    let adaptive = params.adaptive_threshold > 0.0;
+    let schedule: Box<dyn Iterator<Item = usize>> = if params.time_limit > 0 || adaptive {
+        Box::new(pass_sizes())
+    } else {
+        Box::new(passes(params.ns).into_iter())
+    };
+
     let begin_time = Instant::now();
-    // output
-    let mut data = ImageBuffer::new(scene.params.nx as u32, scene.params.ny as u32);
+    let budget = params.ns as u64 * u64::from(params.nx) * u64::from(params.ny);
+    // seconds per sample in the previous pass
+    let mut sample_time = 0.0;
+    for (pass, spp) in schedule.enumerate().skip(passes_done) {
+        let active: Vec<bool> = if adaptive {
+            (0..params.ny)
+                .flat_map(|y| (0..params.nx).map(move |x| (x, y)))
+                .map(|(x, y)| !converged(params, &acc, x, y))
+                .collect()
+        } else {
+            vec![true; acc.samples.len()]
+        };
+        let num_active = active.iter().filter(|&&a| a).count() as u64;
+        if num_active == 0 {
+            break;
+        }
+        let spp = if adaptive && params.time_limit == 0 {
+            let spent = acc.samples.iter().sum::<u64>();
+            match spp.min((budget.saturating_sub(spent) / num_active) as usize) {
+                0 => break,
+                spp => spp,
+            }
+        } else {
+            spp
+        };
+        let samples = num_active * spp as u64;
+        let elapsed = (elapsed_before + begin_time.elapsed()).as_secs_f64();
+        let time_limit = params.time_limit as f64;
+        // a new render always takes its first pass, whose duration is not known yet, a
+        // resumed one only if there is time left
+        let over_time = if pass > passes_done {
+            elapsed + sample_time * samples as f64 > time_limit
+        } else {
+            resumed && elapsed >= time_limit
+        };
+        if params.time_limit > 0 && over_time {
+            break;
+        }
+
+        let pass_begin = Instant::now();
+        render_pass(scene, pass, spp, &active, &mut acc, &progress);
+        sample_time = pass_begin.elapsed().as_secs_f64() / samples.max(1) as f64;
+        snapshot(&acc, pass + 1)?;
+    }
+    Ok(acc)
+}
+
+/// The requested AOVs, plus the guides of the denoiser.
+fn aovs_to_render(params: &RenderParams) -> Vec<Aov> {
+    let mut aovs = params.aovs.clone();
+    if params.denoise.is_some() {
+        for aov in &[Aov::Albedo, Aov::Normal, Aov::Depth] {
+            if !aovs.contains(aov) {
+                aovs.push(*aov);
+            }
+        }
+    }
+    aovs
+}
 
-    // RNG for anti-aliasing (average sampling)
-    let mut rng = rand::thread_rng();
+/// Whether an adaptively sampled pixel needs no more samples.
+fn converged(params: &RenderParams, acc: &Accumulator, x: u32, y: u32) -> bool {
+    let n = acc.samples[(y * acc.width + x) as usize];
+    n >= MIN_ADAPTIVE_SAMPLES
+        && (acc.relative_error(x, y) <= params.adaptive_threshold
+            || (params.time_limit == 0 && n as usize >= MAX_ADAPTIVE_FACTOR * params.ns))
+}
 
-    // let samples_per_thread = params.ns / params.nt;
+/// Take `spp` samples of every pixel marked in `active` and add them to `acc`.
+fn render_pass<F>(
+    scene: &Scene,
+    pass: usize,
+    spp: usize,
+    active: &[bool],
+    acc: &mut Accumulator,
+    progress: &F,
+) where
+    F: Fn() + Sync,
+{
+    let rendered: Vec<_> = tiles(acc.width, acc.height, scene.params.tile_size)
+        .into_par_iter()
+        .enumerate()
+        .map(|(index, tile)| {
+            // every tile has its own RNG seeded from the scene seed, the pass and the tile
+            // index, so the result does not depend on the order in which the threads pick
+            // up the tiles
+            let stream = (pass as u64) << 32 | index as u64;
+            let mut rng = StdRng::seed_from_u64(
+                scene.params.seed ^ stream.wrapping_mul(0x9e37_79b9_7f4a_7c15),
+            );
+            let (pixels, splats) = render_tile(scene, &tile, spp, active, &mut rng);
+            progress();
+            (tile, pixels, splats)
+        })
+        .collect();
 
-    let mut pbr = pbr::ProgressBar::new(u64::from(data.width() * data.height()));
+    let (nx, ny) = (acc.width, acc.height);
+    if scene.params.integrator == Integrator::Bdpt {
+        let samples = active.iter().filter(|&&a| a).count() as u64 * spp as u64;
+        acc.light_paths += samples;
+    }
+    for (tile, pixels, splats) in rendered {
+        for ((s, t), c) in splats {
+            // image coordinates count rows from the bottom
+            let x = ((s * nx as f32) as u32).min(nx - 1);
+            let y = ny - 1 - ((t * ny as f32) as u32).min(ny - 1);
+            acc.splat(x, y, c);
+        }
+        for (k, (c, sum_sq)) in pixels.into_iter().enumerate() {
+            let k = k as u32;
+            let (x, y) = (tile.x + k % tile.width, tile.y + k / tile.width);
+            if active[(y * acc.width + x) as usize] {
+                acc.add(x, y, c, sum_sq, spp as u64);
+            }
+        }
+    }
+}
+
+/// Light reaching the camera from a light subpath, with the image coordinates it is seen at.
+pub(crate) type Splat = ((f32, f32), Vec3);
+
+/// Render the pixels of a tile row by row, returning the sum of `spp` samples and of their
+/// squared luminance per pixel, as well as the light the bidirectional integrator carried
+/// to anywhere in the image. Pixels not marked in `active` are skipped and left zero.
+fn render_tile(
+    scene: &Scene,
+    tile: &Tile,
+    spp: usize,
+    active: &[bool],
+    rng: &mut dyn RngCore,
+) -> (Vec<(Vec3, f32)>, Vec<Splat>) {
+    let mut pixels = Vec::with_capacity((tile.width * tile.height) as usize);
+    let mut splats = vec![];
+    let mut paths = bdpt::Paths::default();
+    for y in tile.y..tile.y + tile.height {
+        for i in tile.x..tile.x + tile.width {
+            if !active[(y * scene.params.nx + i) as usize] {
+                pixels.push((Vec3::default(), 0.0));
+                continue;
+            }
+            // invert y coordinate
+            let j = scene.params.ny - y - 1;
+
+            let mut sum = Vec3::default();
+            let mut sum_sq = 0.0;
+            for _ in 0..spp {
+                let u = (i as f32 + rng.gen::<f32>()) / scene.params.nx as f32;
+                let v = (j as f32 + rng.gen::<f32>()) / scene.params.ny as f32;
+                let r = scene.cam.get_ray(u, v, rng);
+                let c = match scene.params.integrator {
+                    Integrator::Path => color(r, scene, rng),
+                    Integrator::Bdpt => bdpt::color(r, scene, &mut paths, &mut splats, rng),
+                };
+                sum += c;
+                sum_sq += luminance(c) * luminance(c);
+            }
+            pixels.push((sum, sum_sq));
+        }
+    }
+    (pixels, splats)
+}
+
+/// Render the scene with a progress bar and save the result to `scene.params.filename`.
+///
+/// A progressive render also rewrites the image after every pass that finishes at least
+/// `snapshot_interval` seconds after the last write. With a `checkpoint_interval`, the
+/// render state is saved to [`checkpoint::path_for`] the image file in the same way, and
+/// removed once the image is done. With a `time_limit`, the progress bar shows the time used
+/// up instead of the finished tiles, an adaptive render shows the share of the sample budget
+/// spent. With a `sample_map` file name, the samples taken per pixel are saved there as well,
+/// and the `aovs` are rendered once the image is done. With `denoise`, the saved image is
+/// denoised with the help of albedo, normal and depth AOVs rendered for that purpose.
+pub fn render(scene: Scene) -> Result<(), RaytraceError> {
+    render_from(scene, None)
+}
+
+/// Continue the render saved in `checkpoint`, see [`render`].
+pub fn resume(scene: Scene, checkpoint: Checkpoint) -> Result<(), RaytraceError> {
+    render_from(scene, Some(checkpoint))
+}
+
+fn render_from(scene: Scene, resume: Option<Checkpoint>) -> Result<(), RaytraceError> {
+    let begin_time = Instant::now();
+    let elapsed_before = resume.as_ref().map_or(Duration::default(), |c| c.elapsed);
+    let elapsed = || elapsed_before + begin_time.elapsed();
+
+    let num_passes = passes(scene.params.ns).len();
+    let time_limit = scene.params.time_limit;
+    let adaptive = scene.params.adaptive_threshold > 0.0;
+    let budget = scene.params.ns as u64 * u64::from(scene.params.nx) * u64::from(scene.params.ny);
+    let mut pbr = if time_limit > 0 {
+        pbr::ProgressBar::new(time_limit)
+    } else if adaptive {
+        pbr::ProgressBar::new(budget)
+    } else {
+        // only the fixed schedule of `passes` has a known number of passes left
+        let passes_left = num_passes.saturating_sub(resume.as_ref().map_or(0, |c| c.passes_done));
+        let num_tiles = tiles(scene.params.nx, scene.params.ny, scene.params.tile_size).len();
+        pbr::ProgressBar::new((num_tiles * passes_left) as u64)
+    };
     pbr.show_percent = true;
     pbr.show_time_left = true;
     pbr.show_counter = false;
     pbr.set_max_refresh_rate(Some(std::time::Duration::from_millis(500)));
+    let pbr = Mutex::new(pbr);
+    let progress = || {
+        let mut pbr = pbr.lock().unwrap();
+        if time_limit > 0 {
+            pbr.set(elapsed().as_secs().min(time_limit));
+        } else if !adaptive {
+            pbr.inc();
+        }
+    };
 
-    for (i, j, pixel) in data.enumerate_pixels_mut() {
-        // invert y coordinate
-        let j = scene.params.ny - j - 1;
-
-        let work: Vec<(f32, f32)> = (0..scene.params.ns)
-            .map(|_| {
-                (
-                    (i as f32 + rng.gen::<f32>()) / scene.params.nx as f32,
-                    (j as f32 + rng.gen::<f32>()) / scene.params.ny as f32,
-                )
-            })
-            .collect();
-
-        let c = work
-            .par_iter()
-            .map(|(u, v)| {
-                let r = scene.cam.get_ray(*u, *v);
-                color(r, &scene.world, &scene.background, 0)
-            })
-            .sum::<Vec3>()
-            / scene.params.ns as f32;
+    let checkpoint_path = checkpoint::path_for(&scene.params.filename);
+    let snapshot_interval = Duration::from_secs(scene.params.snapshot_interval);
+    let checkpoint_interval = Duration::from_secs(scene.params.checkpoint_interval);
+    let mut last_snapshot = Instant::now();
+    let mut last_checkpoint = Instant::now();
+    let acc = render_accumulator(&scene, resume, progress, |acc, passes_done| {
+        if adaptive && time_limit == 0 {
+            let spent = acc.samples.iter().sum::<u64>();
+            pbr.lock().unwrap().set(spent.min(budget));
+        }
+        // the last pass is saved below anyway; time-limited and adaptive renders do not
+        // know which one it is
+        if time_limit == 0 && !adaptive && passes_done == num_passes {
+            return Ok(());
+        }
+        if scene.params.progressive && last_snapshot.elapsed() >= snapshot_interval {
+            output::save(
+                &acc.to_framebuffer(),
+                &scene.params.filename,
+                &scene.params.tone_mapping,
+            )?;
+            last_snapshot = Instant::now();
+        }
+        if scene.params.checkpoint_interval > 0 && last_checkpoint.elapsed() >= checkpoint_interval
+        {
+            let checkpoint = Checkpoint {
+                params: scene.params.clone(),
+                passes_done,
+                elapsed: elapsed(),
+                acc: acc.clone(),
+            };
+            checkpoint.save(&checkpoint_path)?;
+            last_checkpoint = Instant::now();
+        }
+        Ok(())
+    })?;
+    let mut pbr = pbr.into_inner().unwrap();
 
-        *pixel = image::Rgb(convert_rgb_u8(&c, 2.0));
-        pbr.inc();
+    let mut fb = acc.to_framebuffer();
+    let mut aovs = aov::render_aovs(&scene, &aovs_to_render(&scene.params));
+    if let Some(denoiser) = &scene.params.denoise {
+        fb = denoiser.denoise(&fb, &aovs);
+        aovs.retain(|(aov, _)| scene.params.aovs.contains(aov));
+    }
+    output::save_with_aovs(
+        &fb,
+        &aovs,
+        &scene.params.filename,
+        &scene.params.tone_mapping,
+    )?;
+    if let Some(filename) = &scene.params.sample_map {
+        acc.to_sample_map()
+            .save(filename)
+            .map_err(|source| RaytraceError::Image {
+                path: PathBuf::from(filename),
+                source,
+            })?;
+    }
+    if checkpoint_path.exists() {
+        std::fs::remove_file(&checkpoint_path).map_err(|source| RaytraceError::Io {
+            path: checkpoint_path.clone(),
+            source,
+        })?;
     }
-
-    data.save(&scene.params.filename).unwrap();
     pbr.finish_println(&format!(
         "Done in {}\n",
         humantime::format_duration(Duration::from_secs(begin_time.elapsed().as_secs()))
     ));
+    Ok(())
 }
 
-fn color(r: Ray, world: &HitableList, background: &Background, depth: u32) -> Vec3 {
-    if let Some(rec) = world.hit(&r, 0.001, std::f32::MAX) {
-        if depth < 64 {
-            let emitted = rec.material.emitted(rec.u, rec.v, &rec.p);
-            if let Some(s) = rec.material.scatter(&r, &rec) {
-                emitted + color(s.ray, world, background, depth + 1) * s.att
-            } else {
-                emitted
+/// BSDF sample a ray was scattered by, to weight the light it finds against light sampling.
+struct Bounce {
+    p: Vec3,
+    /// probability density of the scattered direction
+    pdf: f32,
+}
+
+/// Radiance arriving along `r`, traced as a path of up to `max_depth` vertices.
+///
+/// With light sampling, the direct light of the `scene.lights` is sampled at every rough
+/// vertex as well as found by the scattered ray, and the two estimates are combined by
+/// multiple importance sampling with the power heuristic: the emission of a light hit after
+/// a BSDF sampled bounce is weighted by how likely light sampling would have picked the same
+/// direction.
+fn color(r: Ray, scene: &Scene, rng: &mut dyn RngCore) -> Vec3 {
+    let mut r = r;
+    let mut radiance = Vec3::default();
+    // product of the sample weights of the bounces so far
+    let mut throughput = Vec3::new(1.0, 1.0, 1.0);
+    let mut bounce: Option<Bounce> = None;
+    for depth in 0..scene.params.max_depth {
+        let rec = match scene.world.hit(&r, 0.001, f32::MAX, rng) {
+            Some(rec) => rec,
+            None => {
+                radiance += throughput * scene.background.color(&r);
+                break;
+            }
+        };
+
+        let mut emitted = rec.material.emitted(rec.u, rec.v, &rec.p);
+        if let Some(bounce) = bounce {
+            if let Some(light) = rec.light.map(|i| &scene.lights[i]) {
+                let light_pdf = light.pdf(bounce.p, rec.p) / scene.lights.len() as f32;
+                emitted *= power_heuristic(bounce.pdf, light_pdf);
+            }
+        }
+        let sample =
+            scene.params.light_sampling && !scene.lights.is_empty() && rec.material.is_rough();
+        if sample {
+            emitted += sample_lights(&r, &rec, scene, rng);
+        }
+        radiance += throughput * emitted;
+
+        let s = match rec.material.scatter(&r, &rec, rng) {
+            Some(s) => s,
+            None => break,
+        };
+        throughput *= s.att;
+        if depth + 1 >= scene.params.roulette_depth {
+            // continue with a probability following the throughput, dim paths end early
+            let p = throughput
+                .x()
+                .max(throughput.y())
+                .max(throughput.z())
+                .min(1.0);
+            if rng.gen::<f32>() >= p {
+                break;
+            }
+            throughput /= p;
+        }
+        bounce = match s.pdf {
+            Some(pdf) if sample => Some(Bounce { p: rec.p, pdf }),
+            _ => None,
+        };
+        r = Ray::new(rec.p, s.direction);
+    }
+    radiance
+}
+
+/// Direct light reaching the hit point `rec` from one randomly chosen light of the scene
+/// (which must have some) and scattered back along `r`, weighted against finding the light
+/// by scattering.
+fn sample_lights(r: &Ray, rec: &HitRecord, scene: &Scene, rng: &mut dyn RngCore) -> Vec3 {
+    let i = rng.gen_range(0, scene.lights.len());
+    let light = &scene.lights[i];
+    if let Some((wi, pdf)) = light.sample(rec.p, rng) {
+        let shadow = Ray::new(rec.p, wi);
+        if let Some(l) = scene.world.hit(&shadow, 0.001, f32::MAX, rng) {
+            if l.light == Some(i) {
+                let light_pdf = pdf / scene.lights.len() as f32;
+                let wo = -r.direction.normalize();
+                let weight = power_heuristic(light_pdf, rec.material.pdf(rec, wi, wo));
+                let f = rec.material.eval(rec, wi, wo);
+                return f * l.material.emitted(l.u, l.v, &l.p) * (weight / light_pdf);
             }
-        } else {
-            Vec3::default()
         }
+    }
+    Vec3::default()
+}
+
+/// Weight of a sample taken with density `pdf` when `other_pdf` is the density of the other
+/// strategy for the same direction.
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    if pdf > 0.0 {
+        // as ratio, the squares of large densities would overflow
+        let ratio = other_pdf / pdf;
+        1.0 / (1.0 + ratio * ratio)
     } else {
-        background.color(&r)
+        0.0
     }
 }
 
-fn convert_rgb_u8(v: &Vec3, gamma: f32) -> [u8; 3] {
-    let mut rgb = [0; 3];
-    for i in 0..3 {
-        rgb[i] = (255.99 * v.i(i).powf(1.0 / gamma)).min(255.0) as u8;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Material;
+    use crate::rect::{Axes, Rect};
+    use crate::texture::Texture;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_tiles_cover_image() {
+        let tiles = tiles(70, 33, 32);
+        assert_eq!(tiles.len(), 6);
+        assert_eq!(
+            tiles[5],
+            Tile {
+                x: 64,
+                y: 32,
+                width: 6,
+                height: 1
+            }
+        );
+
+        let mut covered = vec![0; 70 * 33];
+        for tile in &tiles {
+            for y in tile.y..tile.y + tile.height {
+                for x in tile.x..tile.x + tile.width {
+                    covered[(y * 70 + x) as usize] += 1;
+                }
+            }
+        }
+        assert!(covered.iter().all(|&c| c == 1));
+    }
+
+    #[test]
+    fn test_validate_denoiser() {
+        let mut params = RenderParams {
+            denoise: Some(Denoiser::default()),
+            ..RenderParams::default()
+        };
+        assert!(params.validate().is_ok());
+        params.denoise = Some(Denoiser {
+            sigma_normal: 0.0,
+            ..Denoiser::default()
+        });
+        assert!(params.validate().is_err());
+    }
+
+    #[test]
+    fn test_passes() {
+        assert_eq!(passes(1), vec![1]);
+        assert_eq!(passes(16), vec![1, 2, 4, 8, 1]);
+        assert_eq!(passes(15), vec![1, 2, 4, 8]);
+        assert_eq!(passes(300), vec![1, 2, 4, 8, 16, 32, 64, 64, 64, 45]);
+    }
+
+    fn constant(l: f32) -> Texture {
+        Texture::Constant {
+            color: Vec3::new(l, l, l),
+        }
+    }
+
+    /// A floor lit by a light half a unit wide one unit above it, optionally below a ceiling.
+    fn lit_floor(floor: Material, ceiling: Option<Material>) -> Scene {
+        let rect = |y: f32, size: f32, flip_normal: bool, material: Material| {
+            Box::new(Rect::new(
+                Axes::XZ {
+                    x: (-size, size),
+                    y,
+                    z: (-size, size),
+                },
+                flip_normal,
+                Arc::new(material),
+            )) as Box<dyn Hitable>
+        };
+        let mut list = vec![
+            rect(0.0, 5.0, false, floor),
+            rect(
+                1.0,
+                0.25,
+                true,
+                Material::DiffuseLight {
+                    emit: constant(4.0),
+                },
+            ),
+        ];
+        if let Some(ceiling) = ceiling {
+            list.push(rect(1.5, 5.0, true, ceiling));
+        }
+        let mut world = HitableList { list };
+        let mut lights = vec![];
+        world.collect_lights(&mut lights);
+        assert_eq!(lights.len(), 1);
+        Scene {
+            world,
+            lights,
+            materials: vec![],
+            cam: Camera::new(
+                Vec3::new(0.0, 1.0, 0.0),
+                Vec3::default(),
+                Vec3::new(0.0, 0.0, 1.0),
+                40.0,
+                1.0,
+                0.0,
+                1.0,
+            ),
+            background: Background::Color(Vec3::default()),
+            params: RenderParams::default(),
+        }
+    }
+
+    /// Mean red radiance of `n` paths along a ray hitting the floor next to the light.
+    fn mean_color(scene: &Scene, n: usize, rng: &mut dyn RngCore) -> f32 {
+        (0..n)
+            .map(|_| {
+                let r = Ray::new(Vec3::new(1.2, 1.2, 0.0), Vec3::new(-0.6, -1.2, 0.0));
+                color(r, scene, rng).x()
+            })
+            .sum::<f32>()
+            / n as f32
+    }
+
+    #[test]
+    fn test_light_sampling() {
+        // the same mean with and without light sampling, on a diffuse and a glossy floor
+        let floors = vec![
+            Material::Diffuse {
+                albedo: constant(0.5),
+            },
+            Material::Metal {
+                albedo: Vec3::new(0.8, 0.8, 0.8),
+                fuzz: 0.3,
+            },
+        ];
+        for floor in floors {
+            let mut scene = lit_floor(floor, None);
+            let mut rng = StdRng::seed_from_u64(1);
+            let sampled = mean_color(&scene, 40000, &mut rng);
+            scene.params.light_sampling = false;
+            let scattered = mean_color(&scene, 40000, &mut rng);
+            assert!(sampled > 0.0);
+            assert!(
+                (sampled - scattered).abs() < 0.03 * scattered,
+                "{} {}",
+                sampled,
+                scattered
+            );
+        }
+    }
+
+    #[test]
+    fn test_russian_roulette() {
+        // light bouncing between floor and ceiling: ending paths early keeps the mean
+        let diffuse = || Material::Diffuse {
+            albedo: constant(0.8),
+        };
+        let mut scene = lit_floor(diffuse(), Some(diffuse()));
+        let mut rng = StdRng::seed_from_u64(1);
+        scene.params.roulette_depth = 1;
+        let roulette = mean_color(&scene, 40000, &mut rng);
+        scene.params.roulette_depth = scene.params.max_depth;
+        let full = mean_color(&scene, 40000, &mut rng);
+        assert!(
+            (roulette - full).abs() < 0.03 * full,
+            "{} {}",
+            roulette,
+            full
+        );
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let mut scene = lit_floor(
+            Material::Diffuse {
+                albedo: constant(0.5),
+            },
+            None,
+        );
+        let mut rng = StdRng::seed_from_u64(1);
+        scene.params.max_depth = 1;
+        // the light is only reached by a second bounce, or by light sampling
+        assert!(mean_color(&scene, 100, &mut rng) > 0.0);
+        scene.params.light_sampling = false;
+        assert_eq!(mean_color(&scene, 100, &mut rng), 0.0);
     }
-    rgb
 }
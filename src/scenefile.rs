@@ -0,0 +1,561 @@
+use crate::camera::Camera;
+use crate::error::RaytraceError;
+use crate::gltf;
+use crate::hitable::{Hitable, HitableList};
+use crate::material::Material;
+use crate::obj;
+use crate::plane::Plane;
+use crate::ply;
+use crate::rect::{Axes, Cuboid, Rect};
+use crate::render::{Background, RenderParams, Scene};
+use crate::sphere::Sphere;
+use crate::texture::{Perlin, Texture};
+use crate::transform::{RotateXYZ, Translate};
+use crate::triangle::Triangle;
+use crate::vec3::Vec3;
+use crate::volume::ConstantMedium;
+
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Declarative description of a scene, as read from a RON scene file.
+#[derive(Serialize, Deserialize)]
+pub struct SceneFile {
+    pub camera: CameraDesc,
+    pub background: Background,
+    #[serde(default)]
+    pub params: RenderParams,
+    /// named materials in file order, which is also the order of their IDs
+    #[serde(default, with = "ordered_map")]
+    pub materials: Vec<(String, MaterialDesc)>,
+    pub world: Vec<ObjectDesc>,
+    /// directory of the scene file, relative resource paths are resolved against it
+    #[serde(skip)]
+    pub base_dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CameraDesc {
+    pub look_from: Vec3,
+    pub look_at: Vec3,
+    #[serde(default = "default_vup")]
+    pub vup: Vec3,
+    pub vfov: f32,
+    #[serde(default)]
+    pub aperture: f32,
+    pub focus_dist: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum ObjectDesc {
+    Sphere {
+        center: Vec3,
+        radius: f32,
+        material: MaterialDesc,
+    },
+    Rect {
+        a: Axes,
+        #[serde(default)]
+        flip_normal: bool,
+        material: MaterialDesc,
+    },
+    /// infinite plane, kept outside of the BVH
+    Plane {
+        point: Vec3,
+        normal: Vec3,
+        material: MaterialDesc,
+    },
+    Cuboid {
+        p_min: Vec3,
+        p_max: Vec3,
+        material: MaterialDesc,
+    },
+    Translate {
+        offset: Vec3,
+        object: Box<ObjectDesc>,
+    },
+    RotateXYZ {
+        angles: Vec3,
+        object: Box<ObjectDesc>,
+    },
+    ConstantMedium {
+        boundary: Box<ObjectDesc>,
+        density: f32,
+        albedo: TextureDesc,
+    },
+    Triangle {
+        v: [Vec3; 3],
+        material: MaterialDesc,
+    },
+    /// Wavefront OBJ file, using its MTL materials unless `material` is given
+    Obj {
+        path: PathBuf,
+        #[serde(default)]
+        material: Option<MaterialDesc>,
+    },
+    /// PLY file, using its vertex colors unless `material` is given
+    Ply {
+        path: PathBuf,
+        #[serde(default)]
+        material: Option<MaterialDesc>,
+    },
+    /// meshes of a glTF 2.0 file (.gltf or .glb) with their node transforms and materials
+    Gltf { path: PathBuf },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum MaterialDesc {
+    Diffuse {
+        albedo: TextureDesc,
+    },
+    Metal {
+        albedo: Vec3,
+        fuzz: f32,
+    },
+    Dielectric {
+        ref_index: f32,
+    },
+    DiffuseLight {
+        emit: TextureDesc,
+    },
+    Isotropic {
+        albedo: TextureDesc,
+    },
+    Microfacet {
+        albedo: TextureDesc,
+        metallic: f32,
+        roughness: f32,
+    },
+    /// reference to an entry of the scene file's `materials` table
+    Named(String),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum TextureDesc {
+    Constant {
+        color: Vec3,
+    },
+    Checker {
+        odd: Box<TextureDesc>,
+        even: Box<TextureDesc>,
+    },
+    PerlinNoise {
+        scale: f32,
+    },
+    Image {
+        path: PathBuf,
+    },
+}
+
+fn default_vup() -> Vec3 {
+    Vec3::new(0.0, 1.0, 0.0)
+}
+
+/// (De)serialize a list of name / value pairs as a map, keeping the order of the entries.
+mod ordered_map {
+    use serde::de::{Deserialize, Deserializer, Error, MapAccess, Visitor};
+    use serde::ser::{Serialize, SerializeMap, Serializer};
+    use std::fmt;
+    use std::marker::PhantomData;
+
+    pub fn serialize<S, V>(entries: &[(String, V)], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        V: Serialize,
+    {
+        let mut map = serializer.serialize_map(Some(entries.len()))?;
+        for (key, value) in entries {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+
+    pub fn deserialize<'de, D, V>(deserializer: D) -> Result<Vec<(String, V)>, D::Error>
+    where
+        D: Deserializer<'de>,
+        V: Deserialize<'de>,
+    {
+        struct EntriesVisitor<V>(PhantomData<V>);
+
+        impl<'de, V: Deserialize<'de>> Visitor<'de> for EntriesVisitor<V> {
+            type Value = Vec<(String, V)>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a map")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+                let mut entries: Vec<(String, V)> = vec![];
+                while let Some((key, value)) = access.next_entry::<String, V>()? {
+                    if entries.iter().any(|(k, _)| *k == key) {
+                        return Err(A::Error::custom(format!("duplicate entry: {}", key)));
+                    }
+                    entries.push((key, value));
+                }
+                Ok(entries)
+            }
+        }
+
+        deserializer.deserialize_map(EntriesVisitor(PhantomData))
+    }
+}
+
+/// Load a RON scene file, or a glTF file (by its .gltf / .glb extension) together with the
+/// first camera it contains.
+pub fn load(path: &Path) -> Result<SceneFile, RaytraceError> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_lowercase);
+    if let Some("gltf") | Some("glb") = extension.as_deref() {
+        return load_gltf(path);
+    }
+
+    let text = std::fs::read_to_string(path).map_err(|source| RaytraceError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let mut scene_file: SceneFile =
+        ron::de::from_str(&text).map_err(|source| RaytraceError::SceneParse {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    scene_file.base_dir = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+    Ok(scene_file)
+}
+
+fn load_gltf(path: &Path) -> Result<SceneFile, RaytraceError> {
+    let camera = gltf::load_camera(path)?.ok_or_else(|| RaytraceError::NoCamera {
+        path: path.to_path_buf(),
+    })?;
+    Ok(SceneFile {
+        camera,
+        background: Background::BlendY(Vec3::new(1.0, 1.0, 1.0), Vec3::new(0.5, 0.7, 1.0)),
+        params: RenderParams::default(),
+        materials: vec![],
+        world: vec![ObjectDesc::Gltf {
+            path: path.file_name().map(PathBuf::from).unwrap_or_default(),
+        }],
+        base_dir: path.parent().unwrap_or_else(|| Path::new("")).to_path_buf(),
+    })
+}
+
+/// Materials built for a scene: all of them in the order they were built, and the entries
+/// of the `materials` table by name.
+#[derive(Default)]
+struct Materials {
+    list: Vec<Arc<Material>>,
+    named: HashMap<String, Arc<Material>>,
+}
+
+impl SceneFile {
+    pub fn into_scene(self) -> Result<Scene, RaytraceError> {
+        self.params.validate()?;
+
+        let cam = Camera::new(
+            self.camera.look_from,
+            self.camera.look_at,
+            self.camera.vup,
+            self.camera.vfov,
+            self.params.nx as f32 / self.params.ny as f32,
+            self.camera.aperture,
+            self.camera.focus_dist,
+        );
+
+        // scene construction (e.g. Perlin noise tables) draws from its own RNG
+        let mut rng = StdRng::seed_from_u64(self.params.seed);
+        let mut materials = self.build_named_materials(&mut rng)?;
+        let world = HitableList {
+            list: self
+                .world
+                .iter()
+                .map(|o| self.build_object(o, &mut materials, &mut rng))
+                .collect::<Result<_, _>>()?,
+        };
+
+        let mut world = world.into_bvh_with(self.params.bvh)?;
+        let mut lights = vec![];
+        world.collect_lights(&mut lights);
+        Ok(Scene {
+            world,
+            lights,
+            materials: materials.list,
+            cam,
+            background: self.background,
+            params: self.params,
+        })
+    }
+
+    /// Build every entry of the `materials` table once in file order, the objects referring
+    /// to it share the result.
+    fn build_named_materials(&self, rng: &mut dyn RngCore) -> Result<Materials, RaytraceError> {
+        let mut materials = Materials::default();
+        for (name, desc) in &self.materials {
+            let material = match desc {
+                MaterialDesc::Named(_) => return Err(RaytraceError::NestedMaterial(name.clone())),
+                desc => self.build_material(desc, &mut materials, rng)?,
+            };
+            materials.named.insert(name.clone(), material);
+        }
+        Ok(materials)
+    }
+
+    fn build_object(
+        &self,
+        desc: &ObjectDesc,
+        materials: &mut Materials,
+        rng: &mut dyn RngCore,
+    ) -> Result<Box<dyn Hitable>, RaytraceError> {
+        Ok(match desc {
+            ObjectDesc::Sphere {
+                center,
+                radius,
+                material,
+            } => Box::new(Sphere::new(
+                *center,
+                *radius,
+                self.build_material(material, materials, rng)?,
+            )),
+            ObjectDesc::Rect {
+                a,
+                flip_normal,
+                material,
+            } => Box::new(Rect::new(
+                a.clone(),
+                *flip_normal,
+                self.build_material(material, materials, rng)?,
+            )),
+            ObjectDesc::Plane {
+                point,
+                normal,
+                material,
+            } => Box::new(Plane {
+                point: *point,
+                normal: *normal,
+                material: self.build_material(material, materials, rng)?,
+            }),
+            ObjectDesc::Cuboid {
+                p_min,
+                p_max,
+                material,
+            } => Box::new(Cuboid::new(
+                *p_min,
+                *p_max,
+                self.build_material(material, materials, rng)?,
+            )),
+            ObjectDesc::Translate { offset, object } => Box::new(Translate::new(
+                self.build_object(object, materials, rng)?,
+                *offset,
+            )),
+            ObjectDesc::RotateXYZ { angles, object } => Box::new(RotateXYZ::new(
+                self.build_object(object, materials, rng)?,
+                *angles,
+            )),
+            ObjectDesc::ConstantMedium {
+                boundary,
+                density,
+                albedo,
+            } => Box::new(ConstantMedium::new(
+                self.build_object(boundary, materials, rng)?,
+                *density,
+                self.build_texture(albedo, rng)?,
+            )),
+            ObjectDesc::Triangle { v, material } => Box::new(Triangle {
+                v: *v,
+                material: self.build_material(material, materials, rng)?,
+            }),
+            ObjectDesc::Obj { path, material } => {
+                let material = match material {
+                    Some(material) => Some(self.build_material(material, materials, rng)?),
+                    None => None,
+                };
+                Box::new(obj::load(&self.base_dir.join(path), material)?)
+            }
+            ObjectDesc::Ply { path, material } => {
+                let material = match material {
+                    Some(material) => Some(self.build_material(material, materials, rng)?),
+                    None => None,
+                };
+                Box::new(ply::load(&self.base_dir.join(path), material)?)
+            }
+            ObjectDesc::Gltf { path } => Box::new(gltf::load(&self.base_dir.join(path))?),
+        })
+    }
+
+    /// Build a material and number it after the ones built before, or look up a named one.
+    fn build_material(
+        &self,
+        desc: &MaterialDesc,
+        materials: &mut Materials,
+        rng: &mut dyn RngCore,
+    ) -> Result<Arc<Material>, RaytraceError> {
+        let material = Arc::new(match desc {
+            MaterialDesc::Diffuse { albedo } => Material::Diffuse {
+                albedo: self.build_texture(albedo, rng)?,
+            },
+            MaterialDesc::Metal { albedo, fuzz } => Material::Metal {
+                albedo: *albedo,
+                fuzz: *fuzz,
+            },
+            MaterialDesc::Dielectric { ref_index } => Material::Dielectric {
+                ref_index: *ref_index,
+            },
+            MaterialDesc::DiffuseLight { emit } => Material::DiffuseLight {
+                emit: self.build_texture(emit, rng)?,
+            },
+            MaterialDesc::Isotropic { albedo } => Material::Isotropic {
+                albedo: self.build_texture(albedo, rng)?,
+            },
+            MaterialDesc::Microfacet {
+                albedo,
+                metallic,
+                roughness,
+            } => Material::Microfacet {
+                albedo: self.build_texture(albedo, rng)?,
+                metallic: *metallic,
+                roughness: *roughness,
+            },
+            MaterialDesc::Named(name) => {
+                return materials
+                    .named
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| RaytraceError::UnknownMaterial(name.clone()))
+            }
+        });
+        materials.list.push(material.clone());
+        Ok(material)
+    }
+
+    fn build_texture(
+        &self,
+        desc: &TextureDesc,
+        rng: &mut dyn RngCore,
+    ) -> Result<Texture, RaytraceError> {
+        Ok(match desc {
+            TextureDesc::Constant { color } => Texture::Constant { color: *color },
+            TextureDesc::Checker { odd, even } => Texture::Checker {
+                odd: Box::new(self.build_texture(odd, rng)?),
+                even: Box::new(self.build_texture(even, rng)?),
+            },
+            TextureDesc::PerlinNoise { scale } => Texture::PerlinNoise {
+                perlin: Box::new(Perlin::new(rng)),
+                scale: *scale,
+            },
+            TextureDesc::Image { path } => {
+                let path = self.base_dir.join(path);
+                let img =
+                    image::open(&path).map_err(|source| RaytraceError::Image { path, source })?;
+                Texture::Image {
+                    image: Box::new(img.into_rgb()),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ray::Ray;
+
+    #[test]
+    fn test_parse_named_material() {
+        let text = r#"(
+            camera: (look_from: (0.0, 0.0, -1.0), look_at: (0.0, 0.0, 0.0), vfov: 40.0, focus_dist: 1.0),
+            background: Color((0.0, 0.0, 0.0)),
+            params: (nx: 4, ny: 2),
+            materials: {
+                "white": Diffuse(albedo: Constant(color: (0.73, 0.73, 0.73))),
+            },
+            world: [
+                Translate(
+                    offset: (1.0, 0.0, 0.0),
+                    object: Sphere(center: (0.0, 0.0, 0.0), radius: 1.0, material: Named("white")),
+                ),
+            ],
+        )"#;
+        let scene_file: SceneFile = ron::de::from_str(text).unwrap();
+        assert_eq!(scene_file.camera.vup, Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(scene_file.world.len(), 1);
+
+        let scene = scene_file.into_scene().unwrap();
+        assert_eq!(scene.params.nx, 4);
+        assert_eq!(scene.params.ns, 256);
+        assert_eq!(scene.params.filename, "image.png");
+    }
+
+    #[test]
+    fn test_named_material_is_shared() {
+        let text = r#"(
+            camera: (look_from: (0.0, 0.0, -1.0), look_at: (0.0, 0.0, 0.0), vfov: 40.0, focus_dist: 1.0),
+            background: Color((0.0, 0.0, 0.0)),
+            materials: {
+                "white": Diffuse(albedo: Constant(color: (0.73, 0.73, 0.73))),
+            },
+            world: [
+                Sphere(center: (-2.0, 0.0, 0.0), radius: 1.0, material: Named("white")),
+                Sphere(center: (2.0, 0.0, 0.0), radius: 1.0, material: Named("white")),
+            ],
+        )"#;
+        let scene_file: SceneFile = ron::de::from_str(text).unwrap();
+        let scene = scene_file.into_scene().unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut hit = |x: f32| {
+            let r = Ray::new(Vec3::new(x, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0));
+            scene
+                .world
+                .hit(&r, 0.001, f32::MAX, &mut rng)
+                .unwrap()
+                .material as *const Material
+        };
+        assert_eq!(hit(-2.0), hit(2.0));
+    }
+
+    #[test]
+    fn test_material_order() {
+        // "white" would sort after "light", the file order is kept nonetheless
+        let text = r#"(
+            camera: (look_from: (0.0, 0.0, -1.0), look_at: (0.0, 0.0, 0.0), vfov: 40.0, focus_dist: 1.0),
+            background: Color((0.0, 0.0, 0.0)),
+            materials: {
+                "white": Diffuse(albedo: Constant(color: (0.73, 0.73, 0.73))),
+                "light": DiffuseLight(emit: Constant(color: (4.0, 4.0, 4.0))),
+            },
+            world: [
+                Sphere(center: (0.0, 0.0, 0.0), radius: 1.0, material: Metal(albedo: (0.5, 0.5, 0.5), fuzz: 0.0)),
+                Sphere(center: (2.0, 0.0, 0.0), radius: 1.0, material: Named("light")),
+                Sphere(center: (4.0, 0.0, 0.0), radius: 1.0, material: Named("white")),
+            ],
+        )"#;
+        let scene_file: SceneFile = ron::de::from_str(text).unwrap();
+        let scene = scene_file.into_scene().unwrap();
+        assert_eq!(scene.materials.len(), 3);
+        assert!(matches!(*scene.materials[0], Material::Diffuse { .. }));
+        assert!(matches!(*scene.materials[1], Material::DiffuseLight { .. }));
+        assert!(matches!(*scene.materials[2], Material::Metal { .. }));
+
+        let duplicate = text.replace("\"light\": DiffuseLight", "\"white\": DiffuseLight");
+        assert!(ron::de::from_str::<SceneFile>(&duplicate).is_err());
+    }
+
+    #[test]
+    fn test_unknown_material() {
+        let text = r#"(
+            camera: (look_from: (0.0, 0.0, -1.0), look_at: (0.0, 0.0, 0.0), vfov: 40.0, focus_dist: 1.0),
+            background: Color((0.0, 0.0, 0.0)),
+            world: [
+                Sphere(center: (0.0, 0.0, 0.0), radius: 1.0, material: Named("white")),
+            ],
+        )"#;
+        let scene_file: SceneFile = ron::de::from_str(text).unwrap();
+        match scene_file.into_scene() {
+            Err(RaytraceError::UnknownMaterial(name)) => assert_eq!(name, "white"),
+            _ => panic!("expected an unknown material error"),
+        }
+    }
+}
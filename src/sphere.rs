@@ -1,18 +1,42 @@
 use crate::boundingbox::BoundingBox;
 use crate::hitable::{HitRecord, Hitable};
+use crate::light::{Light, LightShape};
 use crate::material::Material;
 use crate::ray::Ray;
 use crate::vec3::Vec3;
 
+use rand::RngCore;
+
+use std::sync::Arc;
+
 #[derive(Clone)]
 pub struct Sphere {
     pub center: Vec3,
     pub radius: f32,
-    pub material: Material,
+    pub material: Arc<Material>,
+    /// index of the sphere in the lights of the scene, see [`HitRecord::light`]
+    light: Option<usize>,
+}
+
+impl Sphere {
+    pub fn new(center: Vec3, radius: f32, material: Arc<Material>) -> Sphere {
+        Sphere {
+            center,
+            radius,
+            material,
+            light: None,
+        }
+    }
 }
 
 impl Hitable for Sphere {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+        _rng: &mut dyn RngCore,
+    ) -> Option<HitRecord<'_>> {
         let oc = r.origin - self.center;
         let a = Vec3::dot(r.direction, r.direction);
         let b = Vec3::dot(oc, r.direction);
@@ -32,12 +56,14 @@ impl Hitable for Sphere {
                     u,
                     v,
                     material: &self.material,
+                    color: Vec3::new(1.0, 1.0, 1.0),
+                    light: self.light,
                 })
             } else {
                 // second solution
                 let t = (-b + d.sqrt()) / a;
                 if t < t_max && t > t_min {
-                    let n = r.point(t) - self.center / self.radius;
+                    let n = (r.point(t) - self.center) / self.radius;
                     let (u, v) = sphere_uv(&n);
                     Some(HitRecord {
                         t,
@@ -46,6 +72,8 @@ impl Hitable for Sphere {
                         u,
                         v,
                         material: &self.material,
+                        color: Vec3::new(1.0, 1.0, 1.0),
+                        light: self.light,
                     })
                 } else {
                     None
@@ -62,9 +90,22 @@ impl Hitable for Sphere {
             max: self.center + Vec3::new(self.radius, self.radius, self.radius),
         })
     }
+
+    fn collect_lights(&mut self, lights: &mut Vec<Light>) {
+        if let Material::DiffuseLight { .. } = *self.material {
+            self.light = Some(lights.len());
+            lights.push(Light::new(
+                LightShape::Sphere {
+                    center: self.center,
+                    radius: self.radius,
+                },
+                &self.material,
+            ));
+        }
+    }
 }
 
-fn sphere_uv(p: &Vec3) -> (f32, f32) {
+pub(crate) fn sphere_uv(p: &Vec3) -> (f32, f32) {
     let phi = p.z().atan2(p.x());
     let theta = p.y().asin();
     let u = 1.0 - (phi + std::f32::consts::PI) / (2.0 * std::f32::consts::PI);
@@ -2,7 +2,7 @@ use crate::vec3::Vec3;
 
 use image::RgbImage;
 use rand::seq::SliceRandom;
-use rand::Rng;
+use rand::{Rng, RngCore};
 
 #[derive(Clone)]
 pub enum Texture {
@@ -20,6 +20,11 @@ pub enum Texture {
     Image {
         image: Box<RgbImage>,
     },
+    /// another texture multiplied by a constant color
+    Tinted {
+        color: Vec3,
+        texture: Box<Texture>,
+    },
 }
 
 impl Texture {
@@ -39,6 +44,7 @@ impl Texture {
                     * Vec3::new(1.0, 1.0, 1.0)
             }
             Texture::Image { image } => image_texture_value(image, u, v, p),
+            Texture::Tinted { color, texture } => *color * texture.value(u, v, p),
         }
     }
 }
@@ -52,12 +58,12 @@ pub struct Perlin {
 }
 
 impl Perlin {
-    pub fn new() -> Perlin {
+    pub fn new(rng: &mut dyn RngCore) -> Perlin {
         Perlin {
-            values: perlin_generate(),
-            perm_x: perlin_generate_perm(),
-            perm_y: perlin_generate_perm(),
-            perm_z: perlin_generate_perm(),
+            values: perlin_generate(rng),
+            perm_x: perlin_generate_perm(rng),
+            perm_y: perlin_generate_perm(rng),
+            perm_z: perlin_generate_perm(rng),
         }
     }
 
@@ -95,8 +101,7 @@ impl Perlin {
     }
 }
 
-fn perlin_generate() -> [Vec3; 256] {
-    let mut rng = rand::thread_rng();
+fn perlin_generate(rng: &mut dyn RngCore) -> [Vec3; 256] {
     let v = (0..256)
         .map(|_| {
             Vec3::new(
@@ -112,11 +117,10 @@ fn perlin_generate() -> [Vec3; 256] {
     p
 }
 
-fn perlin_generate_perm() -> [usize; 256] {
-    let mut rng = rand::thread_rng();
+fn perlin_generate_perm(rng: &mut dyn RngCore) -> [usize; 256] {
     let mut v: Vec<usize> = (0..256).collect();
-    v.shuffle(&mut rng);
-    let mut p = [0 as usize; 256];
+    v.shuffle(rng);
+    let mut p = [0_usize; 256];
     p.copy_from_slice(&v);
     p
 }
@@ -126,14 +130,14 @@ fn perlin_interpolate(c: [[[Vec3; 2]; 2]; 2], u: f32, v: f32, w: f32) -> f32 {
     let vv = v * v * (3. - 2. * v);
     let ww = w * w * (3. - 2. * w);
     let mut acc = 0.0;
-    for i in 0..2 {
-        for j in 0..2 {
-            for k in 0..2 {
+    for (i, ci) in c.iter().enumerate() {
+        for (j, cij) in ci.iter().enumerate() {
+            for (k, cijk) in cij.iter().enumerate() {
                 let weight_v = Vec3::new(u - i as f32, v - j as f32, w - k as f32);
                 acc += (i as f32 * uu + (1 - i) as f32 * (1.0 - uu))
                     * (j as f32 * vv + (1 - j) as f32 * (1.0 - vv))
                     * (k as f32 * ww + (1 - k) as f32 * (1.0 - ww))
-                    * Vec3::dot(c[i][j][k], weight_v);
+                    * Vec3::dot(*cijk, weight_v);
             }
         }
     }
@@ -0,0 +1,220 @@
+use crate::framebuffer::luminance;
+use crate::vec3::Vec3;
+
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Operator compressing the linear radiance into the displayable range 0 ... 1.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum ToneMap {
+    /// cut off everything above 1
+    #[default]
+    Clamp,
+    /// L / (1 + L) on the luminance, never reaching white
+    Reinhard,
+    /// Reinhard with the luminance `white_point` mapped to white
+    ExtendedReinhard,
+    /// filmic curve of the ACES reference rendering transform (fit by Krzysztof Narkowicz)
+    Aces,
+    /// filmic curve by John Hable (Uncharted 2), with `white_point` mapped to white
+    Hable,
+}
+
+impl FromStr for ToneMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ToneMap, String> {
+        match s {
+            "clamp" => Ok(ToneMap::Clamp),
+            "reinhard" => Ok(ToneMap::Reinhard),
+            "reinhard-extended" => Ok(ToneMap::ExtendedReinhard),
+            "aces" => Ok(ToneMap::Aces),
+            "hable" => Ok(ToneMap::Hable),
+            _ => Err(format!("unknown tone mapping operator: {}", s)),
+        }
+    }
+}
+
+/// How the linear radiance of a framebuffer is turned into 8 bit color values.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ToneMapping {
+    pub operator: ToneMap,
+    /// exposure correction in stops, the radiance is scaled by 2^exposure
+    pub exposure: f32,
+    /// radiance mapped to white by `ExtendedReinhard` and `Hable`
+    pub white_point: f32,
+    /// pure power law transfer curve with this gamma instead of the sRGB curve
+    pub gamma: Option<f32>,
+    /// add noise of one quantization step before rounding, against banding
+    pub dither: bool,
+}
+
+impl Default for ToneMapping {
+    fn default() -> ToneMapping {
+        ToneMapping {
+            operator: ToneMap::default(),
+            exposure: 0.0,
+            white_point: 11.2,
+            gamma: None,
+            dither: false,
+        }
+    }
+}
+
+impl ToneMapping {
+    /// Map the radiance `c` of pixel (x, y) to 8 bit values. The pixel position only seeds
+    /// the dithering noise, so that the same image always gives the same result.
+    pub fn map(&self, c: Vec3, x: u32, y: u32) -> [u8; 3] {
+        let c = self.operator(c * 2.0_f32.powf(self.exposure));
+        let mut rgb = [0; 3];
+        for (i, v) in rgb.iter_mut().enumerate() {
+            let encoded = self.encode(c.i(i).clamp(0.0, 1.0));
+            let noise = if self.dither {
+                // triangular distribution in -1 ... 1
+                hash(x, y, 2 * i as u32) + hash(x, y, 2 * i as u32 + 1) - 1.0
+            } else {
+                0.0
+            };
+            *v = (255.0 * encoded + noise).round().clamp(0.0, 255.0) as u8;
+        }
+        rgb
+    }
+
+    fn operator(&self, c: Vec3) -> Vec3 {
+        match self.operator {
+            ToneMap::Clamp => c,
+            ToneMap::Reinhard => scale_luminance(c, |l| l / (1.0 + l)),
+            ToneMap::ExtendedReinhard => {
+                let white_sq = self.white_point * self.white_point;
+                scale_luminance(c, |l| l * (1.0 + l / white_sq) / (1.0 + l))
+            }
+            ToneMap::Aces => map_channels(c, |x| {
+                (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+            }),
+            ToneMap::Hable => {
+                let white = hable(self.white_point);
+                map_channels(c, |x| hable(x) / white)
+            }
+        }
+    }
+
+    /// Apply the transfer curve to a linear value in 0 ... 1.
+    fn encode(&self, v: f32) -> f32 {
+        match self.gamma {
+            Some(gamma) => v.powf(1.0 / gamma),
+            None if v <= 0.003_130_8 => 12.92 * v,
+            None => 1.055 * v.powf(1.0 / 2.4) - 0.055,
+        }
+    }
+}
+
+fn map_channels<F: Fn(f32) -> f32>(c: Vec3, f: F) -> Vec3 {
+    Vec3::new(f(c.x()), f(c.y()), f(c.z()))
+}
+
+/// Scale the color so that its luminance becomes `f(luminance)`, keeping the hue.
+fn scale_luminance<F: Fn(f32) -> f32>(c: Vec3, f: F) -> Vec3 {
+    let l = luminance(c);
+    if l > 0.0 {
+        c * (f(l) / l)
+    } else {
+        c
+    }
+}
+
+fn hable(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f
+}
+
+/// Pseudo random number in 0 ... 1 derived from a pixel position and a channel.
+fn hash(x: u32, y: u32, channel: u32) -> f32 {
+    let mut h = x
+        .wrapping_mul(0x8da6_b343)
+        .wrapping_add(y.wrapping_mul(0xd816_3841))
+        .wrapping_add(channel.wrapping_mul(0xcb1a_b31f));
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x2c1b_3c6d);
+    h ^= h >> 12;
+    h = h.wrapping_mul(0x297a_2d39);
+    h ^= h >> 15;
+    (h >> 8) as f32 / (1 << 24) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operators() {
+        let gray = |l: f32| Vec3::new(l, l, l);
+        for operator in &[
+            ToneMap::Clamp,
+            ToneMap::Reinhard,
+            ToneMap::ExtendedReinhard,
+            ToneMap::Aces,
+            ToneMap::Hable,
+        ] {
+            let tm = ToneMapping {
+                operator: *operator,
+                ..Default::default()
+            };
+            assert_eq!(tm.map(gray(0.0), 0, 0), [0, 0, 0], "{:?}", operator);
+            // monotonic
+            let mut last = 0;
+            for k in 1..100 {
+                let v = tm.map(gray(k as f32 * 0.1), 0, 0)[0];
+                assert!(v >= last, "{:?}", operator);
+                last = v;
+            }
+        }
+
+        let tm = |operator| ToneMapping {
+            operator,
+            white_point: 4.0,
+            ..Default::default()
+        };
+        assert_eq!(tm(ToneMap::Clamp).map(gray(15.0), 0, 0), [255, 255, 255]);
+        assert!(tm(ToneMap::Reinhard).map(gray(15.0), 0, 0)[0] < 255);
+        assert_eq!(tm(ToneMap::ExtendedReinhard).map(gray(4.0), 0, 0)[0], 255);
+        assert_eq!(tm(ToneMap::Hable).map(gray(4.0), 0, 0)[0], 255);
+    }
+
+    #[test]
+    fn test_transfer_and_exposure() {
+        let gray = |l: f32| Vec3::new(l, l, l);
+        let srgb = ToneMapping::default();
+        assert_eq!(srgb.map(gray(0.5), 0, 0), [188, 188, 188]);
+        assert_eq!(srgb.map(gray(0.001), 0, 0), [3, 3, 3]);
+        let gamma = ToneMapping {
+            gamma: Some(2.0),
+            ..Default::default()
+        };
+        assert_eq!(gamma.map(gray(0.25), 0, 0), [128, 128, 128]);
+        let exposed = ToneMapping {
+            exposure: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(exposed.map(gray(0.25), 0, 0), srgb.map(gray(0.5), 0, 0));
+    }
+
+    #[test]
+    fn test_dither() {
+        let tm = ToneMapping {
+            dither: true,
+            ..Default::default()
+        };
+        // a value between two steps is rounded both ways, on average to the right value
+        let c = Vec3::new(0.2, 0.2, 0.2);
+        let exact = 255.0 * ToneMapping::default().encode(0.2);
+        let values: Vec<u8> = (0..64).map(|x| tm.map(c, x, 0)[0]).collect();
+        assert!(values.iter().all(|&v| (v as f32 - exact).abs() < 2.0));
+        let mean = values.iter().map(|&v| v as f32).sum::<f32>() / values.len() as f32;
+        assert!((mean - exact).abs() < 0.25);
+        assert_eq!(
+            values,
+            (0..64).map(|x| tm.map(c, x, 0)[0]).collect::<Vec<_>>()
+        );
+    }
+}
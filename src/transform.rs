@@ -1,8 +1,11 @@
 use crate::boundingbox::BoundingBox;
 use crate::hitable::{HitRecord, Hitable};
+use crate::light::Light;
 use crate::ray::Ray;
 use crate::vec3::Vec3;
 
+use rand::RngCore;
+
 pub struct Translate {
     pub offset: Vec3,
     pub h: Box<dyn Hitable>,
@@ -15,9 +18,9 @@ impl Translate {
 }
 
 impl Hitable for Translate {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rng: &mut dyn RngCore) -> Option<HitRecord<'_>> {
         let r_moved = Ray::new(r.origin - self.offset, r.direction);
-        if let Some(mut rec) = self.h.hit(&r_moved, t_min, t_max) {
+        if let Some(mut rec) = self.h.hit(&r_moved, t_min, t_max, rng) {
             rec.p += self.offset;
             Some(rec)
         } else {
@@ -26,14 +29,17 @@ impl Hitable for Translate {
     }
 
     fn bounding_box(&self) -> Option<BoundingBox> {
-        if let Some(bbox) = self.h.bounding_box() {
-            Some(BoundingBox {
-                min: bbox.min + self.offset,
-                max: bbox.max + self.offset,
-            })
-        } else {
-            None
-        }
+        self.h.bounding_box().map(|bbox| BoundingBox {
+            min: bbox.min + self.offset,
+            max: bbox.max + self.offset,
+        })
+    }
+
+    fn collect_lights(&mut self, lights: &mut Vec<Light>) {
+        let first = lights.len();
+        self.h.collect_lights(lights);
+        let moved = lights.split_off(first);
+        lights.extend(moved.into_iter().map(|light| light.translate(self.offset)));
     }
 }
 
@@ -93,12 +99,41 @@ impl RotateXYZ {
                 Vec3::new(-sin_theta, sin_phi * cos_theta, cos_phi * cos_theta),
             ],
         };
+        RotateXYZ::from_matrix(h, rot_matrix)
+    }
+
+    /// Rotate by a unit quaternion given as (x, y, z, w).
+    pub fn from_quaternion(h: Box<dyn Hitable>, q: [f32; 4]) -> RotateXYZ {
+        let [x, y, z, w] = q;
+        let rot_matrix = RotationMatrix {
+            r: [
+                Vec3::new(
+                    1.0 - 2.0 * (y * y + z * z),
+                    2.0 * (x * y - z * w),
+                    2.0 * (x * z + y * w),
+                ),
+                Vec3::new(
+                    2.0 * (x * y + z * w),
+                    1.0 - 2.0 * (x * x + z * z),
+                    2.0 * (y * z - x * w),
+                ),
+                Vec3::new(
+                    2.0 * (x * z - y * w),
+                    2.0 * (y * z + x * w),
+                    1.0 - 2.0 * (x * x + y * y),
+                ),
+            ],
+        };
+        RotateXYZ::from_matrix(h, rot_matrix)
+    }
+
+    fn from_matrix(h: Box<dyn Hitable>, rot_matrix: RotationMatrix) -> RotateXYZ {
         let inv_rot_matrix = rot_matrix.invert();
 
         let bbox = match h.bounding_box() {
             Some(bbox) => {
-                let mut min = Vec3::new(std::f32::MAX, std::f32::MAX, std::f32::MAX);
-                let mut max = Vec3::new(-std::f32::MAX, -std::f32::MAX, -std::f32::MAX);
+                let mut min = Vec3::new(f32::MAX, f32::MAX, f32::MAX);
+                let mut max = Vec3::new(-f32::MAX, -f32::MAX, -f32::MAX);
                 for i in 0..2 {
                     for j in 0..2 {
                         for k in 0..2 {
@@ -136,13 +171,13 @@ impl RotateXYZ {
 }
 
 impl Hitable for RotateXYZ {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rng: &mut dyn RngCore) -> Option<HitRecord<'_>> {
         let r_inv_rotated = Ray::new(
             self.inv_rot_matrix.rotate(r.origin),
             self.inv_rot_matrix.rotate(r.direction),
         );
 
-        if let Some(mut rec) = self.h.hit(&r_inv_rotated, t_min, t_max) {
+        if let Some(mut rec) = self.h.hit(&r_inv_rotated, t_min, t_max, rng) {
             rec.p = self.rot_matrix.rotate(rec.p);
             rec.n = self.rot_matrix.rotate(rec.n);
             Some(rec)
@@ -155,3 +190,150 @@ impl Hitable for RotateXYZ {
         self.bbox.clone()
     }
 }
+
+/// Non-uniform scaling along the coordinate axes.
+pub struct Scale {
+    pub factor: Vec3,
+    pub h: Box<dyn Hitable>,
+}
+
+impl Scale {
+    pub fn new(h: Box<dyn Hitable>, factor: Vec3) -> Scale {
+        Scale { h, factor }
+    }
+}
+
+impl Hitable for Scale {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rng: &mut dyn RngCore) -> Option<HitRecord<'_>> {
+        // scaling origin and direction alike keeps the ray parameter t unchanged
+        let r_scaled = Ray::new(r.origin / self.factor, r.direction / self.factor);
+        if let Some(mut rec) = self.h.hit(&r_scaled, t_min, t_max, rng) {
+            rec.p *= self.factor;
+            // normals transform with the inverse transpose, i.e. the inverse scale
+            rec.n = (rec.n / self.factor).normalize();
+            Some(rec)
+        } else {
+            None
+        }
+    }
+
+    fn bounding_box(&self) -> Option<BoundingBox> {
+        self.h.bounding_box().map(|bbox| {
+            let a = bbox.min * self.factor;
+            let b = bbox.max * self.factor;
+            BoundingBox {
+                min: Vec3::new(a.x().min(b.x()), a.y().min(b.y()), a.z().min(b.z())),
+                max: Vec3::new(a.x().max(b.x()), a.y().max(b.y()), a.z().max(b.z())),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hitable::HitableList;
+    use crate::light::LightShape;
+    use crate::material::Material;
+    use crate::rect::{Axes, Rect};
+    use crate::sphere::Sphere;
+    use crate::texture::Texture;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::sync::Arc;
+
+    fn sphere() -> Box<dyn Hitable> {
+        Box::new(Sphere::new(
+            Vec3::new(2.0, 0.0, 0.0),
+            0.5,
+            Arc::new(Material::Diffuse {
+                albedo: Texture::Constant {
+                    color: Vec3::new(0.5, 0.5, 0.5),
+                },
+            }),
+        ))
+    }
+
+    #[test]
+    fn test_quaternion_matches_euler_angles() {
+        let half = std::f32::consts::FRAC_PI_4;
+        let euler = RotateXYZ::new(sphere(), Vec3::new(0.0, 90.0, 0.0));
+        let quat = RotateXYZ::from_quaternion(sphere(), [0.0, half.sin(), 0.0, half.cos()]);
+
+        // rotating (2, 0, 0) by 90 degrees around y gives (0, 0, -2)
+        let r = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let t_euler = euler
+            .hit(&r, 0.001, f32::MAX, &mut StdRng::seed_from_u64(0))
+            .unwrap()
+            .t;
+        let t_quat = quat
+            .hit(&r, 0.001, f32::MAX, &mut StdRng::seed_from_u64(0))
+            .unwrap()
+            .t;
+        assert!((t_euler - 6.5).abs() < 1e-4);
+        assert!((t_quat - 6.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_scale() {
+        let scaled = Scale::new(sphere(), Vec3::new(2.0, 1.0, 1.0));
+        let bbox = scaled.bounding_box().unwrap();
+        assert_eq!(bbox.min.x(), 3.0);
+        assert_eq!(bbox.max.x(), 5.0);
+
+        let r = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let rec = scaled
+            .hit(&r, 0.001, f32::MAX, &mut StdRng::seed_from_u64(0))
+            .unwrap();
+        assert!((rec.t - 3.0).abs() < 1e-4);
+        assert!((rec.n - Vec3::new(-1.0, 0.0, 0.0)).len() < 1e-4);
+    }
+
+    #[test]
+    fn test_translate_lights() {
+        // both rects share the material, but are separate lights
+        let material = Arc::new(Material::DiffuseLight {
+            emit: Texture::Constant {
+                color: Vec3::new(1.0, 1.0, 1.0),
+            },
+        });
+        let rect = || {
+            Box::new(Rect::new(
+                Axes::XY {
+                    x: (-1.0, 1.0),
+                    y: (-1.0, 1.0),
+                    z: 0.0,
+                },
+                false,
+                material.clone(),
+            ))
+        };
+        let mut world = HitableList {
+            list: vec![
+                rect(),
+                Box::new(Translate::new(rect(), Vec3::new(0.0, 0.0, -2.0))),
+            ],
+        };
+        let mut lights = vec![];
+        world.collect_lights(&mut lights);
+        assert_eq!(lights.len(), 2);
+        match &lights[1].shape {
+            LightShape::Rect {
+                a: Axes::XY { z, .. },
+            } => assert_eq!(*z, -2.0),
+            _ => panic!("expected a rect"),
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let r = Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        assert_eq!(
+            world.hit(&r, 0.001, f32::MAX, &mut rng).unwrap().light,
+            Some(0)
+        );
+        let r = Ray::new(Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 0.0, -1.0));
+        assert_eq!(
+            world.hit(&r, 0.001, f32::MAX, &mut rng).unwrap().light,
+            Some(1)
+        );
+    }
+}
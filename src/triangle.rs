@@ -0,0 +1,353 @@
+use crate::boundingbox::BoundingBox;
+use crate::bvhnode::{Bvh, BvhBuilder, Items};
+use crate::hitable::{HitRecord, Hitable};
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+use rand::RngCore;
+use std::sync::Arc;
+
+/// A single triangle, the normal follows the counter-clockwise winding of the vertices.
+pub struct Triangle {
+    pub v: [Vec3; 3],
+    pub material: Arc<Material>,
+}
+
+impl Hitable for Triangle {
+    fn hit(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+        _rng: &mut dyn RngCore,
+    ) -> Option<HitRecord<'_>> {
+        let (t, b1, b2) = intersect(&self.v, r, t_min, t_max)?;
+        Some(HitRecord {
+            t,
+            p: r.point(t),
+            n: Vec3::cross(self.v[1] - self.v[0], self.v[2] - self.v[0]).normalize(),
+            u: b1,
+            v: b2,
+            material: &self.material,
+            color: Vec3::new(1.0, 1.0, 1.0),
+            light: None,
+        })
+    }
+
+    fn bounding_box(&self) -> Option<BoundingBox> {
+        Some(triangle_box(&self.v))
+    }
+}
+
+/// Triangle mesh with shared vertex buffers and an internal BVH over its triangles.
+///
+/// `normals`, `uvs` and the vertex colors are either empty or hold one entry per vertex.
+/// Without normals the geometric normal is used, without texture coordinates the
+/// barycentric coordinates are reported as (u, v).
+pub struct TriangleMesh {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<(f32, f32)>,
+    indices: Vec<[u32; 3]>,
+    material: Arc<Material>,
+    /// optional per-vertex colors, see [`HitRecord::color`]
+    colors: Vec<Vec3>,
+    /// SAH hierarchy over the face indices, `None` for a mesh without faces
+    bvh: Option<Bvh<usize>>,
+}
+
+impl TriangleMesh {
+    /// Panics if `normals` or `uvs` are neither empty nor of the length of `positions`, the
+    /// loaders check this and report it as error.
+    pub fn new(
+        positions: Vec<Vec3>,
+        normals: Vec<Vec3>,
+        uvs: Vec<(f32, f32)>,
+        indices: Vec<[u32; 3]>,
+        material: Arc<Material>,
+    ) -> TriangleMesh {
+        for len in &[normals.len(), uvs.len()] {
+            assert!(
+                *len == 0 || *len == positions.len(),
+                "one attribute per vertex"
+            );
+        }
+        TriangleMesh {
+            positions,
+            normals,
+            uvs,
+            indices,
+            material,
+            colors: vec![],
+            bvh: None,
+        }
+        .build()
+    }
+
+    /// Tint the mesh with one color per vertex, interpolated over the faces and multiplied
+    /// with the albedo of the material.
+    ///
+    /// Panics unless there is exactly one color per vertex.
+    pub fn with_vertex_colors(mut self, colors: Vec<Vec3>) -> TriangleMesh {
+        assert_eq!(colors.len(), self.positions.len(), "one color per vertex");
+        self.colors = colors;
+        self
+    }
+
+    fn build(mut self) -> TriangleMesh {
+        let faces: Items<usize> = self
+            .indices
+            .iter()
+            .enumerate()
+            .map(|(i, idx)| (triangle_box(&self.vertices(idx)), i))
+            .collect();
+        if !faces.is_empty() {
+            self.bvh = Some(Bvh::new(faces, BvhBuilder::Sah));
+        }
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    fn vertices(&self, idx: &[u32; 3]) -> [Vec3; 3] {
+        [
+            self.positions[idx[0] as usize],
+            self.positions[idx[1] as usize],
+            self.positions[idx[2] as usize],
+        ]
+    }
+
+    fn hit_triangle(&self, i: usize, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        let idx = &self.indices[i];
+        let v = self.vertices(idx);
+        let (t, b1, b2) = intersect(&v, r, t_min, t_max)?;
+        let b0 = 1.0 - b1 - b2;
+
+        let n = if self.normals.is_empty() {
+            Vec3::cross(v[1] - v[0], v[2] - v[0]).normalize()
+        } else {
+            (b0 * self.normals[idx[0] as usize]
+                + b1 * self.normals[idx[1] as usize]
+                + b2 * self.normals[idx[2] as usize])
+                .normalize()
+        };
+        let (u, v) = if self.uvs.is_empty() {
+            (b1, b2)
+        } else {
+            let uv0 = self.uvs[idx[0] as usize];
+            let uv1 = self.uvs[idx[1] as usize];
+            let uv2 = self.uvs[idx[2] as usize];
+            (
+                b0 * uv0.0 + b1 * uv1.0 + b2 * uv2.0,
+                b0 * uv0.1 + b1 * uv1.1 + b2 * uv2.1,
+            )
+        };
+
+        Some(HitRecord {
+            t,
+            p: r.point(t),
+            n,
+            u,
+            v,
+            material: &self.material,
+            color: if self.colors.is_empty() {
+                Vec3::new(1.0, 1.0, 1.0)
+            } else {
+                b0 * self.colors[idx[0] as usize]
+                    + b1 * self.colors[idx[1] as usize]
+                    + b2 * self.colors[idx[2] as usize]
+            },
+            light: None,
+        })
+    }
+}
+
+impl Hitable for TriangleMesh {
+    fn hit(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+        _rng: &mut dyn RngCore,
+    ) -> Option<HitRecord<'_>> {
+        self.bvh.as_ref()?.hit(r, t_min, t_max, |&i, t_max| {
+            self.hit_triangle(i, r, t_min, t_max)
+        })
+    }
+
+    fn bounding_box(&self) -> Option<BoundingBox> {
+        self.bvh.as_ref().map(|bvh| bvh.bounding_box().clone())
+    }
+}
+
+/// Möller-Trumbore ray triangle intersection, returns t and the barycentric coordinates
+/// of the second and third vertex.
+fn intersect(v: &[Vec3; 3], r: &Ray, t_min: f32, t_max: f32) -> Option<(f32, f32, f32)> {
+    let e1 = v[1] - v[0];
+    let e2 = v[2] - v[0];
+    let pvec = Vec3::cross(r.direction, e2);
+    let det = Vec3::dot(e1, pvec);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+
+    let tvec = r.origin - v[0];
+    let b1 = Vec3::dot(tvec, pvec) * inv_det;
+    if !(0.0..=1.0).contains(&b1) {
+        return None;
+    }
+    let qvec = Vec3::cross(tvec, e1);
+    let b2 = Vec3::dot(r.direction, qvec) * inv_det;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return None;
+    }
+
+    let t = Vec3::dot(e2, qvec) * inv_det;
+    if t < t_max && t > t_min {
+        Some((t, b1, b2))
+    } else {
+        None
+    }
+}
+
+fn triangle_box(v: &[Vec3; 3]) -> BoundingBox {
+    // pad the box so that axis-aligned triangles do not get a degenerate box
+    let pad = Vec3::new(0.0001, 0.0001, 0.0001);
+    BoundingBox {
+        min: Vec3::new(
+            v[0].x().min(v[1].x()).min(v[2].x()),
+            v[0].y().min(v[1].y()).min(v[2].y()),
+            v[0].z().min(v[1].z()).min(v[2].z()),
+        ) - pad,
+        max: Vec3::new(
+            v[0].x().max(v[1].x()).max(v[2].x()),
+            v[0].y().max(v[1].y()).max(v[2].y()),
+            v[0].z().max(v[1].z()).max(v[2].z()),
+        ) + pad,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::Texture;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use rand::Rng;
+
+    fn grey() -> Arc<Material> {
+        Arc::new(Material::Diffuse {
+            albedo: Texture::Constant {
+                color: Vec3::new(0.5, 0.5, 0.5),
+            },
+        })
+    }
+
+    #[test]
+    fn test_triangle_hit() {
+        let tri = Triangle {
+            v: [
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+            ],
+            material: grey(),
+        };
+        let r = Ray::new(Vec3::new(0.25, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let rec = tri
+            .hit(&r, 0.001, f32::MAX, &mut StdRng::seed_from_u64(0))
+            .unwrap();
+        assert_eq!(rec.t, 1.0);
+        assert_eq!(rec.n, Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!((rec.u, rec.v), (0.25, 0.5));
+
+        let r = Ray::new(Vec3::new(0.75, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(tri
+            .hit(&r, 0.001, f32::MAX, &mut StdRng::seed_from_u64(0))
+            .is_none());
+    }
+
+    #[test]
+    fn test_mesh_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let positions: Vec<Vec3> = (0..300)
+            .map(|_| {
+                Vec3::new(
+                    rng.gen_range(-1.0, 1.0),
+                    rng.gen_range(-1.0, 1.0),
+                    rng.gen_range(-1.0, 1.0),
+                )
+            })
+            .collect();
+        let indices: Vec<[u32; 3]> = (0..100).map(|i| [3 * i, 3 * i + 1, 3 * i + 2]).collect();
+        let triangles: Vec<Triangle> = indices
+            .iter()
+            .map(|idx| Triangle {
+                v: [
+                    positions[idx[0] as usize],
+                    positions[idx[1] as usize],
+                    positions[idx[2] as usize],
+                ],
+                material: grey(),
+            })
+            .collect();
+        let mesh = TriangleMesh::new(positions, vec![], vec![], indices, grey());
+        assert_eq!(mesh.len(), 100);
+
+        for _ in 0..1000 {
+            let r = Ray::new(
+                Vec3::new(rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0), 3.0),
+                Vec3::new(rng.gen_range(-0.2, 0.2), rng.gen_range(-0.2, 0.2), -1.0),
+            );
+            let expected = triangles
+                .iter()
+                .filter_map(|tri| tri.hit(&r, 0.001, f32::MAX, &mut rng))
+                .map(|rec| rec.t)
+                .fold(None, |acc: Option<f32>, t| {
+                    Some(acc.map_or(t, |a| a.min(t)))
+                });
+            assert_eq!(
+                mesh.hit(&r, 0.001, f32::MAX, &mut rng).map(|rec| rec.t),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_vertex_colors() {
+        let material = grey();
+        let mesh = TriangleMesh::new(
+            vec![
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+            ],
+            vec![],
+            vec![(0.0, 0.0), (1.0, 0.0), (0.0, 2.0)],
+            vec![[0, 1, 2]],
+            material.clone(),
+        )
+        .with_vertex_colors(vec![
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+        ]);
+        let r = Ray::new(Vec3::new(0.25, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let rec = mesh
+            .hit(&r, 0.001, f32::MAX, &mut StdRng::seed_from_u64(0))
+            .unwrap();
+        assert_eq!(rec.color, Vec3::new(0.25, 0.25, 0.5));
+        // the mesh keeps its material and texture coordinates
+        assert!(std::ptr::eq(rec.material, &*material));
+        assert_eq!((rec.u, rec.v), (0.25, 1.0));
+    }
+}
@@ -8,3 +8,26 @@ pub use self::sse2::*;
 
 #[cfg(not(target_feature = "sse2"))]
 pub use self::scalar::*;
+
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+
+// (de)serialize as a plain (x, y, z) tuple, independent of the SIMD backend
+impl Serialize for Vec3 {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        (self.x(), self.y(), self.z()).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Vec3 {
+    fn deserialize<D>(deserializer: D) -> Result<Vec3, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let (x, y, z) = <(f32, f32, f32)>::deserialize(deserializer)?;
+        Ok(Vec3::new(x, y, z))
+    }
+}
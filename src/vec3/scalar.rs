@@ -7,12 +7,14 @@ pub struct Vec3 {
     e: [f32; 3],
 }
 
-impl Vec3 {
+impl Default for Vec3 {
     #[inline]
-    pub fn default() -> Vec3 {
+    fn default() -> Vec3 {
         Vec3 { e: [0.0, 0.0, 0.0] }
     }
+}
 
+impl Vec3 {
     #[inline]
     pub fn new(e0: f32, e1: f32, e2: f32) -> Vec3 {
         Vec3 { e: [e0, e1, e2] }
@@ -429,7 +431,7 @@ mod tests {
     fn test_normalize() {
         let epsilon = 1e-4;
         let v = Vec3::new(3., 4., 5.);
-        let v_res = Vec3::new(0.42426, 0.56569, 0.70711);
+        let v_res = Vec3::new(0.42426, 0.56569, std::f32::consts::FRAC_1_SQRT_2);
         let v_diff = v_res - v.normalize();
 
         assert!(v_diff.x().abs() < epsilon);
@@ -10,12 +10,14 @@ use std::arch::x86_64::*;
 #[derive(Debug, Clone, Copy)]
 pub struct Vec3(__m128);
 
-impl Vec3 {
+impl Default for Vec3 {
     #[inline]
-    pub fn default() -> Vec3 {
+    fn default() -> Vec3 {
         unsafe { Vec3(_mm_set1_ps(0.0)) }
     }
+}
 
+impl Vec3 {
     #[inline]
     pub fn new(e0: f32, e1: f32, e2: f32) -> Vec3 {
         unsafe { Vec3(_mm_set_ps(e2, e2, e1, e0)) }
@@ -396,7 +398,7 @@ mod tests {
     fn test_normalize() {
         let epsilon = 1e-4;
         let v = Vec3::new(3., 4., 5.);
-        let v_res = Vec3::new(0.42426, 0.56569, 0.70711);
+        let v_res = Vec3::new(0.42426, 0.56569, std::f32::consts::FRAC_1_SQRT_2);
         let v_diff = v_res - v.normalize();
 
         assert!(v_diff.x().abs() < epsilon);
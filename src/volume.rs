@@ -5,7 +5,7 @@ use crate::ray::Ray;
 use crate::texture::Texture;
 use crate::vec3::Vec3;
 
-use rand::Rng;
+use rand::{Rng, RngCore};
 
 pub struct ConstantMedium {
     pub boundary: Box<dyn Hitable>,
@@ -24,12 +24,12 @@ impl ConstantMedium {
 }
 
 impl Hitable for ConstantMedium {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rng: &mut dyn RngCore) -> Option<HitRecord<'_>> {
         // check if our ray hits the boundary (e.g. enters the volume)
-        let opt_rec1 = self.boundary.hit(r, -std::f32::MAX, std::f32::MAX);
+        let opt_rec1 = self.boundary.hit(r, -f32::MAX, f32::MAX, rng);
         if let Some(mut rec1) = opt_rec1 {
             // check if there is a second point where the ray exits the volume
-            let opt_rec2 = self.boundary.hit(r, rec1.t + 0.0001, std::f32::MAX);
+            let opt_rec2 = self.boundary.hit(r, rec1.t + 0.0001, f32::MAX, rng);
             if let Some(mut rec2) = opt_rec2 {
                 // limit entry / exit point to our range (t_min .. t_max)
                 rec1.t = rec1.t.max(t_min);
@@ -43,7 +43,6 @@ impl Hitable for ConstantMedium {
                     let distance_inside_boundary = (rec2.t - rec1.t) * r.direction.len();
 
                     // generate random hit distance depending on the density
-                    let mut rng = rand::thread_rng();
                     let hit_distance = (-1. / self.density) * rng.gen::<f32>().ln();
 
                     // if the hit distance is smaller than our ray travel distance, we have a hit
@@ -56,6 +55,8 @@ impl Hitable for ConstantMedium {
                             u: 0.0,
                             v: 0.0,
                             material: &self.phase_function,
+                            color: Vec3::new(1.0, 1.0, 1.0),
+                            light: None,
                         })
                     } else {
                         None
@@ -0,0 +1,336 @@
+use raytrace::aov::{self, Aov};
+use raytrace::boundingbox::BoundingBox;
+use raytrace::checkpoint::Checkpoint;
+use raytrace::framebuffer::Accumulator;
+use raytrace::hitable::{HitRecord, Hitable, HitableList};
+use raytrace::material::Material;
+use raytrace::ray::Ray;
+use raytrace::render;
+use raytrace::scenefile;
+use raytrace::texture::Texture;
+use raytrace::vec3::Vec3;
+
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use std::path::Path;
+use std::time::Duration;
+
+#[test]
+fn test_example_scenes_load() {
+    for name in &[
+        "cornell_balls",
+        "cornell_blocks",
+        "cornell_blocks_volume",
+        "cornell_mesh",
+        "earth_perlin",
+        "random_scene",
+    ] {
+        let path = Path::new("scenes").join(format!("{}.ron", name));
+        let scene = scenefile::load(&path).unwrap().into_scene().unwrap();
+        assert_eq!(scene.world.list.len(), 1);
+        assert!(scene.world.bounding_box().is_some());
+    }
+}
+
+#[test]
+fn test_render_framebuffer() {
+    let mut scene_file = scenefile::load(Path::new("scenes/cornell_blocks.ron")).unwrap();
+    scene_file.params.nx = 8;
+    scene_file.params.ny = 4;
+    scene_file.params.ns = 2;
+    let scene = scene_file.into_scene().unwrap();
+
+    let fb = render::render_framebuffer(&scene);
+    assert_eq!((fb.width, fb.height), (8, 4));
+    assert_eq!(fb.data.len(), 32);
+    assert!(fb
+        .data
+        .iter()
+        .all(|c| (0..3).all(|i| c.i(i).is_finite() && c.i(i) >= 0.0)));
+}
+
+#[test]
+fn test_render_is_deterministic() {
+    let render_with_seed = |seed| {
+        // the Perlin noise texture is drawn from the seed as well
+        let mut scene_file = scenefile::load(Path::new("scenes/earth_perlin.ron")).unwrap();
+        scene_file.params.nx = 16;
+        scene_file.params.ny = 8;
+        scene_file.params.ns = 4;
+        scene_file.params.tile_size = 4;
+        scene_file.params.seed = seed;
+        render::render_framebuffer(&scene_file.into_scene().unwrap()).data
+    };
+    assert_eq!(render_with_seed(7), render_with_seed(7));
+    assert_ne!(render_with_seed(7), render_with_seed(8));
+}
+
+#[test]
+fn test_render_progressive() {
+    let mut scene_file = scenefile::load(Path::new("scenes/cornell_blocks.ron")).unwrap();
+    scene_file.params.nx = 8;
+    scene_file.params.ny = 4;
+    scene_file.params.ns = 5;
+    let scene = scene_file.into_scene().unwrap();
+
+    let mut snapshots = vec![];
+    let fb = render::render_progressive(
+        &scene,
+        None,
+        || (),
+        |acc, passes_done| {
+            assert_eq!(acc.sum.len(), 32);
+            snapshots.push((passes_done, acc.samples[0]));
+            Ok(())
+        },
+    )
+    .unwrap();
+    assert_eq!(snapshots, vec![(1, 1), (2, 3), (3, 5)]);
+    assert_eq!((fb.width, fb.height), (8, 4));
+}
+
+#[test]
+fn test_render_time_limit() {
+    let mut scene_file = scenefile::load(Path::new("scenes/cornell_blocks.ron")).unwrap();
+    scene_file.params.nx = 8;
+    scene_file.params.ny = 4;
+    scene_file.params.ns = 1;
+    scene_file.params.time_limit = 1;
+    let scene = scene_file.into_scene().unwrap();
+
+    // how many passes fit depends on the machine, but every pass covers the whole image
+    let acc = render::render_accumulator(&scene, None, || (), |_, _| Ok(())).unwrap();
+    assert!(acc.samples[0] >= 1);
+    assert!(acc.samples.iter().all(|&n| n == acc.samples[0]));
+}
+
+#[test]
+fn test_resume_time_limit() {
+    let mut scene_file = scenefile::load(Path::new("scenes/cornell_blocks.ron")).unwrap();
+    scene_file.params.nx = 8;
+    scene_file.params.ny = 4;
+    scene_file.params.ns = 1;
+    scene_file.params.time_limit = 1;
+    scene_file.params.filename = std::env::temp_dir()
+        .join(format!(
+            "raytrace_test_time_limit_{}.png",
+            std::process::id()
+        ))
+        .to_string_lossy()
+        .into_owned();
+    let params = scene_file.params.clone();
+    let scene = scene_file.into_scene().unwrap();
+
+    // a checkpoint with more passes than the `ns` schedule has
+    let checkpoint = |elapsed| Checkpoint {
+        params: params.clone(),
+        passes_done: 8,
+        elapsed,
+        acc: Accumulator::new(params.nx, params.ny),
+    };
+    // with time left, at least one pass of 64 samples is rendered
+    let acc = render::render_accumulator(
+        &scene,
+        Some(checkpoint(Duration::from_millis(500))),
+        || (),
+        |_, _| Ok(()),
+    )
+    .unwrap();
+    assert!(acc.samples[0] >= 64);
+    assert!(acc.samples.iter().all(|&n| n == acc.samples[0]));
+
+    // with the time used up, nothing is added
+    let acc = render::render_accumulator(
+        &scene,
+        Some(checkpoint(Duration::from_secs(1))),
+        || (),
+        |_, _| Ok(()),
+    )
+    .unwrap();
+    assert!(acc.samples.iter().all(|&n| n == 0));
+
+    render::resume(scene, checkpoint(Duration::from_secs(1))).unwrap();
+    std::fs::remove_file(&params.filename).unwrap();
+}
+
+#[test]
+fn test_render_adaptive() {
+    let mut scene_file = scenefile::load(Path::new("scenes/cornell_balls.ron")).unwrap();
+    scene_file.params.nx = 16;
+    scene_file.params.ny = 16;
+    scene_file.params.ns = 64;
+    scene_file.params.adaptive_threshold = 0.05;
+    let scene = scene_file.into_scene().unwrap();
+
+    let acc = render::render_accumulator(&scene, None, || (), |_, _| Ok(())).unwrap();
+    let total: u64 = acc.samples.iter().sum();
+    let min = *acc.samples.iter().min().unwrap();
+    let max = *acc.samples.iter().max().unwrap();
+    // the budget of 64 samples per pixel is spent unevenly
+    assert!(total <= 64 * 16 * 16);
+    assert!(min >= 16);
+    assert!(max > 64);
+}
+
+#[test]
+fn test_render_bdpt() {
+    let mean_with = |integrator| {
+        let mut scene_file = scenefile::load(Path::new("scenes/cornell_blocks.ron")).unwrap();
+        scene_file.params.nx = 16;
+        scene_file.params.ny = 16;
+        scene_file.params.ns = 64;
+        scene_file.params.integrator = integrator;
+        let fb = render::render_framebuffer(&scene_file.into_scene().unwrap());
+        // below the light, whose edges are noisy in either case
+        fb.data[64..].iter().copied().sum::<Vec3>() / 192.0
+    };
+    // both integrators converge to the same image
+    let path = mean_with(render::Integrator::Path);
+    let bdpt = mean_with(render::Integrator::Bdpt);
+    for i in 0..3 {
+        assert!((bdpt.i(i) - path.i(i)).abs() < 0.05 * path.i(i));
+    }
+}
+
+#[test]
+fn test_render_aovs() {
+    let mut scene_file = scenefile::load(Path::new("scenes/cornell_blocks.ron")).unwrap();
+    scene_file.params.nx = 16;
+    scene_file.params.ny = 16;
+    let scene = scene_file.into_scene().unwrap();
+
+    let aovs = aov::render_aovs(&scene, &Aov::ALL);
+    assert_eq!(aovs.len(), Aov::ALL.len());
+    for (aov, fb) in &aovs {
+        assert_eq!((fb.width, fb.height), (16, 16));
+        match aov {
+            // the camera looks into the closed box
+            Aov::Depth => assert!(fb.data.iter().all(|c| c.x() > 0.0)),
+            Aov::Normal => assert!(fb.data.iter().all(|n| (n.len() - 1.0).abs() < 1e-3)),
+            Aov::MaterialId => {
+                // the green wall in the top left corner is the third entry of the table
+                assert_eq!(fb.data[0].x(), 3.0);
+                assert!(fb.data.iter().all(|c| c.x() >= 1.0 && c.x() <= 4.0));
+                assert!(fb.data.iter().any(|c| c.x() == 2.0));
+            }
+            _ => (),
+        }
+    }
+    // the same for every run
+    let again = aov::render_aovs(&scene, &Aov::ALL);
+    for ((_, a), (_, b)) in aovs.iter().zip(&again) {
+        assert_eq!(a.data, b.data);
+    }
+}
+
+#[test]
+fn test_resume_from_checkpoint() {
+    let mut scene_file = scenefile::load(Path::new("scenes/cornell_blocks.ron")).unwrap();
+    scene_file.params.nx = 8;
+    scene_file.params.ny = 4;
+    scene_file.params.ns = 10;
+    let params = scene_file.params.clone();
+    let scene = scene_file.into_scene().unwrap();
+
+    let mut checkpoint = None;
+    let uninterrupted = render::render_progressive(
+        &scene,
+        None,
+        || (),
+        |acc, passes_done| {
+            if passes_done == 2 {
+                checkpoint = Some(Checkpoint {
+                    params: params.clone(),
+                    passes_done,
+                    elapsed: Duration::default(),
+                    acc: acc.clone(),
+                });
+            }
+            Ok(())
+        },
+    )
+    .unwrap();
+
+    // round trip through the file format
+    let path = std::env::temp_dir().join(format!(
+        "raytrace_test_resume_{}.checkpoint",
+        std::process::id()
+    ));
+    checkpoint.unwrap().save(&path).unwrap();
+    let checkpoint = Checkpoint::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let resumed =
+        render::render_progressive(&scene, Some(checkpoint), || (), |_, _| Ok(())).unwrap();
+    assert_eq!(resumed.data, uninterrupted.data);
+}
+
+/// unit square in the z = 0 plane, implemented outside of the crate
+struct Square {
+    material: Material,
+}
+
+impl Hitable for Square {
+    fn hit(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+        _rng: &mut dyn RngCore,
+    ) -> Option<HitRecord<'_>> {
+        let t = -r.origin.z() / r.direction.z();
+        let p = r.point(t);
+        if t < t_min || t > t_max || p.x().abs() > 0.5 || p.y().abs() > 0.5 {
+            None
+        } else {
+            Some(HitRecord {
+                t,
+                p,
+                n: Vec3::new(0.0, 0.0, 1.0),
+                u: p.x() + 0.5,
+                v: p.y() + 0.5,
+                material: &self.material,
+                color: Vec3::new(1.0, 1.0, 1.0),
+                light: None,
+            })
+        }
+    }
+
+    fn bounding_box(&self) -> Option<BoundingBox> {
+        Some(BoundingBox {
+            min: Vec3::new(-0.5, -0.5, -0.0001),
+            max: Vec3::new(0.5, 0.5, 0.0001),
+        })
+    }
+}
+
+#[test]
+fn test_custom_hitable() {
+    let world = HitableList {
+        list: vec![Box::new(Square {
+            material: Material::Diffuse {
+                albedo: Texture::Constant {
+                    color: Vec3::new(0.5, 0.5, 0.5),
+                },
+            },
+        })],
+    }
+    .into_bvh()
+    .unwrap();
+
+    let hit = world.hit(
+        &Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0)),
+        0.001,
+        f32::MAX,
+        &mut StdRng::seed_from_u64(0),
+    );
+    assert_eq!(hit.map(|rec| rec.t), Some(1.0));
+
+    let miss = world.hit(
+        &Ray::new(Vec3::new(1.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0)),
+        0.001,
+        f32::MAX,
+        &mut StdRng::seed_from_u64(0),
+    );
+    assert!(miss.is_none());
+}